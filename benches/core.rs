@@ -0,0 +1,74 @@
+//! Benchmarks for the hot paths: stepping instructions, drawing sprites and rendering
+//! a frame. Run with `cargo bench`.
+//!
+//! Baseline on a Linux x86-64 host, to compare against rather than to match:
+//!
+//! | benchmark                      | time     |
+//! |--------------------------------|----------|
+//! | execute opcode mix (8 steps)   | 65 ns    |
+//! | draw_sprite/0                  | 10 ns    |
+//! | draw_sprite/3                  | 15 ns    |
+//! | draw_sprite/60                 | 15 ns    |
+//! | render frame                   | 142 µs   |
+
+use std::io::sink;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use chip8::cpu::CPU;
+use chip8::terminal::Terminal;
+
+// A loop of the common opcodes: loads, arithmetic, a shift, a skip, a draw and a jump
+const OPCODE_MIX: &[u8] = &[
+    0x60, 0x05, // LD V0, 5
+    0x71, 0x01, // ADD V1, 1
+    0x82, 0x14, // ADD V2, V1
+    0x83, 0x26, // SHR V3, V2
+    0x33, 0x00, // SE V3, 0
+    0x84, 0x32, // AND V4, V3
+    0xD0, 0x15, // DRW V0, V1, 5
+    0x12, 0x00, // JP 0x200
+];
+
+fn execute_instruction(c: &mut Criterion) {
+    let input: &[u8] = &[];
+    let mut cpu = CPU::new(input, sink());
+    cpu.set_rewind_depth(0);
+    cpu.load(OPCODE_MIX);
+    let steps = OPCODE_MIX.len() / 2;
+    c.bench_function("execute opcode mix", |b| {
+        b.iter(|| {
+            for _ in 0..steps {
+                cpu.step().unwrap();
+            }
+        })
+    });
+}
+
+fn draw_sprite(c: &mut Criterion) {
+    let input: &[u8] = &[];
+    let mut terminal = Terminal::new(input, sink());
+    let sprite = [0xF0, 0x90, 0xF0, 0x90, 0x90, 0xFF, 0x81, 0xFF];
+    let mut group = c.benchmark_group("draw_sprite");
+    // Byte aligned, unaligned, and wrapping around the right edge
+    for &x in &[0u8, 3, 60] {
+        group.bench_with_input(BenchmarkId::from_parameter(x), &x, |b, &x| {
+            b.iter(|| terminal.draw_sprite(black_box(x), 10, &sprite))
+        });
+    }
+    group.finish();
+}
+
+fn render(c: &mut Criterion) {
+    let input: &[u8] = &[];
+    let mut terminal = Terminal::new(input, sink());
+    for y in (0..32).step_by(4) {
+        for x in (0..64).step_by(9) {
+            terminal.draw_sprite(x, y, &[0xAA, 0x55, 0xAA]);
+        }
+    }
+    c.bench_function("render frame", |b| b.iter(|| terminal.render()));
+}
+
+criterion_group!(benches, execute_instruction, draw_sprite, render);
+criterion_main!(benches);
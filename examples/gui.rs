@@ -0,0 +1,65 @@
+//! Runs a ROM in a window: `cargo run --features gui --example gui -- ROM [SCALE]`.
+//! The left 4x4 block of the keyboard is the keypad, Escape quits.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+use chip8::backend::Keypad;
+use chip8::cpu::CPU;
+use chip8::gui::Window;
+
+const DEFAULT_SCALE: usize = 10;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let usage = || -> ! {
+        eprintln!("usage: gui ROM [SCALE]");
+        process::exit(2);
+    };
+    let (rom, scale) = match &args[..] {
+        [rom] => (rom, DEFAULT_SCALE),
+        [rom, scale] => match scale.parse() {
+            Ok(scale) if scale > 0 => (rom, scale),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+    let rom = fs::read(rom).unwrap_or_else(|e| {
+        eprintln!("error: cannot open {}: {}", rom, e);
+        process::exit(1);
+    });
+
+    let window = Window::new("chip8", scale).unwrap_or_else(|e| {
+        eprintln!("error: cannot open a window: {}", e);
+        process::exit(1);
+    });
+    // Shared, to read keys from the window the CPU presents frames on
+    let window = Rc::new(RefCell::new(window));
+    // The terminal only keeps display and keypad state here, its output goes nowhere
+    let mut cpu = CPU::new(io::empty(), io::sink());
+    cpu.set_display(Some(Box::new(window.clone())));
+    cpu.load(&rom);
+    let frame = Duration::from_micros(16667);
+
+    while !window.borrow().quit_requested() {
+        let start = Instant::now();
+        let events = window.borrow_mut().poll_keys();
+        for event in events {
+            cpu.terminal_mut().handle_key_event(event);
+        }
+        match cpu.run_frame() {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+        if let Some(rest) = frame.checked_sub(start.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::{Read, Seek};
+
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Splits a path like `games.zip:pong.ch8` into the archive and the entry inside it.
+pub fn split_entry(path: &str) -> Option<(&str, &str)> {
+    let end = path.find(".zip:")? + ".zip".len();
+    Some((&path[..end], &path[end + 1..]))
+}
+
+/// Reads the entry called `name` out of a zip archive.
+pub fn read_entry<R: Read + Seek>(archive: R, name: &str) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(archive).map_err(|e| e.to_string())?;
+    let mut entry = archive.by_name(name).map_err(|e| match e {
+        ZipError::FileNotFound => format!("no {} in the archive", name),
+        e => e.to_string(),
+    })?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::CompressionMethod;
+
+    use super::{read_entry, split_entry};
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            split_entry("roms/games.zip:pong.ch8"),
+            Some(("roms/games.zip", "pong.ch8"))
+        );
+        assert_eq!(
+            split_entry("a.zip:games/b.ch8"),
+            Some(("a.zip", "games/b.ch8"))
+        );
+        assert_eq!(split_entry("pong.ch8"), None);
+        assert_eq!(split_entry("games.zip"), None);
+    }
+
+    #[test]
+    fn extracts_an_entry() {
+        let pong = [0x6A, 0x02, 0x6B, 0x0C, 0x12, 0x04];
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        writer.start_file("README", options).unwrap();
+        writer.write_all(b"Two games").unwrap();
+        writer.start_file("pong.ch8", options).unwrap();
+        writer.write_all(&pong).unwrap();
+        let archive = writer.finish().unwrap();
+
+        assert_eq!(read_entry(archive.clone(), "pong.ch8"), Ok(pong.to_vec()));
+        assert_eq!(
+            read_entry(archive, "tetris.ch8"),
+            Err("no tetris.ch8 in the archive".to_string())
+        );
+        assert!(read_entry(Cursor::new(pong.to_vec()), "pong.ch8").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Interfaces for front-ends other than the terminal. The CPU's `Terminal` still keeps
+//! the display and keypad state; the CPU presents each frame it renders on the display
+//! given to `CPU::set_display`, and a front-end feeds it the key presses and releases
+//! it sees.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::terminal::DrawEvent;
+
+/// A keypad key going down or coming back up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+pub trait Display {
+    /// Told of each change to the display as the ROM makes it, before the frame is
+    /// presented. Backends that only show whole frames can leave this out.
+    fn draw(&mut self, _event: &DrawEvent) {}
+
+    /// Shows a finished frame, once every draw in it is done, one word per row with
+    /// the leftmost pixel in the most significant bit, as returned by
+    /// `Terminal::rows`: 32 rows, or 64 for HIRES CHIP-8.
+    fn present(&mut self, rows: &[u64]);
+}
+
+/// Lets a front-end hand its display to the CPU with `set_display` while keeping it
+/// around for other things, such as reading keys from the same window.
+impl<D: Display> Display for Rc<RefCell<D>> {
+    fn draw(&mut self, event: &DrawEvent) {
+        self.borrow_mut().draw(event);
+    }
+
+    fn present(&mut self, rows: &[u64]) {
+        self.borrow_mut().present(rows);
+    }
+}
+
+/// Presents every frame on several displays at once, such as a window and a recorder,
+/// in the order they were added.
+#[derive(Default)]
+pub struct CompositeDisplay {
+    displays: Vec<Box<dyn Display>>,
+}
+
+impl CompositeDisplay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, display: Box<dyn Display>) {
+        self.displays.push(display);
+    }
+}
+
+impl Display for CompositeDisplay {
+    fn draw(&mut self, event: &DrawEvent) {
+        for display in self.displays.iter_mut() {
+            display.draw(event);
+        }
+    }
+
+    fn present(&mut self, rows: &[u64]) {
+        for display in self.displays.iter_mut() {
+            display.present(rows);
+        }
+    }
+}
+
+pub trait Keypad {
+    /// Key events since the last call, oldest first.
+    fn poll_keys(&mut self) -> Vec<KeyEvent>;
+
+    /// Whether the user closed the front-end.
+    fn quit_requested(&self) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{CompositeDisplay, Display};
+    use crate::terminal::DrawEvent;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Draw(DrawEvent),
+        Present(Vec<u64>),
+    }
+
+    struct Recorder(Rc<RefCell<Vec<Call>>>);
+
+    impl Display for Recorder {
+        fn draw(&mut self, event: &DrawEvent) {
+            self.0.borrow_mut().push(Call::Draw(event.clone()));
+        }
+
+        fn present(&mut self, rows: &[u64]) {
+            self.0.borrow_mut().push(Call::Present(rows.to_vec()));
+        }
+    }
+
+    #[test]
+    fn composite_shows_every_frame_on_each_display() {
+        let first = Rc::new(RefCell::new(Vec::new()));
+        let second = Rc::new(RefCell::new(Vec::new()));
+        let mut composite = CompositeDisplay::new();
+        composite.push(Box::new(Recorder(first.clone())));
+        composite.push(Box::new(Recorder(second.clone())));
+
+        let sprite = DrawEvent::Sprite {
+            x: 0,
+            y: 3,
+            rows: 1,
+            collision: false,
+        };
+        let clear = DrawEvent::Clear { planes: 1 };
+        let mut frame = [0; 32];
+        composite.present(&frame);
+        composite.draw(&sprite);
+        frame[3] = 1 << 63;
+        composite.present(&frame);
+        composite.draw(&clear);
+        composite.draw(&sprite);
+        // A HIRES frame, twice as tall
+        let mut tall = [0; 64];
+        tall[3] = 1 << 63;
+        composite.present(&tall);
+
+        assert_eq!(
+            *first.borrow(),
+            [
+                Call::Present(vec![0; 32]),
+                Call::Draw(sprite.clone()),
+                Call::Present(frame.to_vec()),
+                Call::Draw(clear),
+                Call::Draw(sprite),
+                Call::Present(tall.to_vec()),
+            ]
+        );
+        assert_eq!(*first.borrow(), *second.borrow());
+    }
+
+    #[test]
+    fn shared_display() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let shared = Rc::new(RefCell::new(Recorder(calls.clone())));
+        let mut boxed: Box<dyn Display> = Box::new(shared.clone());
+        boxed.present(&[1; 32]);
+        shared.borrow_mut().present(&[2; 32]);
+        assert_eq!(
+            *calls.borrow(),
+            [Call::Present(vec![1; 32]), Call::Present(vec![2; 32])]
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+const FRAMES_PER_SECOND: u32 = 60;
+// How many frames a host that stalled gets to catch up on, rather than racing through
+// every frame it missed
+const MAX_FRAMES_BEHIND: u64 = 6;
+
+/// A source of time, so frame pacing can be tested without waiting.
+pub trait Clock {
+    /// Time since an arbitrary fixed point.
+    fn now(&self) -> Duration;
+
+    /// Waits for `duration` to pass.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// The host's monotonic clock.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Paces the main loop at 60 frames a second, counted from when the timer was made so
+/// rounding never adds up to drift.
+pub struct FrameTimer {
+    clock: Box<dyn Clock>,
+    fps: u32,
+    start: Duration,
+    ticks: u64,
+}
+
+impl FrameTimer {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self::with_fps(clock, FRAMES_PER_SECOND)
+    }
+
+    /// Paces frames at `fps` a second instead, for `--fps-cap`.
+    pub fn with_fps(clock: Box<dyn Clock>, fps: u32) -> Self {
+        assert!(fps > 0, "the frame rate must be positive");
+        let start = clock.now();
+        FrameTimer {
+            clock,
+            fps,
+            start,
+            ticks: 0,
+        }
+    }
+
+    /// Whether another frame is due, taking it if so. Frames missed while the
+    /// host was busy are due straight away, up to a few of them.
+    pub fn should_tick_timers(&mut self) -> bool {
+        let due = self.frames_since_start();
+        if due <= self.ticks {
+            return false;
+        }
+        self.ticks = self.ticks.max(due.saturating_sub(MAX_FRAMES_BEHIND)) + 1;
+        true
+    }
+
+    /// Sleeps until the next frame is due, if it isn't already.
+    pub fn sleep_until_next_frame(&mut self) {
+        let nanos = ((self.ticks as u128 + 1) * NANOS_PER_SECOND).div_ceil(self.fps as u128);
+        let next = self.start + Duration::from_nanos(nanos as u64);
+        if let Some(rest) = next.checked_sub(self.clock.now()) {
+            self.clock.sleep(rest);
+        }
+    }
+
+    fn frames_since_start(&self) -> u64 {
+        let elapsed = self.clock.now().saturating_sub(self.start);
+        (elapsed.as_nanos() * self.fps as u128 / NANOS_PER_SECOND) as u64
+    }
+}
+
+/// Decides which 60Hz frames get rendered when the display should refresh less often,
+/// spreading the rendered frames evenly. Rates of 60 and above render every frame.
+pub struct RenderSchedule {
+    fps: u32,
+    // Sixtieths of a render owed, rendering once a whole one has built up
+    credit: u32,
+}
+
+impl RenderSchedule {
+    pub fn new(fps: u32) -> Self {
+        RenderSchedule { fps, credit: 0 }
+    }
+
+    /// Called once per frame, returns whether this frame should be rendered.
+    pub fn due(&mut self) -> bool {
+        if self.fps >= 60 {
+            return true;
+        }
+        self.credit += self.fps;
+        if self.credit < 60 {
+            return false;
+        }
+        self.credit -= 60;
+        true
+    }
+}
+
+impl Default for RenderSchedule {
+    fn default() -> Self {
+        Self::new(60)
+    }
+}
+
+/// How many times the delay and sound timers count down in each 60Hz frame, for
+/// experimenting with timers at another rate. Fractions carry over to later frames,
+/// so over a second the timers count down exactly `hz` times.
+pub struct TimerRate {
+    hz: u32,
+    // Sixtieths of a tick carried over from earlier frames
+    credit: u32,
+}
+
+impl TimerRate {
+    pub fn new(hz: u32) -> Self {
+        TimerRate { hz, credit: 0 }
+    }
+
+    /// Called once per frame, returns how many ticks fall in it.
+    pub fn ticks_this_frame(&mut self) -> u32 {
+        self.credit += self.hz;
+        let ticks = self.credit / 60;
+        self.credit %= 60;
+        ticks
+    }
+}
+
+impl Default for TimerRate {
+    fn default() -> Self {
+        Self::new(60)
+    }
+}
+
+/// Instructions and frames actually run per second, as shown by `--show-stats`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rates {
+    pub ips: u64,
+    pub fps: f64,
+}
+
+impl Rates {
+    /// The rates for `cycles` instructions and `frames` frames run over `elapsed`.
+    pub fn over(cycles: u64, frames: u64, elapsed: Duration) -> Self {
+        let seconds = elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return Rates { ips: 0, fps: 0.0 };
+        }
+        Rates {
+            ips: (cycles as f64 / seconds).round() as u64,
+            fps: frames as f64 / seconds,
+        }
+    }
+}
+
+impl fmt::Display for Rates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} IPS {:.1} FPS", self.ips, self.fps)
+    }
+}
+
+/// Measures `Rates` over each second of wall-clock time.
+pub struct RateMeter {
+    clock: Box<dyn Clock>,
+    // When the current sample started, and the cycle counter then
+    since: Duration,
+    cycles: u64,
+    frames: u64,
+    last: Option<Rates>,
+}
+
+impl RateMeter {
+    pub fn new(clock: Box<dyn Clock>, cycles: u64) -> Self {
+        let since = clock.now();
+        RateMeter {
+            clock,
+            since,
+            cycles,
+            frames: 0,
+            last: None,
+        }
+    }
+
+    /// Called once per frame with the CPU's cycle counter. Returns the rates over the
+    /// last whole second, or `None` for the first second.
+    pub fn frame(&mut self, cycles: u64) -> Option<Rates> {
+        self.frames += 1;
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.since);
+        if elapsed >= Duration::from_secs(1) {
+            let executed = cycles.saturating_sub(self.cycles);
+            self.last = Some(Rates::over(executed, self.frames, elapsed));
+            self.since = now;
+            self.cycles = cycles;
+            self.frames = 0;
+        }
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::{
+        Clock, FrameTimer, RateMeter, Rates, RenderSchedule, TimerRate, MAX_FRAMES_BEHIND,
+    };
+
+    #[derive(Clone, Default)]
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+
+    fn ticks_over(timer: &mut FrameTimer, clock: &FakeClock, steps: u32, step: Duration) -> u32 {
+        let mut ticks = 0;
+        for _ in 0..steps {
+            clock.advance(step);
+            while timer.should_tick_timers() {
+                ticks += 1;
+            }
+        }
+        ticks
+    }
+
+    #[test]
+    fn ticks_at_60hz() {
+        let clock = FakeClock::default();
+        clock.advance(Duration::from_secs(5));
+        let mut timer = FrameTimer::new(Box::new(clock.clone()));
+        assert!(!timer.should_tick_timers());
+
+        // A tick every 16.67ms, however finely time is sliced
+        let ms = Duration::from_millis(1);
+        assert_eq!(ticks_over(&mut timer, &clock, 16, ms), 0);
+        assert_eq!(ticks_over(&mut timer, &clock, 1, ms), 1);
+        assert_eq!(ticks_over(&mut timer, &clock, 983, ms), 59);
+        let us = Duration::from_micros(1);
+        assert_eq!(ticks_over(&mut timer, &clock, 10_000_000, us), 600);
+    }
+
+    #[test]
+    fn ticks_at_capped_rate() {
+        let clock = FakeClock::default();
+        let mut timer = FrameTimer::with_fps(Box::new(clock.clone()), 30);
+        let ms = Duration::from_millis(1);
+        assert_eq!(ticks_over(&mut timer, &clock, 33, ms), 0);
+        assert_eq!(ticks_over(&mut timer, &clock, 1, ms), 1);
+        assert_eq!(ticks_over(&mut timer, &clock, 1966, ms), 59);
+
+        timer.sleep_until_next_frame();
+        assert_eq!(clock.now(), Duration::from_nanos(2_033_333_334));
+    }
+
+    #[test]
+    fn sleeps_until_the_next_frame() {
+        let clock = FakeClock::default();
+        let mut timer = FrameTimer::new(Box::new(clock.clone()));
+        for frame in 1..=120u64 {
+            timer.sleep_until_next_frame();
+            assert!(timer.should_tick_timers());
+            assert!(!timer.should_tick_timers());
+            let expected = (frame * 1_000_000_000).div_ceil(60);
+            assert_eq!(clock.now(), Duration::from_nanos(expected));
+        }
+        assert_eq!(clock.now(), Duration::from_secs(2));
+
+        // Being late already, there's nothing to wait for
+        clock.advance(Duration::from_millis(20));
+        timer.sleep_until_next_frame();
+        assert_eq!(clock.now(), Duration::from_millis(2020));
+    }
+
+    #[test]
+    fn stalls_catch_up_a_few_frames() {
+        let clock = FakeClock::default();
+        let mut timer = FrameTimer::new(Box::new(clock.clone()));
+        clock.advance(Duration::from_secs(3));
+        let mut ticks = 0;
+        while timer.should_tick_timers() {
+            ticks += 1;
+        }
+        assert_eq!(ticks, MAX_FRAMES_BEHIND);
+        // And then carries on in step with the clock
+        assert_eq!(
+            ticks_over(&mut timer, &clock, 60, Duration::from_micros(16_667)),
+            60
+        );
+    }
+
+    fn rendered(fps: u32, frames: usize) -> Vec<usize> {
+        let mut schedule = RenderSchedule::new(fps);
+        (0..frames).filter(|_| schedule.due()).collect()
+    }
+
+    #[test]
+    fn renders_every_frame_at_60() {
+        assert_eq!(rendered(60, 5), [0, 1, 2, 3, 4]);
+        assert_eq!(rendered(144, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn skips_frames_below_60() {
+        assert_eq!(rendered(30, 6), [1, 3, 5]);
+        assert_eq!(rendered(20, 9), [2, 5, 8]);
+        // Uneven rates still average out over a second
+        let frames = rendered(25, 60);
+        assert_eq!(frames.len(), 25);
+        assert!(frames
+            .windows(2)
+            .all(|pair| (2..=3).contains(&(pair[1] - pair[0]))));
+        assert_eq!(rendered(1, 120), [59, 119]);
+    }
+
+    #[test]
+    fn timer_rates() {
+        let ticks = |hz, frames| {
+            let mut rate = TimerRate::new(hz);
+            (0..frames)
+                .map(|_| rate.ticks_this_frame())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ticks(60, 3), [1, 1, 1]);
+        assert_eq!(ticks(120, 3), [2, 2, 2]);
+        assert_eq!(ticks(30, 4), [0, 1, 0, 1]);
+        assert_eq!(ticks(90, 4), [1, 2, 1, 2]);
+        assert_eq!(ticks(25, 60).iter().sum::<u32>(), 25);
+    }
+
+    #[test]
+    fn rates() {
+        let rates = Rates::over(1500, 30, Duration::from_millis(500));
+        assert_eq!(
+            rates,
+            Rates {
+                ips: 3000,
+                fps: 60.0
+            }
+        );
+        assert_eq!(rates.to_string(), "3000 IPS 60.0 FPS");
+        assert_eq!(Rates::over(7, 1, Duration::from_secs(3)).ips, 2);
+        assert_eq!(Rates::over(7, 1, Duration::ZERO).ips, 0);
+
+        let clock = FakeClock::default();
+        let mut meter = RateMeter::new(Box::new(clock.clone()), 100);
+        for frame in 1..50 {
+            clock.advance(Duration::from_millis(20));
+            assert_eq!(meter.frame(100 + frame * 10), None);
+        }
+        clock.advance(Duration::from_millis(20));
+        let expected = Some(Rates {
+            ips: 500,
+            fps: 50.0,
+        });
+        assert_eq!(meter.frame(600), expected);
+        // Holds until the next second is up
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(meter.frame(610), expected);
+    }
+}
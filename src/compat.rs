@@ -0,0 +1,144 @@
+//! Runs a directory of ROMs headlessly and reports which ones fail, for catching
+//! regressions across a ROM collection.
+
+use std::fs;
+use std::io::{self, sink, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::cpu::{ExecutionError, CPU};
+use crate::quirks::Quirks;
+use crate::rom::validate_rom;
+
+pub const DEFAULT_MAX_CYCLES: u64 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Reached a halt after this many instructions.
+    Halted(u64),
+    /// Settled into a loop that changes nothing, noticed after this many instructions.
+    Stable(u64),
+    /// Still running at the cycle limit, which is normal for games.
+    TimedOut,
+    Failed(ExecutionError),
+    /// The emulator panicked, with the panic message if there was one.
+    Panicked(String),
+}
+
+/// Notices a ROM idling in a loop more involved than a jump to itself, such as one
+/// that keeps redrawing the same screen.
+///
+/// The machine state is compared at the end of each frame: registers, I, the stack,
+/// timers and display, but not PC, which lands at different places in the loop. Loops
+/// that change registers partway through may look different from frame to frame and
+/// then go unnoticed.
+pub struct StableLoopDetector {
+    window: u32,
+    last: Option<MachineState>,
+    unchanged: u32,
+}
+
+#[derive(PartialEq)]
+struct MachineState {
+    registers: [u8; 16],
+    i: u16,
+    stack: Vec<u16>,
+    timers: (u8, u8),
+    display: Vec<u64>,
+}
+
+impl StableLoopDetector {
+    /// Reports a stable loop once the state has stayed the same for `window` frames.
+    pub fn new(window: u32) -> Self {
+        StableLoopDetector {
+            window,
+            last: None,
+            unchanged: 0,
+        }
+    }
+
+    /// Call after each frame. Returns whether the ROM has been stable for the window.
+    pub fn observe<R: Read, W: Write>(&mut self, cpu: &CPU<R, W>) -> bool {
+        let state = MachineState {
+            registers: cpu.registers(),
+            i: cpu.i(),
+            stack: cpu.stack().to_vec(),
+            timers: (cpu.delay_timer(), cpu.sound_timer()),
+            display: cpu.terminal().rows().to_vec(),
+        };
+        if self.last.as_ref() == Some(&state) {
+            self.unchanged += 1;
+        } else {
+            self.unchanged = 0;
+            self.last = Some(state);
+        }
+        self.unchanged >= self.window
+    }
+}
+
+/// Runs `rom` until it halts, fails or has executed `max_cycles` instructions, with no
+/// input and no display. With `stable_frames`, a ROM that settles into a loop for that
+/// many frames stops early too.
+pub fn run(rom: &[u8], max_cycles: u64, stable_frames: Option<u32>, quirks: Quirks) -> Outcome {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let input: &[u8] = b"";
+        let mut cpu = CPU::new(input, sink());
+        cpu.set_quirks(quirks);
+        cpu.load(rom);
+        let mut detector = stable_frames.map(StableLoopDetector::new);
+        while cpu.cycles() < max_cycles {
+            match cpu.run_frame() {
+                Ok(true) => (),
+                Ok(false) => return Outcome::Halted(cpu.cycles()),
+                Err(e) => return Outcome::Failed(e),
+            }
+            if let Some(detector) = &mut detector {
+                if detector.observe(&cpu) {
+                    return Outcome::Stable(cpu.cycles());
+                }
+            }
+        }
+        Outcome::TimedOut
+    }));
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Outcome::Panicked(message)
+    })
+}
+
+/// Runs every file in `dir` and lists the outcomes, one ROM per line in name order.
+pub fn report(
+    dir: &Path,
+    max_cycles: u64,
+    stable_frames: Option<u32>,
+    quirks: Quirks,
+) -> io::Result<String> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    let mut report = String::new();
+    for path in paths {
+        let rom = fs::read(&path)?;
+        let name = path.file_name().unwrap().to_string_lossy();
+        if let Err(e) = validate_rom(&rom) {
+            report += &format!("{}: invalid, {}\n", name, e);
+            continue;
+        }
+        let outcome = match run(&rom, max_cycles, stable_frames, quirks) {
+            Outcome::Halted(cycles) => format!("ok, halted after {} cycles", cycles),
+            Outcome::Stable(cycles) => format!("ok, stable after {} cycles", cycles),
+            Outcome::TimedOut => format!("timed out after {} cycles", max_cycles),
+            Outcome::Failed(e) => format!("error: {}", e),
+            Outcome::Panicked(message) => format!("panicked: {}", message),
+        };
+        report += &format!("{}: {}\n", name, outcome);
+    }
+    Ok(report)
+}
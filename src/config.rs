@@ -0,0 +1,940 @@
+use std::slice::Iter;
+
+use termion::event::Key;
+
+use crate::cpu::{
+    SeedSource, Timing, ZeroOpcode, DEFAULT_CYCLES_PER_FRAME, DEFAULT_STACK_DEPTH, MAX_STACK_DEPTH,
+};
+use crate::quirks::{IndexOverflowQuirk, LowResScrollQuirk, Quirks, ShiftQuirk};
+use crate::terminal::{Color, DEFAULT_BRIGHTNESS_RAMP};
+
+// Keeps the cycles run in a frame well within a u32 at any --fps-cap
+const MAX_SPEED: u32 = 1_000_000;
+// Faster timers than this run all the way down from 255 within a single frame anyway
+const MAX_TIMER_HZ: u32 = 60 * 255;
+
+pub const USAGE: &str = "\
+Usage: chip8 [OPTIONS] ROM
+
+Options:
+    --speed N            Cycles executed per 60Hz frame (default 10)
+    --hz N               Instructions per second, instead of --speed
+    --auto-speed         Raise the speed while the ROM isn't drawing, starting from
+                         --speed or --hz
+    --platform NAME      Interpreter whose quirks to follow: vip for the COSMAC VIP or
+                         modern for CHIP-48 and SUPER-CHIP (default modern, or what
+                         a game recognized by its checksum needs)
+    --shift MODE         What 8xy6 and 8xyE shift: vx for Vx = Vx, vy for Vx = Vy
+                         or vy-to-vy for Vy = Vy, instead of the platform's
+    --vf-reset, --load-store-increments-i, --display-wait, --add-i-wraps,
+    --wrap-draw-origin, --clip-sprites-x, --clip-sprites-y
+                         Switch a single quirk on, or off with --no-, instead of
+                         the platform's. Quirks given after --platform override it
+    --index-overflow MODE
+                         When ADD I, Vx sets VF: never, past-fff or past-ffff
+    --low-res-scroll MODE
+                         How far SUPER-CHIP scrolls move the low-res display:
+                         doubled or halved
+    --timing MODE        Instruction cost model: flat or weighted (default flat)
+    --seed-source SRC    Seed for random numbers: entropy, fixed:N for the same
+                         numbers every run, or rom-hash for the same numbers every
+                         run of the same ROM (default entropy)
+    --stack-depth N      Nested calls allowed before a stack overflow, up to 256
+                         (default 16)
+    --lenient            Skip unknown opcodes instead of stopping
+    --zero-opcode MODE   What 0000, as in zeroed memory, does: nop, halt to stop there
+                         or error for an unknown opcode (default nop)
+    --strict             Reject SUPER-CHIP extensions to the instruction set
+    --protect-reserved   Stop with an error when PC reaches the font and interpreter
+                         memory below 200, instead of running what's there
+    --no-hires           Run a ROM opening with JP 260 as plain CHIP-8, rather than
+                         as HIRES CHIP-8 with a 64x64 display starting at 260
+    --warn               Log likely ROM bugs to stderr, e.g. 2>chip8.log
+    --keymap-file FILE   Read `CHAR = KEY` lines mapping keyboard to keypad keys,
+                         instead of 0-9 and a-f
+    --splash             Show \"C8\" and the ROM's name for a second before it starts,
+                         or until a key is pressed
+    --no-splash          Start the ROM straight away, the default
+    --status-line        Show PC, the instruction there and the cycle count below
+                         the display
+    --show-stats         Show the instructions and frames actually run each second
+                         below the display, updated every second
+    --tui                Show the keypad beside the display, highlighting held keys
+    --anti-flicker       Keep pixels lit for an extra frame to hide XOR flicker
+    --double-buffer      Only show the display once each frame has finished drawing
+    --timer-hz N         Count the delay and sound timers down N times a second,
+                         up to 15300 (default 60)
+    --render-fps N       Draw at most N frames a second, still running at 60Hz
+                         (default 60)
+    --fps-cap N          Run N frames a second instead of 60, up to 60, to save power.
+                         Instructions run at the same rate, but the display and
+                         timers only update N times a second
+    --quit-key KEY       Key that quits: a character, esc or ctrl+C (default ctrl+c)
+    --cancel-key KEY     Key that finishes a wait for a key press (Fx0A) without
+                         pressing a keypad key, for debugging stuck ROMs
+    --ramp CHARS         Characters for pixel brightness from off to lit
+                         (default \" ░▒▓█\")
+    --on-char C          Character for lit pixels, replacing the end of the ramp
+    --off-char C         Character for unlit pixels, replacing the start of the ramp
+    --palette C,C,C,C    Color pixels by XO-CHIP plane with ANSI 256-color indices:
+                         unlit, first plane, second plane, both
+    --patch FILE         Apply a patch to the ROM, either IPS or `ADDR: BYTE` lines
+    --log-format FILE    Write a line per instruction executed to FILE, as
+                         PC OPCODE MNEMONIC V0..VF I SP, for diffing with other
+                         emulators
+    --draw-log FILE      Write a line to FILE for each sprite, clear and scroll, with
+                         the address of the instruction and the rows it touched
+    --compare-trace FILE Run the ROM without a display, checking each instruction
+                         against FILE, a --log-format trace from another emulator,
+                         and report where they first differ
+    --compare-registers  With --compare-trace, compare registers too, not just PC
+                         and opcode
+    --script FILE        Run the commands in FILE, a line each, without a display:
+                         load ROM, run FRAMES, press KEY, release KEY, screenshot
+                         FILE and assert_pixel X Y on|off. ROM is optional, and
+                         loaded before the first line
+    --preload ADDR FILE  Write FILE into memory from ADDR, in hex, before starting;
+                         can be given more than once
+    --start ADDR         Start running at ADDR, in hex, instead of 200
+    --map-display ADDR   Show the display in memory from ADDR, in hex, 8 bytes a row,
+                         for experimental ROMs that read or write it there
+    --watch              Reload the ROM and start it over whenever its file changes,
+                         for developing ROMs; --patch and --preload aren't applied
+                         again. Needs the watch feature
+    --max-cycles N       Stop after executing N instructions
+    --rewind N           Instructions Ctrl+B can undo while paused (default 1000)
+    --screenshot FILE    Write a screenshot to FILE when Ctrl+S is pressed, as PBM if
+                         FILE ends in .pbm and as text otherwise
+    --screenshot-on-exit FILE
+                         Write a screenshot to FILE when the emulator stops
+    --dump-font          Print the 16 font glyphs, no ROM needed
+    --stack-profile      Print the deepest the stack got and the CALLs leading there
+                         when the emulator stops
+    --dump-state FILE    Write the registers, timers and a hash of the display to FILE
+                         as JSON when the emulator stops
+    --test-suite         Run the built-in quirk test ROMs and report, no ROM needed
+    --compat-report DIR  Run every ROM in DIR headlessly for --max-cycles (default
+                         100000) and report which halt, fail or keep running
+    --stable-frames N    With --compat-report, also stop a ROM once its registers and
+                         display stay the same for N frames
+    -h, --help           Print this message
+
+Options can also be set per ROM in a file named after it with .toml added, such as
+pong.ch8.toml, holding `option = value` lines like `speed = 20` or `tui = true`.
+Options given on the command line take precedence, and those switched on in the
+file can be switched off with --no-, as --no-tui.
+
+Built with the zip feature, ROM can also name a file inside a zip archive, as
+games.zip:pong.ch8.
+
+Press F1 or ? while running to list the control keys.";
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub rom: String,
+    pub speed: u32,
+    pub auto_speed: bool,
+    pub timing: Timing,
+    pub seed_source: SeedSource,
+    pub quirks: Quirks,
+    /// Whether a recognized ROM's quirks replace `quirks`; --platform turns this off.
+    pub auto_quirks: bool,
+    pub stack_depth: usize,
+    pub lenient: bool,
+    pub zero_opcode: ZeroOpcode,
+    pub strict: bool,
+    pub protect_reserved: bool,
+    pub hires: bool,
+    pub warn: bool,
+    pub anti_flicker: bool,
+    pub double_buffer: bool,
+    pub tui: bool,
+    pub splash: bool,
+    pub status_line: bool,
+    pub show_stats: bool,
+    pub render_fps: u32,
+    pub fps_cap: u32,
+    pub timer_hz: u32,
+    pub quit_key: Key,
+    pub cancel_key: Option<Key>,
+    pub keymap_file: Option<String>,
+    pub ramp: Vec<char>,
+    pub on_char: Option<char>,
+    pub off_char: Option<char>,
+    pub palette: Option<[Color; 4]>,
+    pub patch: Option<String>,
+    pub instruction_log: Option<String>,
+    pub draw_log: Option<String>,
+    pub compare_trace: Option<String>,
+    pub compare_registers: bool,
+    pub script: Option<String>,
+    pub start: Option<u16>,
+    pub map_display: Option<u16>,
+    pub preload: Vec<(u16, String)>,
+    pub max_cycles: Option<u64>,
+    pub rewind: usize,
+    pub screenshot: Option<String>,
+    pub screenshot_on_exit: Option<String>,
+    pub dump_state: Option<String>,
+    pub stack_profile: bool,
+    pub watch: bool,
+    pub dump_font: bool,
+    pub test_suite: bool,
+    pub compat_report: Option<String>,
+    pub stable_frames: Option<u32>,
+    pub help: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rom: String::new(),
+            speed: DEFAULT_CYCLES_PER_FRAME,
+            auto_speed: false,
+            timing: Timing::Flat,
+            seed_source: SeedSource::Entropy,
+            quirks: Quirks::default(),
+            auto_quirks: true,
+            stack_depth: DEFAULT_STACK_DEPTH,
+            lenient: false,
+            zero_opcode: ZeroOpcode::Nop,
+            strict: false,
+            protect_reserved: false,
+            hires: true,
+            warn: false,
+            anti_flicker: false,
+            double_buffer: false,
+            tui: false,
+            splash: false,
+            status_line: false,
+            show_stats: false,
+            render_fps: 60,
+            fps_cap: 60,
+            timer_hz: 60,
+            quit_key: Key::Ctrl('c'),
+            cancel_key: None,
+            keymap_file: None,
+            ramp: DEFAULT_BRIGHTNESS_RAMP.to_vec(),
+            on_char: None,
+            off_char: None,
+            palette: None,
+            patch: None,
+            instruction_log: None,
+            draw_log: None,
+            compare_trace: None,
+            compare_registers: false,
+            script: None,
+            start: None,
+            map_display: None,
+            preload: Vec::new(),
+            max_cycles: None,
+            rewind: 1000,
+            screenshot: None,
+            screenshot_on_exit: None,
+            dump_state: None,
+            stack_profile: false,
+            watch: false,
+            dump_font: false,
+            test_suite: false,
+            compat_report: None,
+            stable_frames: None,
+            help: false,
+        }
+    }
+}
+
+/// Parses the command line arguments, not including the program name.
+pub fn parse_args(args: &[String]) -> Result<Config, String> {
+    parse_args_with(Config::default(), args)
+}
+
+/// Like `parse_args`, but starting from `base` instead of the defaults, typically as
+/// read by `parse_rom_config`. Options given in `args` replace those in `base`.
+pub fn parse_args_with(base: Config, args: &[String]) -> Result<Config, String> {
+    let mut config = base;
+    let rom = apply_options(&mut config, args)?;
+    if config.script.is_some() {
+        config.rom = rom.unwrap_or_default();
+    } else if !config.help
+        && !config.dump_font
+        && !config.test_suite
+        && config.compat_report.is_none()
+    {
+        config.rom = rom.ok_or_else(|| "missing ROM path".to_string())?;
+    }
+    Ok(config)
+}
+
+/// Parses a per-ROM config file. Each line is `option = value`, naming a command line
+/// option without its dashes, with the value optionally in double quotes. Options that
+/// take no value are switched on with `true` and off with `false`. Blank lines, `#`
+/// comments and `[section]` headers are ignored.
+pub fn parse_rom_config(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (n, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() || line.starts_with('[') {
+            continue;
+        }
+        let (option, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `option = value`, got `{}`", n + 1, line))?;
+        let option = option.trim();
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        let args = match value {
+            "true" => vec![format!("--{}", option)],
+            "false" => vec![format!("--no-{}", option)],
+            value => vec![format!("--{}", option), value.to_string()],
+        };
+        match apply_options(&mut config, &args) {
+            Ok(None) => (),
+            Ok(Some(value)) => {
+                return Err(format!(
+                    "line {}: {} takes `true` or `false`, got `{}`",
+                    n + 1,
+                    option,
+                    value
+                ))
+            }
+            Err(message) => return Err(format!("line {}: {}", n + 1, message)),
+        }
+    }
+    Ok(config)
+}
+
+/// Applies the options in `args` to `config`, returning the ROM path if one was given.
+fn apply_options(config: &mut Config, args: &[String]) -> Result<Option<String>, String> {
+    let mut rom = None;
+    let mut speed_given = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--no-") {
+            if let Some(on) = switch(config, name) {
+                *on = false;
+                continue;
+            }
+        }
+        if let Some(on) = arg.strip_prefix("--").and_then(|name| switch(config, name)) {
+            *on = true;
+            continue;
+        }
+        let quirk = match arg.strip_prefix("--no-") {
+            Some(name) => Some((name, false)),
+            None => arg.strip_prefix("--").map(|name| (name, true)),
+        };
+        if let Some((name, on)) = quirk {
+            if let Some(quirk) = quirk_switch(&mut config.quirks, name) {
+                *quirk = on;
+                config.auto_quirks = false;
+                continue;
+            }
+        }
+        match arg.as_str() {
+            "-h" | "--help" => config.help = true,
+            "--speed" | "--hz" => {
+                if speed_given {
+                    return Err("--speed and --hz can only be given once".to_string());
+                }
+                speed_given = true;
+                let n = match value(&mut args, arg)?.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("{} expects a positive number", arg)),
+                };
+                config.speed = if arg == "--hz" {
+                    hz_to_cycles_per_frame(n)
+                } else {
+                    n
+                };
+                if config.speed > MAX_SPEED {
+                    return Err(format!(
+                        "{} {} is more than {} cycles a frame",
+                        arg, n, MAX_SPEED
+                    ));
+                }
+            }
+            "--platform" => {
+                config.auto_quirks = false;
+                config.quirks = match value(&mut args, arg)? {
+                    "vip" => Quirks::cosmac(),
+                    "modern" => Quirks::default(),
+                    other => return Err(format!("unknown platform `{}`", other)),
+                }
+            }
+            "--shift" => {
+                config.auto_quirks = false;
+                config.quirks.shift = match value(&mut args, arg)? {
+                    "vx" => ShiftQuirk::ModernVx,
+                    "vy" => ShiftQuirk::LegacyVy,
+                    "vy-to-vy" => ShiftQuirk::LegacyVyToVy,
+                    other => return Err(format!("unknown shift `{}`", other)),
+                }
+            }
+            "--index-overflow" => {
+                config.auto_quirks = false;
+                config.quirks.index_overflow = match value(&mut args, arg)? {
+                    "never" => IndexOverflowQuirk::Unflagged,
+                    "past-fff" => IndexOverflowQuirk::Past0FFF,
+                    "past-ffff" => IndexOverflowQuirk::Past0FFFF,
+                    other => return Err(format!("unknown index overflow `{}`", other)),
+                }
+            }
+            "--low-res-scroll" => {
+                config.auto_quirks = false;
+                config.quirks.low_res_scroll = match value(&mut args, arg)? {
+                    "doubled" => LowResScrollQuirk::Doubled,
+                    "halved" => LowResScrollQuirk::Halved,
+                    other => return Err(format!("unknown low-res scroll `{}`", other)),
+                }
+            }
+            "--timing" => {
+                config.timing = match value(&mut args, arg)? {
+                    "flat" => Timing::Flat,
+                    "weighted" => Timing::Weighted,
+                    other => return Err(format!("unknown timing `{}`", other)),
+                }
+            }
+            "--seed-source" => {
+                let source = value(&mut args, arg)?;
+                config.seed_source = match source {
+                    "entropy" => SeedSource::Entropy,
+                    "rom-hash" => SeedSource::RomHash,
+                    _ => match source.strip_prefix("fixed:").map(str::parse) {
+                        Some(Ok(seed)) => SeedSource::Fixed(seed),
+                        _ => return Err(format!("unknown seed source `{}`", source)),
+                    },
+                }
+            }
+            "--on-char" => config.on_char = Some(single_char(&mut args, arg)?),
+            "--off-char" => config.off_char = Some(single_char(&mut args, arg)?),
+            "--stack-depth" => {
+                config.stack_depth = match value(&mut args, arg)?.parse() {
+                    Ok(n) if (1..=MAX_STACK_DEPTH).contains(&n) => n,
+                    _ => {
+                        return Err(format!(
+                            "--stack-depth expects a number from 1 to {}",
+                            MAX_STACK_DEPTH
+                        ))
+                    }
+                }
+            }
+            "--zero-opcode" => {
+                config.zero_opcode = match value(&mut args, arg)? {
+                    "nop" => ZeroOpcode::Nop,
+                    "halt" => ZeroOpcode::Halt,
+                    "error" => ZeroOpcode::Error,
+                    other => return Err(format!("unknown zero opcode mode `{}`", other)),
+                }
+            }
+            "--timer-hz" => {
+                config.timer_hz = match value(&mut args, arg)?.parse() {
+                    Ok(n) if (1..=MAX_TIMER_HZ).contains(&n) => n,
+                    _ => {
+                        return Err(format!(
+                            "--timer-hz expects a number from 1 to {}",
+                            MAX_TIMER_HZ
+                        ))
+                    }
+                }
+            }
+            "--render-fps" => {
+                config.render_fps = match value(&mut args, arg)?.parse() {
+                    Ok(n) if (1..=60).contains(&n) => n,
+                    _ => return Err("--render-fps expects a number from 1 to 60".to_string()),
+                }
+            }
+            "--fps-cap" => {
+                config.fps_cap = match value(&mut args, arg)?.parse() {
+                    Ok(n) if (1..=60).contains(&n) => n,
+                    _ => return Err("--fps-cap expects a number from 1 to 60".to_string()),
+                }
+            }
+            "--quit-key" => {
+                config.quit_key = parse_key(value(&mut args, arg)?)
+                    .ok_or_else(|| "--quit-key expects a character, esc or ctrl+C".to_string())?
+            }
+            "--cancel-key" => {
+                let key = parse_key(value(&mut args, arg)?)
+                    .ok_or_else(|| "--cancel-key expects a character, esc or ctrl+C".to_string())?;
+                config.cancel_key = Some(key)
+            }
+            "--keymap-file" => config.keymap_file = Some(value(&mut args, arg)?.to_string()),
+            "--ramp" => {
+                config.ramp = value(&mut args, arg)?.chars().collect();
+                if config.ramp.len() < 2 {
+                    return Err("--ramp expects at least two characters".to_string());
+                }
+            }
+            "--palette" => {
+                config.palette = Some(parse_palette(value(&mut args, arg)?).ok_or_else(|| {
+                    "--palette expects four color numbers from 0 to 255".to_string()
+                })?)
+            }
+            "--patch" => config.patch = Some(value(&mut args, arg)?.to_string()),
+            "--log-format" => config.instruction_log = Some(value(&mut args, arg)?.to_string()),
+            "--draw-log" => config.draw_log = Some(value(&mut args, arg)?.to_string()),
+            "--compare-trace" => config.compare_trace = Some(value(&mut args, arg)?.to_string()),
+            "--script" => config.script = Some(value(&mut args, arg)?.to_string()),
+            "--preload" => {
+                let addr = value(&mut args, arg)?;
+                let addr = addr.strip_prefix("0x").unwrap_or(addr);
+                let addr = match u16::from_str_radix(addr, 16) {
+                    Ok(addr) if addr < 0x1000 => addr,
+                    _ => return Err("--preload expects an address in hex and a file".to_string()),
+                };
+                config
+                    .preload
+                    .push((addr, value(&mut args, arg)?.to_string()));
+            }
+            "--start" => {
+                let addr = value(&mut args, arg)?;
+                let addr = addr.strip_prefix("0x").unwrap_or(addr);
+                config.start = match u16::from_str_radix(addr, 16) {
+                    Ok(addr) if addr < 0xFFF => Some(addr),
+                    _ => return Err("--start expects an address in hex below FFF".to_string()),
+                }
+            }
+            "--map-display" => {
+                let addr = value(&mut args, arg)?;
+                let addr = addr.strip_prefix("0x").unwrap_or(addr);
+                config.map_display = match u16::from_str_radix(addr, 16) {
+                    Ok(addr) if addr < 0x1000 => Some(addr),
+                    _ => return Err("--map-display expects an address in hex".to_string()),
+                }
+            }
+            "--max-cycles" => {
+                config.max_cycles = match value(&mut args, arg)?.parse() {
+                    Ok(n) => Some(n),
+                    _ => return Err("--max-cycles expects a number".to_string()),
+                }
+            }
+            "--rewind" => {
+                config.rewind = match value(&mut args, arg)?.parse() {
+                    Ok(n) => n,
+                    _ => return Err("--rewind expects a number".to_string()),
+                }
+            }
+            "--screenshot" => config.screenshot = Some(value(&mut args, arg)?.to_string()),
+            "--screenshot-on-exit" => {
+                config.screenshot_on_exit = Some(value(&mut args, arg)?.to_string())
+            }
+            "--dump-state" => config.dump_state = Some(value(&mut args, arg)?.to_string()),
+            "--dump-font" => config.dump_font = true,
+            "--test-suite" => config.test_suite = true,
+            "--compat-report" => config.compat_report = Some(value(&mut args, arg)?.to_string()),
+            "--stable-frames" => {
+                config.stable_frames = match value(&mut args, arg)?.parse() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err("--stable-frames expects a positive number".to_string()),
+                }
+            }
+            flag if flag.starts_with('-') => return Err(format!("unknown option `{}`", flag)),
+            path if rom.is_none() => rom = Some(path.to_string()),
+            extra => return Err(format!("unexpected argument `{}`", extra)),
+        }
+    }
+    Ok(rom)
+}
+
+/// The setting switched on by `--NAME` and off by `--no-NAME`, for the options that
+/// take no value and can be set in a per-ROM config file, so the command line can
+/// undo them.
+fn switch<'a>(config: &'a mut Config, name: &str) -> Option<&'a mut bool> {
+    Some(match name {
+        "auto-speed" => &mut config.auto_speed,
+        "lenient" => &mut config.lenient,
+        "protect-reserved" => &mut config.protect_reserved,
+        "strict" => &mut config.strict,
+        "hires" => &mut config.hires,
+        "warn" => &mut config.warn,
+        "anti-flicker" => &mut config.anti_flicker,
+        "double-buffer" => &mut config.double_buffer,
+        "tui" => &mut config.tui,
+        "splash" => &mut config.splash,
+        "status-line" => &mut config.status_line,
+        "show-stats" => &mut config.show_stats,
+        "stack-profile" => &mut config.stack_profile,
+        "watch" => &mut config.watch,
+        "compare-registers" => &mut config.compare_registers,
+        _ => return None,
+    })
+}
+
+/// The quirk named by `--NAME`, switched on by it and off by `--no-NAME`.
+fn quirk_switch<'a>(quirks: &'a mut Quirks, name: &str) -> Option<&'a mut bool> {
+    Some(match name {
+        "vf-reset" => &mut quirks.vf_reset,
+        "load-store-increments-i" => &mut quirks.load_store_increments_i,
+        "display-wait" => &mut quirks.display_wait,
+        "add-i-wraps" => &mut quirks.add_i_wraps_12_bits,
+        "wrap-draw-origin" => &mut quirks.wrap_draw_origin,
+        "clip-sprites-x" => &mut quirks.clip_sprites_x,
+        "clip-sprites-y" => &mut quirks.clip_sprites_y,
+        _ => return None,
+    })
+}
+
+/// `line` without its comment, which starts with a `#` at the start of the line or
+/// after whitespace, outside double quotes, so a value like `on-char = "#"` is kept.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut after_space = true;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && after_space => return &line[..i],
+            _ => (),
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+/// Converts a clock speed in instructions per second to the per-frame burst size at
+/// 60 frames per second, rounding to the nearest instruction but never below one.
+pub fn hz_to_cycles_per_frame(hz: u32) -> u32 {
+    (hz / 60 + (hz % 60 >= 30) as u32).max(1)
+}
+
+fn value<'a>(args: &mut Iter<'a, String>, flag: &str) -> Result<&'a str, String> {
+    args.next()
+        .map(|value| value.as_str())
+        .ok_or_else(|| format!("{} expects a value", flag))
+}
+
+fn single_char(args: &mut Iter<String>, flag: &str) -> Result<char, String> {
+    let mut chars = value(args, flag)?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("{} expects a single character", flag)),
+    }
+}
+
+/// Parses four comma-separated 256-color codes: unlit, first plane, second, both.
+fn parse_palette(colors: &str) -> Option<[Color; 4]> {
+    let colors = colors
+        .split(',')
+        .map(|color| color.trim().parse().ok().map(Color))
+        .collect::<Option<Vec<_>>>()?;
+    match colors[..] {
+        [unlit, first, second, both] => Some([unlit, first, second, both]),
+        _ => None,
+    }
+}
+
+/// Parses a key name: `esc`, `ctrl+` and a letter, or a single character.
+fn parse_key(name: &str) -> Option<Key> {
+    let lower = name.to_lowercase();
+    if lower == "esc" {
+        return Some(Key::Esc);
+    }
+    let (key, c): (fn(char) -> Key, &str) = match lower.strip_prefix("ctrl+") {
+        Some(rest) => (Key::Ctrl, rest),
+        None => (Key::Char, name),
+    };
+    let mut chars = c.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(key(c)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use termion::event::Key;
+
+    use super::{hz_to_cycles_per_frame, parse_args, parse_args_with, parse_rom_config, Config};
+    use crate::cpu::{SeedSource, Timing, ZeroOpcode};
+    use crate::quirks::{IndexOverflowQuirk, LowResScrollQuirk, Quirks, ShiftQuirk};
+    use crate::terminal::Color;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn rom_only() {
+        let config = parse_args(&args("pong.ch8")).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                rom: "pong.ch8".to_string(),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn all_options() {
+        let config = parse_args(&args(
+            "--speed 20 --auto-speed --timing weighted --seed-source fixed:42 --stack-depth 32 pong.ch8 --lenient --zero-opcode halt --strict --protect-reserved --no-hires --warn --anti-flicker --double-buffer --render-fps 30 --fps-cap 20 --timer-hz 120 --splash --status-line --show-stats --stack-profile --watch --tui --keymap-file keys.txt --screenshot out.pbm \
+             --screenshot-on-exit last.txt --dump-state state.json --max-cycles 1000 --start 0x2A0 --map-display F00 --preload 800 data.bin --rewind 50 --patch fix.txt --log-format trace.log --draw-log draw.log \
+             --compare-trace ref.log --compare-registers",
+        ))
+        .unwrap();
+        assert_eq!(config.rom, "pong.ch8");
+        assert_eq!(config.speed, 20);
+        assert!(config.auto_speed);
+        assert_eq!(config.stack_depth, 32);
+        assert_eq!(config.timing, Timing::Weighted);
+        assert_eq!(config.seed_source, SeedSource::Fixed(42));
+        assert!(config.lenient);
+        assert_eq!(config.zero_opcode, ZeroOpcode::Halt);
+        assert!(config.strict);
+        assert!(config.protect_reserved);
+        assert!(!config.hires);
+        assert!(config.warn);
+        assert!(config.anti_flicker);
+        assert!(config.double_buffer);
+        assert_eq!(config.render_fps, 30);
+        assert_eq!(config.fps_cap, 20);
+        assert_eq!(config.timer_hz, 120);
+        assert!(config.splash);
+        assert!(config.status_line);
+        assert!(config.show_stats);
+        assert!(config.stack_profile);
+        assert!(config.watch);
+        assert!(config.tui);
+        assert_eq!(config.keymap_file, Some("keys.txt".to_string()));
+        assert_eq!(config.screenshot, Some("out.pbm".to_string()));
+        assert_eq!(config.screenshot_on_exit, Some("last.txt".to_string()));
+        assert_eq!(config.dump_state, Some("state.json".to_string()));
+        assert_eq!(config.max_cycles, Some(1000));
+        assert_eq!(config.start, Some(0x2A0));
+        assert_eq!(config.map_display, Some(0xF00));
+        assert_eq!(config.preload, [(0x800, "data.bin".to_string())]);
+        assert_eq!(config.rewind, 50);
+        assert_eq!(config.patch, Some("fix.txt".to_string()));
+        assert_eq!(config.instruction_log, Some("trace.log".to_string()));
+        assert_eq!(config.draw_log, Some("draw.log".to_string()));
+        assert_eq!(config.compare_trace, Some("ref.log".to_string()));
+        assert!(config.compare_registers);
+    }
+
+    #[test]
+    fn quit_key() {
+        assert_eq!(parse_args(&args("a.ch8")).unwrap().quit_key, Key::Ctrl('c'));
+        let config = parse_args(&args("--quit-key Ctrl+Q a.ch8")).unwrap();
+        assert_eq!(config.quit_key, Key::Ctrl('q'));
+        let config = parse_args(&args("--quit-key esc a.ch8")).unwrap();
+        assert_eq!(config.quit_key, Key::Esc);
+        let config = parse_args(&args("--quit-key q a.ch8")).unwrap();
+        assert_eq!(config.quit_key, Key::Char('q'));
+        assert!(parse_args(&args("--quit-key ctrl+ a.ch8")).is_err());
+    }
+
+    #[test]
+    fn cancel_key() {
+        assert_eq!(parse_args(&args("a.ch8")).unwrap().cancel_key, None);
+        let config = parse_args(&args("--cancel-key ctrl+k a.ch8")).unwrap();
+        assert_eq!(config.cancel_key, Some(Key::Ctrl('k')));
+    }
+
+    #[test]
+    fn pixel_chars() {
+        let config = parse_args(&args("--ramp .:# a.ch8")).unwrap();
+        assert_eq!(config.ramp, ['.', ':', '#']);
+        let config = parse_args(&args("--on-char # --off-char . a.ch8")).unwrap();
+        assert_eq!((config.on_char, config.off_char), (Some('#'), Some('.')));
+    }
+
+    #[test]
+    fn seed_source() {
+        let source = |arg: &str| parse_args(&args(&format!("--seed-source {} a.ch8", arg)));
+        assert_eq!(source("rom-hash").unwrap().seed_source, SeedSource::RomHash);
+        assert_eq!(source("entropy").unwrap().seed_source, SeedSource::Entropy);
+        assert!(source("fixed:").is_err());
+        assert!(source("fixed:-1").is_err());
+        assert!(source("clock").is_err());
+    }
+
+    #[test]
+    fn single_quirks() {
+        let config = parse_args(&args("--platform vip --no-vf-reset --shift vy-to-vy a.ch8"));
+        let config = config.unwrap();
+        assert!(!config.auto_quirks);
+        assert_eq!(
+            config.quirks,
+            Quirks {
+                vf_reset: false,
+                shift: ShiftQuirk::LegacyVyToVy,
+                ..Quirks::cosmac()
+            }
+        );
+        // The platform given last replaces every quirk before it
+        let config = parse_args(&args("--display-wait --platform modern a.ch8")).unwrap();
+        assert_eq!(config.quirks, Quirks::default());
+        assert!(
+            !parse_args(&args("--clip-sprites-y a.ch8"))
+                .unwrap()
+                .auto_quirks
+        );
+        assert!(parse_args(&args("--index-overflow always a.ch8")).is_err());
+    }
+
+    #[test]
+    fn palette() {
+        assert_eq!(parse_args(&args("a.ch8")).unwrap().palette, None);
+        let config = parse_args(&args("--palette 0,15,9,11 a.ch8")).unwrap();
+        assert_eq!(
+            config.palette,
+            Some([Color(0), Color(15), Color(9), Color(11)])
+        );
+        assert!(parse_args(&args("--palette 0,15,9 a.ch8")).is_err());
+        assert!(parse_args(&args("--palette 0,15,9,256 a.ch8")).is_err());
+    }
+
+    #[test]
+    fn hz() {
+        assert_eq!(parse_args(&args("--hz 540 pong.ch8")).unwrap().speed, 9);
+        assert!(parse_args(&args("--hz 540 --speed 9 pong.ch8")).is_err());
+        assert!(parse_args(&args("--speed 9 --speed 10 pong.ch8")).is_err());
+        assert!(parse_args(&args("--hz 0 pong.ch8")).is_err());
+    }
+
+    #[test]
+    fn hz_to_cycles() {
+        assert_eq!(hz_to_cycles_per_frame(540), 9);
+        assert_eq!(hz_to_cycles_per_frame(500), 8);
+        assert_eq!(hz_to_cycles_per_frame(550), 9);
+        assert_eq!(hz_to_cycles_per_frame(570), 10);
+        assert_eq!(hz_to_cycles_per_frame(10), 1);
+        assert_eq!(hz_to_cycles_per_frame(u32::MAX), 71_582_788);
+    }
+
+    #[test]
+    fn test_suite() {
+        let config = parse_args(&args("--test-suite")).unwrap();
+        assert!(config.test_suite);
+        assert_eq!(config.rom, "");
+
+        assert!(parse_args(&args("--dump-font")).unwrap().dump_font);
+
+        let config = parse_args(&args("--compat-report roms --stable-frames 60")).unwrap();
+        assert_eq!(config.compat_report, Some("roms".to_string()));
+        assert_eq!(config.stable_frames, Some(60));
+
+        let config = parse_args(&args("--script demo.txt")).unwrap();
+        assert_eq!(config.script, Some("demo.txt".to_string()));
+        assert_eq!(config.rom, "");
+        let config = parse_args(&args("--script demo.txt pong.ch8")).unwrap();
+        assert_eq!(config.rom, "pong.ch8");
+    }
+
+    #[test]
+    fn help() {
+        assert!(parse_args(&args("--help")).unwrap().help);
+        assert!(parse_args(&args("pong.ch8 -h")).unwrap().help);
+    }
+
+    #[test]
+    fn rom_config() {
+        let file = parse_rom_config(
+            "# Blinky\n\
+             [emulator]\n\
+             platform = \"vip\"\n\
+             speed = 20\n\
+             keymap-file = \"blinky keys.txt\"\n\
+             tui = true\n\
+             anti-flicker = false\n",
+        )
+        .unwrap();
+        assert_eq!(file.quirks, Quirks::cosmac());
+        assert!(!file.auto_quirks);
+        assert_eq!(file.speed, 20);
+        assert_eq!(file.keymap_file, Some("blinky keys.txt".to_string()));
+        assert!(file.tui);
+        assert!(!file.anti_flicker);
+
+        let config =
+            parse_args_with(file, &args("--speed 8 --platform modern blinky.ch8")).unwrap();
+        assert_eq!(config.rom, "blinky.ch8");
+        assert_eq!(config.speed, 8);
+        assert_eq!(config.quirks, Quirks::default());
+        assert!(config.tui);
+
+        let config = parse_args_with(
+            parse_rom_config("tui = true\nsplash = true").unwrap(),
+            &args("--no-tui blinky.ch8"),
+        )
+        .unwrap();
+        assert!(!config.tui);
+        assert!(config.splash);
+        assert!(!parse_rom_config("hires = false").unwrap().hires);
+
+        // A `#` only starts a comment at the start of a line or after whitespace
+        let file = parse_rom_config("on-char = \"#\" # lit").unwrap();
+        assert_eq!(file.on_char, Some('#'));
+
+        assert_eq!(
+            parse_rom_config("tui = true\nspeed = fast"),
+            Err("line 2: --speed expects a positive number".to_string())
+        );
+        assert_eq!(
+            parse_rom_config("tui = yes"),
+            Err("line 1: tui takes `true` or `false`, got `yes`".to_string())
+        );
+        assert!(parse_rom_config("speed").is_err());
+        assert!(parse_rom_config("pong.ch8").is_err());
+
+        // Single quirks on top of the platform, as a game needs them
+        let file = parse_rom_config(
+            "platform = vip\n\
+             shift = vx\n\
+             load-store-increments-i = false\n\
+             clip-sprites-x = false\n\
+             index-overflow = past-fff\n\
+             low-res-scroll = halved\n",
+        )
+        .unwrap();
+        assert_eq!(
+            file.quirks,
+            Quirks {
+                shift: ShiftQuirk::ModernVx,
+                load_store_increments_i: false,
+                clip_sprites_x: false,
+                index_overflow: IndexOverflowQuirk::Past0FFF,
+                low_res_scroll: LowResScrollQuirk::Halved,
+                ..Quirks::cosmac()
+            }
+        );
+        assert_eq!(
+            parse_rom_config("shift = vz"),
+            Err("line 1: unknown shift `vz`".to_string())
+        );
+        assert!(parse_rom_config("rom = pong.ch8").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_args(&args("")).is_err());
+        assert!(parse_args(&args("pong.ch8 tetris.ch8")).is_err());
+        assert!(parse_args(&args("pong.ch8 --fast")).is_err());
+        assert!(parse_args(&args("pong.ch8 --speed")).is_err());
+        assert!(parse_args(&args("pong.ch8 --speed 0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --speed fast")).is_err());
+        assert!(parse_args(&args("pong.ch8 --timing slow")).is_err());
+        assert!(parse_args(&args("pong.ch8 --zero-opcode skip")).is_err());
+        assert!(parse_args(&args("pong.ch8 --ramp #")).is_err());
+        assert!(parse_args(&args("pong.ch8 --render-fps 0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --render-fps 61")).is_err());
+        assert!(parse_args(&args("pong.ch8 --speed 1000000")).is_ok());
+        assert!(parse_args(&args("pong.ch8 --speed 1000001")).is_err());
+        assert!(parse_args(&args("pong.ch8 --hz 4294967295")).is_err());
+        assert!(parse_args(&args("pong.ch8 --fps-cap 0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --fps-cap 61")).is_err());
+        assert!(parse_args(&args("pong.ch8 --timer-hz 0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --timer-hz 15301")).is_err());
+        assert!(parse_args(&args("pong.ch8 --stack-depth 0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --stack-depth 257")).is_err());
+        assert!(parse_args(&args("pong.ch8 --start 2G0")).is_err());
+        assert!(parse_args(&args("pong.ch8 --start FFF")).is_err());
+        assert!(parse_args(&args("pong.ch8 --preload 800")).is_err());
+        assert!(parse_args(&args("pong.ch8 --preload data.bin 800")).is_err());
+        assert!(parse_args(&args("pong.ch8 --on-char ##")).is_err());
+    }
+}
@@ -1,10 +1,31 @@
-use std::io::Read;
-
-use rand::random;
-
-use crate::terminal::Terminal;
-
-const MEMORY: usize = 4_096;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use log::{debug, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::backend;
+use crate::clock::{Clock, RenderSchedule, SystemClock, TimerRate};
+use crate::disassembler::mnemonic;
+use crate::known_roms::checksum;
+use crate::patch::Patch;
+use crate::quirks::{IndexOverflowQuirk, LowResScrollQuirk, Quirks, ShiftQuirk, QUIRKS_BYTES};
+use crate::terminal::{Clip, Terminal, DISPLAY_HEIGHT, MAX_DISPLAY_HEIGHT};
+
+pub(crate) const MEMORY: usize = 4_096;
+pub const DEFAULT_CYCLES_PER_FRAME: u32 = 10;
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+pub const MAX_STACK_DEPTH: usize = 256;
+// How many instructions `recent_instructions` remembers
+const RECENT_INSTRUCTIONS: usize = 16;
+// HIRES CHIP-8 ROMs open with JP 0x260 over the interpreter patch that follows
+const HIRES_PREFIX: [u8; 2] = [0x12, 0x60];
+const HIRES_START: u16 = 0x260;
 type Instruction = (u8, u8, u8, u8);
 
 const FONT: [u8; 80] = [
@@ -26,601 +47,3929 @@ const FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
-pub struct CPU<R: Read> {
-    terminal: Terminal<R>,
+/// Where the seed for RND (Cxkk) comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeedSource {
+    /// The operating system, so every run differs.
+    Entropy,
+    /// The same seed every run.
+    Fixed(u64),
+    /// The ROM's checksum, so each ROM plays out the same way every run.
+    RomHash,
+}
+
+impl SeedSource {
+    /// The seed for `rom`, or `None` to seed from the operating system.
+    pub fn seed(self, rom: &[u8]) -> Option<u64> {
+        match self {
+            SeedSource::Entropy => None,
+            SeedSource::Fixed(seed) => Some(seed),
+            SeedSource::RomHash => Some(checksum(rom)),
+        }
+    }
+}
+
+/// What the 0000 opcode does, which is usually a ROM running into zeroed memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZeroOpcode {
+    /// Nothing, as SYS 000, the other SYS addresses included.
+    Nop,
+    /// Stops the ROM there, as a jump to itself would.
+    Halt,
+    /// Fails as an unknown opcode, unless lenient.
+    Error,
+}
+
+/// How much of the per-frame cycle budget each instruction consumes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    /// Every instruction costs one cycle.
+    Flat,
+    /// Instructions cost roughly as much as they did on the original interpreter, so
+    /// screen and memory heavy frames execute fewer instructions.
+    Weighted,
+}
+
+impl Timing {
+    fn cost(self, instruction: Instruction) -> u32 {
+        if self == Timing::Flat {
+            return 1;
+        }
+        match instruction {
+            // CLS
+            (0, 0, 0xE, 0) => 4,
+            // DRW Vx, Vy, nibble
+            (0xD, _, _, n) => 2 + n as u32,
+            // LD B, Vx
+            (0xF, _, 3, 3) => 3,
+            // LD [I], Vx and LD Vx, [I]
+            (0xF, x, 5, 5) | (0xF, x, 6, 5) => 2 + x as u32 / 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExecutionError {
+    /// The instruction at `pc` doesn't decode to any known opcode.
+    UnknownOpcode { pc: u16, opcode: u16 },
+    /// CALL at `pc` with every stack entry in use.
+    StackOverflow { pc: u16 },
+    /// RET at `pc` with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// PC reached `pc`, below 0x200 where the font and interpreter live, with
+    /// `set_protect_reserved` on.
+    ReservedFetch { pc: u16 },
+    /// PC reached `pc`, too near the end of memory for a whole instruction to be
+    /// fetched, as a ROM running off its end or a jump past FFE does.
+    FetchOutOfMemory { pc: u16 },
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecutionError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:04X} at {:03X}", opcode, pc)
+            }
+            ExecutionError::StackOverflow { pc } => {
+                write!(f, "stack overflow calling from {:03X}", pc)
+            }
+            ExecutionError::StackUnderflow { pc } => {
+                write!(f, "return with an empty stack at {:03X}", pc)
+            }
+            ExecutionError::ReservedFetch { pc } => {
+                write!(f, "fetching at {:03X}, below the program at 200", pc)
+            }
+            ExecutionError::FetchOutOfMemory { pc } => {
+                write!(f, "fetching at {:03X}, past the end of memory", pc)
+            }
+        }
+    }
+}
+
+impl Error for ExecutionError {}
+
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+/// Bumped whenever `save_state`'s layout changes; older states are refused.
+const STATE_VERSION: u8 = 1;
+
+/// Why `load_state` refused a save state.
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    /// It doesn't start the way save states do.
+    NotAState,
+    /// It was saved in a layout this version doesn't read.
+    UnsupportedVersion(u8),
+    /// It ends early or holds values no machine could be in.
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} can't be read, only version {}",
+                version, STATE_VERSION
+            ),
+            StateError::Corrupt => write!(f, "the save state is truncated or corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Reads a save state front to back.
+struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < n {
+            return Err(StateError::Corrupt);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn flag(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+}
+
+/// How far `advance` runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Advance {
+    /// One instruction, without ticking the timers.
+    Instruction,
+    /// One frame, as `step_frame`.
+    Frame,
+    /// This many instructions, ticking the timers after each `cycles_per_frame` of
+    /// them as whole frames would. A part frame left at the end doesn't tick them.
+    Cycles(u32),
+    /// Whole frames until the ROM halts or the user quits; forever if it never does.
+    UntilHalt,
+}
+
+/// What happened in a frame run by `step_frame`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameResult {
+    /// Instructions executed, fewer than the budget if the frame stopped early.
+    pub cycles: u32,
+    /// Whether the sound timer is still running after the frame.
+    pub beeping: bool,
+    /// Whether the ROM jumped to itself, so more frames would do nothing.
+    pub halted: bool,
+}
+
+/// Why `trace_to` stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEnd {
+    /// PC reached the address, before the instruction there ran.
+    Reached,
+    Halted,
+    Quit,
+    /// It took every step allowed without getting there.
+    OutOfSteps,
+}
+
+/// How `trace_to` went.
+#[derive(Debug, PartialEq)]
+pub struct Trace {
+    pub end: TraceEnd,
+    /// The address and opcode of each instruction run, oldest first.
+    pub steps: Vec<(u16, u16)>,
+}
+
+/// How deep the stack got over a run, as recorded after `set_stack_profile(true)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StackProfile {
+    /// The stack depth at the end of each frame.
+    pub depths: Vec<usize>,
+    /// The deepest the stack got, checked on every CALL rather than once a frame.
+    pub max_depth: usize,
+    /// Where the CALL that reached `max_depth` went.
+    pub max_pc: u16,
+    /// The CALLs on the stack at `max_depth`, outermost first.
+    pub trail: Vec<u16>,
+}
+
+impl fmt::Display for StackProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.max_depth == 0 {
+            return writeln!(f, "max stack depth 0, no CALLs");
+        }
+        let trail: Vec<String> = self.trail.iter().map(|pc| format!("{:03X}", pc)).collect();
+        writeln!(
+            f,
+            "max stack depth {} at {:03X}, called from {}",
+            self.max_depth,
+            self.max_pc,
+            trail.join(" > ")
+        )?;
+        let mean = self.depths.iter().sum::<usize>() as f64 / self.depths.len().max(1) as f64;
+        writeln!(
+            f,
+            "{} frames, mean depth at frame end {:.1}",
+            self.depths.len(),
+            mean
+        )
+    }
+}
+
+/// Opt-in runtime checks for things ROMs usually only do by mistake. Each one logs a
+/// warning and is remembered as the CPU's `last_warning`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    /// DRW, Fx33, Fx55 or Fx65 use I before the ROM ever set it.
+    pub uninitialized_i: bool,
+    /// A jump, call or return leaves PC at an odd address. Fetching from there works,
+    /// but reads across instruction boundaries.
+    pub odd_pc: bool,
+    /// PC moves past the last byte `load`ed, as when a ROM runs off its end into the
+    /// zeroed memory after it, whose 0000 opcodes do nothing.
+    pub past_rom_end: bool,
+    /// A jump, call or return leaves PC below 0x200, where the interpreter and font
+    /// live rather than the ROM.
+    pub below_program: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warning {
+    UninitializedI { pc: u16 },
+    OddPc { pc: u16, target: u16 },
+    PastRomEnd { pc: u16, target: u16 },
+    BelowProgram { pc: u16, target: u16 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UninitializedI { pc } => {
+                write!(f, "instruction at {:03X} uses I before it was set", pc)
+            }
+            Warning::OddPc { pc, target } => {
+                write!(
+                    f,
+                    "instruction at {:03X} moves PC to odd address {:03X}",
+                    pc, target
+                )
+            }
+            Warning::PastRomEnd { pc, target } => write!(
+                f,
+                "instruction at {:03X} moves PC to {:03X}, past the end of the ROM",
+                pc, target
+            ),
+            Warning::BelowProgram { pc, target } => write!(
+                f,
+                "instruction at {:03X} moves PC to {:03X}, below the program at 200",
+                pc, target
+            ),
+        }
+    }
+}
+
+/// The machine state before a step, for rewinding. Memory is stored as the bytes the
+/// step overwrote rather than a full copy.
+struct Snapshot {
+    v: [u8; 16],
+    i: u16,
+    i_initialized: bool,
+    pc: u16,
+    sp: usize,
+    stack: Vec<u16>,
+    dt: u8,
+    st: u8,
+    cycles: u64,
+    halted: bool,
+    pixels: Vec<u64>,
+    plane2: Vec<u64>,
+    planes: u8,
+    overwritten: Vec<(usize, u8)>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<R: Read, W: Write> {
+    terminal: Terminal<R, W>,
     memory: [u8; MEMORY],
-    stack: [u16; 16],
+    stack: Vec<u16>,
     v: [u8; 16], // General purpose registers
     i: u16,
-    dt: u8,  // Delay timer
-    st: u8,  // Sound timer
-    pc: u16, // Program counter aka instruction pointer
-    sp: u8,  // Stack pointer
+    i_initialized: bool,
+    dt: u8,    // Delay timer
+    st: u8,    // Sound timer
+    pc: u16,   // Program counter aka instruction pointer
+    sp: usize, // Stack pointer
+    cycles: u64,
+    halted: bool,
+    // Sprites drawn in the frame being run, and in the last whole frame
+    draws: u32,
+    draws_last_frame: u32,
+    cycles_per_frame: u32,
+    // Cycles left over from a frame that ran out of time
+    carried_cycles: u32,
+    frame_time_budget: Option<Duration>,
+    clock: Box<dyn Clock>,
+    render_schedule: RenderSchedule,
+    timer_rate: TimerRate,
+    timing: Timing,
+    zero_opcode: ZeroOpcode,
+    quirks: Quirks,
+    lenient: bool,
+    strict: bool,
+    protect_reserved: bool,
+    diagnostics: Diagnostics,
+    last_warning: Option<Warning>,
+    // Just past the last byte loaded
+    rom_end: u16,
+    detect_hires: bool,
+    // Where the display shows up in memory, if anywhere
+    mapped_display: Option<u16>,
+    history: VecDeque<Snapshot>,
+    rewind_depth: usize,
+    breakpoints: HashSet<u16>,
+    instruction_log: Option<Box<dyn Write>>,
+    draw_log: Option<Box<dyn Write>>,
+    display: Option<Box<dyn backend::Display>>,
+    rng: StdRng,
+    stack_profile: Option<StackProfile>,
+    recent: VecDeque<(u16, u16)>,
+    memory_view: Option<u16>,
 }
 
-impl<R: Read> CPU<R> {
-    pub fn new(r: R) -> Self {
+impl<R: Read, W: Write> CPU<R, W> {
+    pub fn new(r: R, w: W) -> Self {
         let mut memory = [0; MEMORY];
         memory[..FONT.len()].clone_from_slice(&FONT[..]);
 
-        let mut terminal = Terminal::new(r);
+        let terminal = Terminal::new(r, w);
 
         CPU {
             terminal,
             memory,
-            stack: [0; 16],
+            stack: vec![0; DEFAULT_STACK_DEPTH],
             v: [0; 16],
             i: 0,
+            i_initialized: false,
             dt: 0,
             st: 0,
             pc: 0x200,
             sp: 0,
+            cycles: 0,
+            halted: false,
+            draws: 0,
+            draws_last_frame: 0,
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            carried_cycles: 0,
+            frame_time_budget: None,
+            clock: Box::new(SystemClock::new()),
+            render_schedule: RenderSchedule::default(),
+            timer_rate: TimerRate::default(),
+            timing: Timing::Flat,
+            zero_opcode: ZeroOpcode::Nop,
+            quirks: Quirks::default(),
+            lenient: false,
+            strict: false,
+            protect_reserved: false,
+            diagnostics: Diagnostics::default(),
+            last_warning: None,
+            rom_end: MEMORY as u16,
+            detect_hires: true,
+            mapped_display: None,
+            history: VecDeque::new(),
+            rewind_depth: 0,
+            breakpoints: HashSet::new(),
+            instruction_log: None,
+            draw_log: None,
+            display: None,
+            rng: StdRng::from_entropy(),
+            stack_profile: None,
+            recent: VecDeque::with_capacity(RECENT_INSTRUCTIONS),
+            memory_view: None,
         }
     }
 
-    pub fn tick(&mut self, update_timers: bool) -> bool {
-        if self.terminal.exit {
-            return false;
-        }
-        let instruction = self.read_instruction();
-        self.execute_instruction(instruction);
-        if self.dt > 0 && update_timers {
-            self.dt -= 1
+    pub fn terminal(&self) -> &Terminal<R, W> {
+        &self.terminal
+    }
+
+    pub fn terminal_mut(&mut self) -> &mut Terminal<R, W> {
+        &mut self.terminal
+    }
+
+    /// How many nested CALLs the stack holds before CALL fails with `StackOverflow`.
+    /// Some interpreters went beyond the original 16.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack.resize(depth, 0);
+        self.sp = self.sp.min(depth);
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles;
+    }
+
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    /// How many DRW instructions the last frame executed.
+    pub fn draws_last_frame(&self) -> u32 {
+        self.draws_last_frame
+    }
+
+    /// Limits how long a frame's instructions may take. A frame that runs out of time
+    /// stops early so timers and rendering stay on schedule, and its unspent cycles are
+    /// added to the next frame, at most one frame's worth so a slow host can't fall
+    /// ever further behind.
+    pub fn set_frame_time_budget(&mut self, budget: Option<Duration>) {
+        self.frame_time_budget = budget;
+    }
+
+    /// Renders at most `fps` frames a second, for terminals too slow to keep up with
+    /// 60. Instructions and timers still run every frame; the frames in between just
+    /// aren't drawn.
+    pub fn set_render_fps(&mut self, fps: u32) {
+        self.render_schedule = RenderSchedule::new(fps);
+    }
+
+    /// Counts the delay and sound timers down `hz` times a second instead of 60,
+    /// still in step with the 60Hz frames: at 120 they count twice a frame, at 30
+    /// every other frame.
+    pub fn set_timer_hz(&mut self, hz: u32) {
+        self.timer_rate = TimerRate::new(hz);
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Cycles the last frame couldn't fit in its time budget, to be run next frame.
+    pub fn carried_cycles(&self) -> u32 {
+        self.carried_cycles
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    /// What 0000 does; `ZeroOpcode::Nop` unless set.
+    pub fn set_zero_opcode(&mut self, zero_opcode: ZeroOpcode) {
+        self.zero_opcode = zero_opcode;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// In lenient mode unknown opcodes are skipped instead of stopping execution.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Makes RND produce the same numbers every run for the same `seed`, rather than
+    /// numbers seeded from the operating system.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Starts or stops recording a `StackProfile`; starting again begins a new one.
+    pub fn set_stack_profile(&mut self, enabled: bool) {
+        self.stack_profile = if enabled {
+            Some(StackProfile::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn stack_profile(&self) -> Option<&StackProfile> {
+        self.stack_profile.as_ref()
+    }
+
+    /// Whether `load` recognizes HIRES CHIP-8 ROMs by their opening JP 0x260, and
+    /// switches to a 64x64 display starting at 0x260 for them. On by default; turn it
+    /// off for an ordinary ROM that happens to open with the same jump.
+    pub fn set_hires_detection(&mut self, detect_hires: bool) {
+        self.detect_hires = detect_hires;
+    }
+
+    /// Maps the display into memory from `addr`, 8 bytes to a row with the leftmost
+    /// pixel in the top bit, so Fx65 reads the pixels there and Fx55 and Fx33 write
+    /// them instead of memory. No interpreter did this; it's for experimental ROMs
+    /// that peek at the display. `None`, the default, keeps it out of memory.
+    pub fn set_mapped_display(&mut self, addr: Option<u16>) {
+        self.mapped_display = addr;
+    }
+
+    /// In strict mode only the original CHIP-8 instruction set is accepted, and
+    /// SUPER-CHIP extensions are reported as unknown opcodes.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Fails with `ReservedFetch` rather than fetching an instruction below 0x200, which
+    /// only a bad jump, call or return gets to. Off by default, running whatever font
+    /// bytes or zeroes are there as the original interpreters would.
+    pub fn set_protect_reserved(&mut self, protect_reserved: bool) {
+        self.protect_reserved = protect_reserved;
+    }
+
+    /// How many steps `step_back` can undo; 0 disables recording.
+    pub fn set_rewind_depth(&mut self, steps: usize) {
+        self.rewind_depth = steps;
+        while self.history.len() > steps {
+            self.history.pop_front();
         }
-        if self.st > 0 && update_timers {
-            self.st -= 1
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.diagnostics = diagnostics;
+    }
+
+    /// The most recent warning raised by an enabled diagnostic.
+    pub fn last_warning(&self) -> Option<Warning> {
+        self.last_warning
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    /// Sets the delay timer as Fx15 would, for trying out timing-dependent code.
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.dt = value;
+    }
+
+    /// Sets the sound timer as Fx18 would.
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.st = value;
+    }
+
+    /// V0 to VF.
+    pub fn registers(&self) -> [u8; 16] {
+        self.v
+    }
+
+    /// The index register I.
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// The address of the next instruction to execute.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Moves execution to `addr`, such as a subroutine to run on its own after `load`.
+    /// Fails unless a whole instruction fits there.
+    pub fn set_pc(&mut self, addr: u16) -> Result<(), String> {
+        if addr as usize + 1 >= MEMORY {
+            return Err(format!("{:03X} is past the end of memory", addr));
         }
-        self.terminal.render();
-        true
+        self.pc = addr;
+        Ok(())
     }
 
-    pub fn load(&mut self, data: &[u8]) {
-        self.memory[0x200..].clone_from_slice(data);
+    /// The return addresses of the CALLs in progress, outermost first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
     }
 
-    fn read_instruction(&self) -> Instruction {
-        let first_byte = self.memory[self.pc as usize];
-        let second_byte = self.memory[self.pc as usize + 1];
-        (
-            first_byte >> 4,
-            first_byte & 0xF,
-            second_byte >> 4,
-            second_byte & 0xF,
-        )
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
     }
 
-    fn execute_instruction(&mut self, instruction: Instruction) {
-        // Increment program counter to point to the next instruction
-        self.pc += 2;
+    /// The address the debugger's memory view starts at while it is on the display.
+    pub fn memory_view(&self) -> Option<u16> {
+        self.memory_view
+    }
 
-        match instruction {
-            // CLS
-            (0, 0, 0xE, 0) => self.terminal.clear(),
-            // RET
-            (0, 0, 0xE, 0xE) => self.ret(),
-            // JP addr
-            (1, a, b, c) => self.pc = addr(a, b, c),
-            // CALL addr
-            (2, a, b, c) => self.call_addr(a, b, c),
-            // SE Vx, byte
-            (3, x, k1, k2) => self.se_vx_byte(x, k1, k2),
-            // SNE Vx, byte
-            (4, x, k1, k2) => self.sne_vx_byte(x, k1, k2),
-            // SE Vx, Vy
-            (5, x, y, 0) => self.se_vx_vy(x, y),
-            // LD Vx, byte
-            (6, x, k1, k2) => self.v[x as usize] = to_byte(k1, k2),
-            // ADD Vx, byte
-            (7, x, k1, k2) => {
-                self.v[x as usize] = (self.v[x as usize] as u16 + to_byte(k1, k2) as u16) as u8
-            }
-            // LD Vx, Vy
-            (8, x, y, 0) => self.v[x as usize] = self.v[y as usize],
-            // OR Vx, Vy
-            (8, x, y, 1) => self.v[x as usize] = self.v[x as usize] | self.v[y as usize],
-            // AND Vx, Vy
-            (8, x, y, 2) => self.v[x as usize] = self.v[x as usize] & self.v[y as usize],
-            // XOR Vx, Vy
-            (8, x, y, 3) => self.v[x as usize] = self.v[x as usize] ^ self.v[y as usize],
-            // ADD Vx, Vy
-            (8, x, y, 4) => self.add_vx_vy(x, y),
-            // SUB Vx, Vy
-            (8, x, y, 5) => self.sub_vx_vy(x, y),
-            // SHR Vx {, Vy}
-            (8, x, _, 6) => self.shr_vx(x),
-            // SUBN Vx, Vy
-            (8, x, y, 7) => self.subn_vx_vy(x, y),
-            // SHL Vx {, Vy}
-            (8, x, _, 0xE) => self.shl_vx(x),
-            // SNE Vx, Vy
-            (9, x, y, 0) => self.sne_vx_vy(x, y),
-            // SLD I, addr
-            (0xA, a, b, c) => self.i = addr(a, b, c),
-            // JP V0, addr
-            (0xB, a, b, c) => self.pc = self.v[0] as u16 + addr(a, b, c),
-            // RND Vx, byte
-            (0xC, x, k1, k2) => self.v[x as usize] = random::<u8>() & to_byte(k1, k2),
-            // DRW Vx, Vy, nibble
-            (0xD, x, y, n) => {
-                self.v[0xF] = self.terminal.draw_sprite(
-                    self.v[x as usize],
-                    self.v[y as usize],
-                    &self.memory[self.i as usize..(self.i as usize) + (n as usize)],
-                )
+    pub fn set_memory_view(&mut self, addr: Option<u16>) {
+        self.memory_view = addr;
+    }
+
+    /// How many CALLs are in progress.
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Total number of instructions executed.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Whether the ROM jumped to its own address, which is how CHIP-8 programs stop.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether the buzzer should currently be sounding; audio backends poll this each frame.
+    pub fn is_beeping(&self) -> bool {
+        self.st > 0
+    }
+
+    /// Runs one 60Hz frame: spends the cycle budget on instructions, then updates the
+    /// timers and renders. Returns false once the user asked to quit or the ROM halted.
+    ///
+    /// Front-ends that own their loop can do the same at their own cadence: per frame,
+    /// call `step_frame`, or `step` for each instruction then `tick_timers` once, and
+    /// show the framebuffer.
+    pub fn run_frame(&mut self) -> Result<bool, ExecutionError> {
+        self.terminal.poll_input();
+        if self.terminal.exit || self.halted {
+            return Ok(false);
+        }
+        self.step_frame()?;
+        if self.render_schedule.due() {
+            self.present();
+        } else {
+            self.terminal.set_sound(self.is_beeping());
+        }
+        Ok(!self.halted)
+    }
+
+    /// Runs one 60Hz frame without reading input or rendering: spends the cycle budget
+    /// on instructions, then counts the timers down once. The unit of work for a
+    /// front-end's own frame callback.
+    pub fn step_frame(&mut self) -> Result<FrameResult, ExecutionError> {
+        let cycles = self.execute_frame()?;
+        self.tick_timers();
+        if let Some(profile) = self.stack_profile.as_mut() {
+            profile.depths.push(self.sp);
+        }
+        Ok(FrameResult {
+            cycles,
+            beeping: self.is_beeping(),
+            halted: self.halted,
+        })
+    }
+
+    /// Runs as far as `advance` says, for headless runs that pick their own pace.
+    /// Returns how many instructions were executed, stopping early if the ROM halts.
+    pub fn advance(&mut self, advance: Advance) -> Result<u32, ExecutionError> {
+        match advance {
+            Advance::Instruction if self.halted => Ok(0),
+            Advance::Instruction => self.step().map(|_| 1),
+            Advance::Frame => self.step_frame().map(|frame| frame.cycles),
+            Advance::Cycles(n) => {
+                let mut executed = 0;
+                while executed < n && !self.halted {
+                    self.step()?;
+                    executed += 1;
+                    if executed % self.cycles_per_frame.max(1) == 0 {
+                        self.tick_timers();
+                    }
+                }
+                Ok(executed)
             }
-            // SKP Vx
-            (0xE, x, 9, 0xE) => {
-                if self.terminal.check_if_pressed(self.v[x as usize]) {
-                    self.pc += 2
+            Advance::UntilHalt => {
+                let mut executed = 0;
+                while !self.halted && !self.terminal.exit {
+                    executed += self.step_frame()?.cycles;
                 }
+                Ok(executed)
             }
-            // SKNP Vx
-            (0xE, x, 0xA, 1) => {
-                if !self.terminal.check_if_pressed(self.v[x as usize]) {
-                    self.pc += 2
+        }
+    }
+
+    /// Fetches and executes a single instruction.
+    pub fn step(&mut self) -> Result<(), ExecutionError> {
+        if self.protect_reserved && self.pc < 0x200 {
+            return Err(ExecutionError::ReservedFetch { pc: self.pc });
+        }
+        if self.pc as usize + 1 >= MEMORY {
+            return Err(ExecutionError::FetchOutOfMemory { pc: self.pc });
+        }
+        self.record_snapshot();
+        let instruction = self.read_instruction();
+        if self.recent.len() == RECENT_INSTRUCTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((self.pc, opcode(instruction)));
+        if let Some(log) = self.instruction_log.as_mut() {
+            let line = format_trace_line(self.pc, instruction, &self.v, self.i, self.sp);
+            if let Err(e) = writeln!(log, "{}", line) {
+                self.instruction_log = None;
+                self.log_failed("instruction log", e);
+            }
+        }
+        let pc = self.pc;
+        let result = self.execute_instruction(instruction);
+        for event in self.terminal.take_draw_events() {
+            if let Some(log) = self.draw_log.as_mut() {
+                if let Err(e) = writeln!(log, "{:03X} {}", pc, event) {
+                    self.draw_log = None;
+                    self.record_draw_events();
+                    self.log_failed("draw log", e);
                 }
             }
-            // LD Vx, DT
-            (0xF, x, 0, 7) => self.v[x as usize] = self.dt,
-            // LD Vx, K
-            (0xF, x, 0, 0xA) => match self.terminal.wait_for_key_press() {
-                Some(key) => self.v[x as usize] = key,
-                None => self.pc -= 2,
-            },
-            // LD DT, Vx
-            (0xF, x, 1, 5) => self.dt = self.v[x as usize],
-            // LD ST, Vx
-            (0xF, x, 1, 8) => self.st = self.v[x as usize],
-            // ADD I, Vx
-            (0xF, x, 1, 0xE) => self.i = self.i + self.v[x as usize] as u16,
-            // LD F, Vx
-            (0xF, x, 2, 9) => self.i = (self.v[x as usize] & 0xF) as u16 * 5,
-            // LD B, Vx
-            (0xF, x, 3, 3) => self.ld_b_vx(x),
-            // LD [I], Vx
-            (0xF, x, 5, 5) => self.ld_i_vx(x),
-            // LD Vx, [I]
-            (0xF, x, 6, 5) => self.ld_vx_i(x),
-            // SYS addr
-            (0, _, _, _) => (), // Ignored by modern interpreters
-            x => panic!("Unrecognized instruction: {:?}", x),
+            if let Some(display) = self.display.as_mut() {
+                display.draw(&event);
+            }
         }
+        result
+    }
+
+    /// Writes a line to `log` before each instruction runs, for diffing against other
+    /// emulators: `PC OPCODE MNEMONIC V0..VF I SP`, all in hex, with the mnemonic padded
+    /// to 16 columns, e.g.
+    /// `0202 A300 LD I, 0x300      01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0000 0`.
+    pub fn set_instruction_log(&mut self, log: Option<Box<dyn Write>>) {
+        self.instruction_log = log;
+    }
+
+    /// Writes a line to `log` for each change an instruction makes to the display: the
+    /// instruction's address, then the sprite, clear or scroll, e.g.
+    /// `20C sprite 8,1 5 rows collision`.
+    pub fn set_draw_log(&mut self, log: Option<Box<dyn Write>>) {
+        self.draw_log = log;
+        self.record_draw_events();
+    }
+
+    /// A backend shown each frame alongside the terminal: it's told of every sprite,
+    /// clear and scroll as the ROM makes them, then presented the frame whenever the
+    /// terminal renders one.
+    pub fn set_display(&mut self, display: Option<Box<dyn backend::Display>>) {
+        self.display = display;
+        self.record_draw_events();
+    }
+
+    fn record_draw_events(&mut self) {
+        let record = self.draw_log.is_some() || self.display.is_some();
+        self.terminal.set_draw_events(record);
+    }
+
+    /// The address and opcode of the last few instructions stepped, oldest first and
+    /// including one that failed, for error reports.
+    pub fn recent_instructions(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.recent.iter().copied()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Steps until PC is at a breakpoint, checked before the instruction there is
+    /// fetched, or until the ROM halts or the user quits. The instruction at the current
+    /// PC always runs, so calling this again continues past the breakpoint it stopped
+    /// at. Each `cycles_per_frame` steps count as a frame, as in `debugger_step`, but
+    /// nothing is rendered. Returns whether a breakpoint was hit.
+    pub fn run_until_breakpoint(&mut self) -> Result<bool, ExecutionError> {
+        let mut steps = 0;
+        loop {
+            self.debugger_step(steps)?;
+            steps += 1;
+            if self.halted || self.terminal.exit {
+                return Ok(false);
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Steps one instruction for the debugger's commands that run ahead, `steps` being
+    /// how many they have run before it. Every `cycles_per_frame` steps the timers tick
+    /// and input is read, as between frames, so delay loops end and the quit key works.
+    fn debugger_step(&mut self, steps: u64) -> Result<(), ExecutionError> {
+        self.step()?;
+        if (steps + 1).is_multiple_of(self.cycles_per_frame.max(1) as u64) {
+            self.tick_timers();
+            self.terminal.poll_input();
+        }
+        Ok(())
+    }
+
+    /// Steps until PC first reaches `addr`, like a breakpoint there that clears itself
+    /// once hit, recording each instruction on the way. Gives up when the ROM halts,
+    /// the user quits or `max_steps` instructions have run. As with
+    /// `run_until_breakpoint`, the instruction at the current PC always runs, and
+    /// timers tick as it goes.
+    pub fn trace_to(&mut self, addr: u16, max_steps: usize) -> Result<Trace, ExecutionError> {
+        let mut steps = Vec::new();
+        let end = loop {
+            if steps.len() == max_steps {
+                break TraceEnd::OutOfSteps;
+            }
+            steps.push((self.pc, opcode(self.read_instruction())));
+            self.debugger_step(steps.len() as u64 - 1)?;
+            if self.pc == addr {
+                break TraceEnd::Reached;
+            }
+            if self.halted {
+                break TraceEnd::Halted;
+            }
+            if self.terminal.exit {
+                break TraceEnd::Quit;
+            }
+        };
+        Ok(Trace { end, steps })
+    }
+
+    /// Steps one instruction, or when it is a CALL, runs the whole subroutine until it
+    /// returns to the instruction after the CALL with the stack as it was before.
+    /// Breakpoints inside the subroutine, halting and quitting stop it early, the same
+    /// as `run_until_breakpoint`, and so does running `max_steps` instructions without
+    /// it returning. Returns whether a breakpoint was hit.
+    pub fn step_over(&mut self, max_steps: u64) -> Result<bool, ExecutionError> {
+        let (after, depth) = (self.pc + 2, self.sp);
+        let is_call = self.read_instruction().0 == 2;
+        self.debugger_step(0)?;
+        if !is_call {
+            return Ok(false);
+        }
+        for steps in 1..max_steps {
+            if self.pc == after && self.sp == depth || self.halted || self.terminal.exit {
+                return Ok(false);
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(true);
+            }
+            self.debugger_step(steps)?;
+        }
+        Ok(false)
+    }
+
+    /// Undoes the most recent step. Returns false when there is no more history.
+    pub fn step_back(&mut self) -> bool {
+        let snapshot = match self.history.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+        self.v = snapshot.v;
+        self.i = snapshot.i;
+        self.i_initialized = snapshot.i_initialized;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.stack = snapshot.stack;
+        self.dt = snapshot.dt;
+        self.st = snapshot.st;
+        self.cycles = snapshot.cycles;
+        self.halted = snapshot.halted;
+        self.terminal.set_rows(&snapshot.pixels);
+        self.terminal.set_second_plane_rows(&snapshot.plane2);
+        self.terminal.select_planes(snapshot.planes);
+        for &(addr, byte) in snapshot.overwritten.iter().rev() {
+            self.memory[addr] = byte;
+        }
+        true
+    }
+
+    fn record_snapshot(&mut self) {
+        if self.rewind_depth == 0 {
+            return;
+        }
+        if self.history.len() == self.rewind_depth {
+            self.history.pop_front();
+        }
+        self.history.push_back(Snapshot {
+            v: self.v,
+            i: self.i,
+            i_initialized: self.i_initialized,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack.clone(),
+            dt: self.dt,
+            st: self.st,
+            cycles: self.cycles,
+            halted: self.halted,
+            pixels: self.terminal.rows().to_vec(),
+            plane2: self.terminal.second_plane_rows().to_vec(),
+            planes: self.terminal.selected_planes(),
+            overwritten: Vec::new(),
+        });
+    }
+
+    /// The line `set_instruction_log` would write for the instruction about to run.
+    pub fn trace_line(&self) -> String {
+        format_trace_line(self.pc, self.read_instruction(), &self.v, self.i, self.sp)
+    }
+
+    /// A one-line summary of where the ROM is, for `--status-line`.
+    pub fn status_line(&self) -> String {
+        format_status_line(self.pc, self.read_instruction(), self.cycles)
+    }
+
+    /// Where `addr` falls in the mapped display, as a byte offset from its top left.
+    fn display_offset(&self, addr: usize) -> Option<usize> {
+        let start = self.mapped_display? as usize;
+        let len = self.terminal.rows().len() * 8;
+        (start..start + len).contains(&addr).then(|| addr - start)
+    }
+
+    /// The address `offset` bytes past I. Past the end of memory it wraps around to the
+    /// start, as I itself can point anywhere up to FFFF without the 12-bit quirk.
+    fn i_addr(&self, offset: usize) -> usize {
+        (self.i as usize + offset) % MEMORY
+    }
+
+    fn read_memory(&self, addr: usize) -> u8 {
+        match self.display_offset(addr) {
+            Some(offset) => self.terminal.rows()[offset / 8].to_be_bytes()[offset % 8],
+            None => self.memory[addr],
+        }
+    }
+
+    fn write_memory(&mut self, addr: usize, byte: u8) {
+        if let Some(offset) = self.display_offset(addr) {
+            // Snapshots keep the whole display, so there's nothing more to record
+            let mut row = self.terminal.rows()[offset / 8].to_be_bytes();
+            row[offset % 8] = byte;
+            self.terminal.set_row(offset / 8, u64::from_be_bytes(row));
+            return;
+        }
+        if let Some(snapshot) = self.history.back_mut() {
+            snapshot.overwritten.push((addr, self.memory[addr]));
+        }
+        self.memory[addr] = byte;
+    }
+
+    /// Counts the delay and sound timers down, by one unless `set_timer_hz` changed
+    /// their rate, and ages held keys; meant to be called at 60Hz.
+    pub fn tick_timers(&mut self) {
+        self.terminal.age_keys();
+        let ticks = self.timer_rate.ticks_this_frame().min(u8::MAX as u32) as u8;
+        self.dt = self.dt.saturating_sub(ticks);
+        self.st = self.st.saturating_sub(ticks);
+    }
+
+    /// The machine state as a JSON object, for scripts checking where a ROM ended up.
+    /// The display is summed up by its `framebuffer_hash`, in hex.
+    pub fn state_json(&self) -> String {
+        let v: Vec<String> = self.v.iter().map(|v| v.to_string()).collect();
+        let stack: Vec<String> = self.stack().iter().map(|a| a.to_string()).collect();
+        format!(
+            "{{\"pc\": {}, \"i\": {}, \"v\": [{}], \"stack\": [{}], \"delay_timer\": {}, \
+             \"sound_timer\": {}, \"cycles\": {}, \"halted\": {}, \"display_hash\": \"{:016x}\"}}",
+            self.pc,
+            self.i,
+            v.join(", "),
+            stack.join(", "),
+            self.dt,
+            self.st,
+            self.cycles,
+            self.halted,
+            self.terminal.framebuffer_hash()
+        )
+    }
+
+    /// The whole machine as bytes, for `load_state` to put back later: registers,
+    /// timers, the stack, memory and the display, along with the quirks and speed it
+    /// was running with, so the restored machine behaves the same whatever it was set
+    /// up with before.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = STATE_MAGIC.to_vec();
+        state.push(STATE_VERSION);
+        state.extend_from_slice(&self.quirks.to_bytes());
+        state.extend_from_slice(&self.cycles_per_frame.to_be_bytes());
+        state.extend_from_slice(&self.v);
+        state.extend_from_slice(&self.i.to_be_bytes());
+        state.push(self.i_initialized as u8);
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.extend_from_slice(&(self.stack.len() as u16).to_be_bytes());
+        state.extend_from_slice(&(self.sp as u16).to_be_bytes());
+        for addr in self.stack.iter() {
+            state.extend_from_slice(&addr.to_be_bytes());
+        }
+        state.push(self.dt);
+        state.push(self.st);
+        state.extend_from_slice(&self.cycles.to_be_bytes());
+        state.push(self.halted as u8);
+        let rows = self.terminal.rows();
+        state.push(rows.len() as u8);
+        state.push(self.terminal.selected_planes());
+        for row in rows.iter().chain(self.terminal.second_plane_rows()) {
+            state.extend_from_slice(&row.to_be_bytes());
+        }
+        state.extend_from_slice(&self.memory);
+        state
+    }
+
+    /// Restores a state from `save_state`, quirks and speed included. Nothing changes
+    /// unless all of it reads back. The rewind history is dropped.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader { bytes: state };
+        if reader.take(STATE_MAGIC.len()) != Ok(&STATE_MAGIC[..]) {
+            return Err(StateError::NotAState);
+        }
+        let version = reader.u8()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let quirks = reader.take(QUIRKS_BYTES)?.try_into().unwrap();
+        let quirks = Quirks::from_bytes(quirks).ok_or(StateError::Corrupt)?;
+        let cycles_per_frame = reader.u32()?;
+        let v: [u8; 16] = reader.take(16)?.try_into().unwrap();
+        let i = reader.u16()?;
+        let i_initialized = reader.flag()?;
+        let pc = reader.u16()?;
+        let depth = reader.u16()? as usize;
+        let sp = reader.u16()? as usize;
+        if !(1..=MAX_STACK_DEPTH).contains(&depth) || sp > depth {
+            return Err(StateError::Corrupt);
+        }
+        let stack = (0..depth)
+            .map(|_| reader.u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        // Both are read a whole instruction at a time, so the next byte must be in
+        // memory too
+        let in_memory = |addr: u16| (addr as usize) + 1 < MEMORY;
+        if !in_memory(pc) || !stack.iter().all(|&addr| in_memory(addr)) || i as usize >= MEMORY {
+            return Err(StateError::Corrupt);
+        }
+        let dt = reader.u8()?;
+        let st = reader.u8()?;
+        let cycles = reader.u64()?;
+        let halted = reader.flag()?;
+        let height = reader.u8()? as usize;
+        let planes = reader.u8()?;
+        if !(1..=MAX_DISPLAY_HEIGHT).contains(&height) || planes > 0b11 {
+            return Err(StateError::Corrupt);
+        }
+        let rows = (0..height)
+            .map(|_| reader.u64())
+            .collect::<Result<Vec<_>, _>>()?;
+        let plane2 = (0..height)
+            .map(|_| reader.u64())
+            .collect::<Result<Vec<_>, _>>()?;
+        let memory = reader.take(MEMORY)?;
+        if !reader.bytes.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        self.quirks = quirks;
+        self.cycles_per_frame = cycles_per_frame;
+        self.v = v;
+        self.i = i;
+        self.i_initialized = i_initialized;
+        self.pc = pc;
+        self.stack = stack;
+        self.sp = sp;
+        self.dt = dt;
+        self.st = st;
+        self.cycles = cycles;
+        self.halted = halted;
+        self.terminal.set_display_height(height);
+        self.terminal.set_rows(&rows);
+        self.terminal.set_second_plane_rows(&plane2);
+        self.terminal.select_planes(planes);
+        self.memory.copy_from_slice(memory);
+        self.history.clear();
+        Ok(())
+    }
+
+    /// The machine state laid out for people rather than scripts, for bug reports:
+    /// registers, timers and the stack in hex, the display's `framebuffer_hash` and
+    /// the quirks in effect, a line each.
+    pub fn debug_dump(&self) -> String {
+        let hex = |v: &[u8]| -> Vec<String> { v.iter().map(|v| format!("{:02X}", v)).collect() };
+        let stack = if self.stack().is_empty() {
+            "empty".to_string()
+        } else {
+            let chain: Vec<_> = self.stack().iter().map(|a| format!("{:03X}", a)).collect();
+            chain.join(" > ")
+        };
+        format!(
+            "PC {:03X}  I {:03X}  SP {}  DT {:02X}  ST {:02X}\n\
+             V0-V7 {}\n\
+             V8-VF {}\n\
+             stack {}\n\
+             cycles {}{}\n\
+             display {:016x}\n\
+             quirks {:?}\n",
+            self.pc,
+            self.i,
+            self.sp,
+            self.dt,
+            self.st,
+            hex(&self.v[..8]).join(" "),
+            hex(&self.v[8..]).join(" "),
+            stack,
+            self.cycles,
+            if self.halted { ", halted" } else { "" },
+            self.terminal.framebuffer_hash(),
+            self.quirks
+        )
+    }
+
+    /// The font Fx29 points into, 5 bytes for each digit 0 to F.
+    pub fn font(&self) -> &[u8] {
+        &self.memory[..FONT.len()]
+    }
+
+    /// Replaces the display with all 16 font glyphs, 0 to 7 along the top and 8 to F
+    /// below, each in a 6x6 cell. Each is drawn through Fx29 and DRW as a ROM would,
+    /// which leaves V0 to V2 and I changed.
+    pub fn draw_font(&mut self) {
+        self.clear_display();
+        for digit in 0..16 {
+            self.v[0] = digit;
+            self.v[1] = digit % 8 * 6;
+            self.v[2] = digit / 8 * 6;
+            // LD F, V0; DRW V1, V2, 5
+            let _ = self.execute_instruction((0xF, 0, 2, 9));
+            self.drw(1, 2, 5);
+        }
+    }
+
+    /// Blanks the screen, leaving registers and memory alone.
+    pub fn clear_display(&mut self) {
+        self.terminal.reset_display();
+    }
+
+    /// Puts the machine back in its power-on state to run the loaded ROM again: the
+    /// display is blank, at the height the ROM starts with and with only the first
+    /// plane selected, registers, I, timers and the stack are zeroed and PC is back
+    /// where the ROM starts. Memory, including anything the ROM wrote to itself, and
+    /// settings are kept.
+    pub fn reset(&mut self) {
+        self.v = [0; 16];
+        self.i = 0;
+        self.i_initialized = false;
+        self.dt = 0;
+        self.st = 0;
+        self.pc = 0x200;
+        self.sp = 0;
+        self.cycles = 0;
+        self.halted = false;
+        self.draws = 0;
+        self.carried_cycles = 0;
+        self.history.clear();
+        self.terminal.select_planes(1);
+        // As `load` sets up the display, which also blanks it
+        if self.detect_hires && is_hires_rom(&self.memory[0x200..]) {
+            self.terminal.set_display_height(MAX_DISPLAY_HEIGHT);
+            self.pc = HIRES_START;
+        } else {
+            self.terminal.set_display_height(DISPLAY_HEIGHT);
+        }
+    }
+
+    /// Draws the current display contents to the terminal, as a complete frame when
+    /// double buffering, and rings the bell if a sound just started. Then presents the
+    /// frame to the `set_display` backend, if there is one.
+    pub fn present(&mut self) {
+        self.terminal.set_sound(self.is_beeping());
+        self.terminal.swap_buffers();
+        self.terminal.render();
+        if let Some(display) = self.display.as_mut() {
+            display.present(self.terminal.rows());
+        }
+    }
+
+    /// Copies the ROM into memory at 0x200, setting up a HIRES CHIP-8 ROM's display
+    /// and start address unless told not to by `set_hires_detection`.
+    pub fn load(&mut self, data: &[u8]) {
+        self.memory[0x200..0x200 + data.len()].clone_from_slice(data);
+        self.rom_end = 0x200 + data.len() as u16;
+        if self.detect_hires && is_hires_rom(data) {
+            self.terminal.set_display_height(MAX_DISPLAY_HEIGHT);
+            self.pc = HIRES_START;
+        }
+    }
+
+    /// Replaces the loaded ROM with `data` and starts it from power-on, as `reset`
+    /// does, for a ROM rebuilt while running. Nothing of the old ROM is left in memory.
+    pub fn reload(&mut self, data: &[u8]) {
+        self.reset();
+        self.memory[0x200..].fill(0);
+        self.terminal.set_display_height(DISPLAY_HEIGHT);
+        self.load(data);
+    }
+
+    /// Overwrites memory with the patch's bytes, usually right after `load`.
+    pub fn apply_patch(&mut self, patch: &Patch) {
+        for &(addr, byte) in patch.writes.iter() {
+            self.memory[addr as usize] = byte;
+        }
+    }
+
+    /// Writes `data` into memory from `addr`, for data a ROM expects to find there
+    /// without carrying it itself. Fails, writing nothing, unless all of it fits.
+    pub fn preload(&mut self, addr: u16, data: &[u8]) -> Result<(), String> {
+        let start = addr as usize;
+        let end = start + data.len();
+        if end > MEMORY {
+            return Err(format!(
+                "{} bytes at {:03X} run past the end of memory",
+                data.len(),
+                addr
+            ));
+        }
+        self.memory[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn execute_frame(&mut self) -> Result<u32, ExecutionError> {
+        let deadline = self
+            .frame_time_budget
+            .map(|budget| self.clock.now() + budget);
+        let mut budget = self.cycles_per_frame + self.carried_cycles;
+        self.carried_cycles = 0;
+        let mut executed = 0;
+        while budget > 0 && !self.terminal.exit && !self.halted {
+            if deadline.is_some_and(|deadline| self.clock.now() >= deadline) {
+                self.carried_cycles = budget.min(self.cycles_per_frame);
+                break;
+            }
+            if self.quirks.display_wait
+                && self.draws > 0
+                && matches!(self.read_instruction(), (0xD, _, _, _))
+            {
+                // Stalled until the next frame, the rest of the budget is lost
+                break;
+            }
+            budget = budget.saturating_sub(self.timing.cost(self.read_instruction()));
+            self.step()?;
+            executed += 1;
+        }
+        self.draws_last_frame = std::mem::take(&mut self.draws);
+        Ok(executed)
+    }
+
+    /// The instruction at PC, with any bytes past the end of memory read as 0. `step`
+    /// refuses to run it.
+    fn read_instruction(&self) -> Instruction {
+        let byte = |addr: usize| self.memory.get(addr).copied().unwrap_or(0);
+        let first_byte = byte(self.pc as usize);
+        let second_byte = byte(self.pc as usize + 1);
+        (
+            first_byte >> 4,
+            first_byte & 0xF,
+            second_byte >> 4,
+            second_byte & 0xF,
+        )
+    }
+
+    fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), ExecutionError> {
+        let pc = self.pc;
+        // Increment program counter to point to the next instruction
+        self.pc += 2;
+        self.cycles += 1;
+
+        match instruction {
+            // SUPER-CHIP and XO-CHIP extensions
+            (0, 0, 0xC, _)
+            | (0, 0, 0xF, 0xB)
+            | (0, 0, 0xF, 0xC)
+            | (0xF, _, 0, 1)
+            | (0xD, _, _, 0)
+                if self.strict =>
+            {
+                return self.unknown_opcode(instruction)
+            }
+            // CLS
+            (0, 0, 0xE, 0) => self.terminal.clear(),
+            // HIRES CHIP-8's CLS for its 64x64 display
+            (0, 2, 3, 0) if self.terminal.dimensions().1 == MAX_DISPLAY_HEIGHT => {
+                self.terminal.clear()
+            }
+            // RET
+            (0, 0, 0xE, 0xE) => self.ret()?,
+            // JP addr
+            (1, a, b, c) => self.jp(addr(a, b, c)),
+            // CALL addr
+            (2, a, b, c) => self.call_addr(a, b, c)?,
+            // SE Vx, byte
+            (3, x, k1, k2) => self.se_vx_byte(x, k1, k2),
+            // SNE Vx, byte
+            (4, x, k1, k2) => self.sne_vx_byte(x, k1, k2),
+            // SE Vx, Vy
+            (5, x, y, 0) => self.se_vx_vy(x, y),
+            // LD Vx, byte
+            (6, x, k1, k2) => self.v[x as usize] = to_byte(k1, k2),
+            // ADD Vx, byte
+            (7, x, k1, k2) => self.v[x as usize] = self.v[x as usize].wrapping_add(to_byte(k1, k2)),
+            // LD Vx, Vy
+            (8, x, y, 0) => self.v[x as usize] = self.v[y as usize],
+            // OR Vx, Vy
+            (8, x, y, 1) => {
+                self.v[x as usize] |= self.v[y as usize];
+                self.reset_vf()
+            }
+            // AND Vx, Vy
+            (8, x, y, 2) => {
+                self.v[x as usize] &= self.v[y as usize];
+                self.reset_vf()
+            }
+            // XOR Vx, Vy
+            (8, x, y, 3) => {
+                self.v[x as usize] ^= self.v[y as usize];
+                self.reset_vf()
+            }
+            // ADD Vx, Vy
+            (8, x, y, 4) => self.add_vx_vy(x, y),
+            // SUB Vx, Vy
+            (8, x, y, 5) => self.sub_vx_vy(x, y),
+            // SHR Vx {, Vy}
+            (8, x, y, 6) => self.shr_vx(x, y),
+            // SUBN Vx, Vy
+            (8, x, y, 7) => self.subn_vx_vy(x, y),
+            // SHL Vx {, Vy}
+            (8, x, y, 0xE) => self.shl_vx(x, y),
+            // SNE Vx, Vy
+            (9, x, y, 0) => self.sne_vx_vy(x, y),
+            // SLD I, addr
+            (0xA, a, b, c) => {
+                self.i = addr(a, b, c);
+                self.i_initialized = true
+            }
+            // JP V0, addr
+            (0xB, a, b, c) => self.pc = self.v[0] as u16 + addr(a, b, c),
+            // RND Vx, byte
+            (0xC, x, k1, k2) => self.v[x as usize] = self.rng.gen::<u8>() & to_byte(k1, k2),
+            // DRW Vx, Vy, nibble: n rows of 8 pixels, or with n = 0 a SUPER-CHIP 16x16
+            // sprite. Base CHIP-8 has no meaning for n = 0, so strict mode rejects it
+            (0xD, x, y, n) => self.drw(x, y, n),
+            // SKP Vx
+            // Only the low nibble of Vx picks the key, as on the COSMAC VIP whose keypad
+            // latch had four bits, so 0x25 is key 5
+            (0xE, x, 9, 0xE) => {
+                if self.terminal.check_if_pressed(self.v[x as usize] & 0xF) {
+                    self.pc += 2
+                }
+            }
+            // SKNP Vx
+            (0xE, x, 0xA, 1) => {
+                if !self.terminal.check_if_pressed(self.v[x as usize] & 0xF) {
+                    self.pc += 2
+                }
+            }
+            // PLANE n
+            (0xF, n, 0, 1) => self.terminal.select_planes(n),
+            // LD Vx, DT
+            (0xF, x, 0, 7) => self.v[x as usize] = self.dt,
+            // LD Vx, K. Keys that aren't on the keypad are never queued as presses, so
+            // they can't stand in for one or push one out. The cancel key moves on
+            // leaving Vx as it was.
+            (0xF, x, 0, 0xA) => match self.terminal.wait_for_key_press() {
+                Some(key) => self.v[x as usize] = key,
+                None if self.terminal.take_wait_cancelled() => (),
+                None => self.pc -= 2,
+            },
+            // LD DT, Vx
+            (0xF, x, 1, 5) => self.dt = self.v[x as usize],
+            // LD ST, Vx
+            (0xF, x, 1, 8) => self.st = self.v[x as usize],
+            // ADD I, Vx
+            (0xF, x, 1, 0xE) => self.add_i_vx(x),
+            // LD F, Vx
+            (0xF, x, 2, 9) => {
+                self.i = (self.v[x as usize] & 0xF) as u16 * 5;
+                self.i_initialized = true
+            }
+            // LD B, Vx
+            (0xF, x, 3, 3) => self.ld_b_vx(x),
+            // LD [I], Vx
+            (0xF, x, 5, 5) => self.ld_i_vx(x),
+            // LD Vx, [I]
+            (0xF, x, 6, 5) => self.ld_vx_i(x),
+            // SCD nibble
+            (0, 0, 0xC, n) => self.terminal.scroll_down(self.scroll_amount(n)),
+            // SCR
+            (0, 0, 0xF, 0xB) => self.terminal.scroll_right(self.scroll_amount(4)),
+            // SCL
+            (0, 0, 0xF, 0xC) => self.terminal.scroll_left(self.scroll_amount(4)),
+            // 0000, usually zeroed memory
+            (0, 0, 0, 0) => match self.zero_opcode {
+                ZeroOpcode::Nop => (),
+                ZeroOpcode::Halt => {
+                    self.pc -= 2;
+                    self.halted = true;
+                }
+                ZeroOpcode::Error => return self.unknown_opcode(instruction),
+            },
+            // SYS addr
+            (0, _, _, _) => (), // Ignored by modern interpreters
+            x => return self.unknown_opcode(x),
+        }
+        if self.diagnostics.odd_pc && self.pc & 1 == 1 && pc & 1 == 0 {
+            self.warn(Warning::OddPc {
+                pc,
+                target: self.pc,
+            });
+        }
+        if self.diagnostics.past_rom_end && pc < self.rom_end && self.pc >= self.rom_end {
+            self.warn(Warning::PastRomEnd {
+                pc,
+                target: self.pc,
+            });
+        }
+        if self.diagnostics.below_program && pc >= 0x200 && self.pc < 0x200 {
+            self.warn(Warning::BelowProgram {
+                pc,
+                target: self.pc,
+            });
+        }
+        Ok(())
+    }
+
+    fn unknown_opcode(&mut self, instruction: Instruction) -> Result<(), ExecutionError> {
+        if self.lenient {
+            return Ok(());
+        }
+        self.pc -= 2;
+        Err(ExecutionError::UnknownOpcode {
+            pc: self.pc,
+            opcode: opcode(instruction),
+        })
+    }
+
+    /// Warns that writing to a log failed, such as on a full disk, after the log was
+    /// dropped so the ROM keeps running without it.
+    fn log_failed(&mut self, log: &str, error: std::io::Error) {
+        warn!("stopped writing the {}: {}", log, error);
+        self.terminal
+            .set_status(format!("stopped writing the {}: {}", log, error));
+    }
+
+    fn warn(&mut self, warning: Warning) {
+        warn!("{}", warning);
+        self.last_warning = Some(warning);
+    }
+
+    fn check_i_initialized(&mut self) {
+        if self.diagnostics.uninitialized_i && !self.i_initialized {
+            self.warn(Warning::UninitializedI { pc: self.pc - 2 });
+        }
+    }
+
+    fn drw(&mut self, x: u8, y: u8, n: u8) {
+        self.check_i_initialized();
+        self.draws += 1;
+        // One set of rows per selected plane
+        let planes = self.terminal.selected_planes().count_ones().max(1) as usize;
+        let large = n == 0;
+        let len = if large { 32 } else { n as usize } * planes;
+        let (x, y) = (self.v[x as usize], self.v[y as usize]);
+        let (width, height) = self.terminal.dimensions();
+        if !self.quirks.wrap_draw_origin && (x as usize >= width || y as usize >= height) {
+            // Starts off the display, so there's nothing to draw or collide with
+            self.v[0xF] = 0;
+            return;
+        }
+        let sprite: Vec<u8> = (0..len).map(|k| self.memory[self.i_addr(k)]).collect();
+        let clip = Clip {
+            x: self.quirks.clip_sprites_x,
+            y: self.quirks.clip_sprites_y,
+        };
+        self.v[0xF] = if large {
+            self.terminal.draw_sprite_16(x, y, &sprite, clip)
+        } else {
+            self.terminal.draw_sprite_with_clip(x, y, &sprite, clip)
+        }
+    }
+
+    fn jp(&mut self, addr: u16) {
+        self.halted = addr == self.pc - 2;
+        self.pc = addr
+    }
+
+    fn sne_vx_vy(&mut self, x: u8, y: u8) {
+        if self.v[x as usize] != self.v[y as usize] {
+            self.pc += 2
+        }
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0
+        }
+    }
+
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        match self.quirks.shift {
+            ShiftQuirk::LegacyVy | ShiftQuirk::LegacyVyToVy => self.v[y as usize],
+            ShiftQuirk::ModernVx => self.v[x as usize],
+        }
+    }
+
+    fn shift_target(&self, x: u8, y: u8) -> u8 {
+        match self.quirks.shift {
+            ShiftQuirk::LegacyVyToVy => y,
+            ShiftQuirk::LegacyVy | ShiftQuirk::ModernVx => x,
+        }
+    }
+
+    // The flag is written after the result, so with VF as the destination the flag
+    // is what's left in it. Every platform we know of agrees on this, shifts included.
+    fn set_with_flag(&mut self, x: u8, result: u8, flag: bool) {
+        self.v[x as usize] = result;
+        self.v[0xF] = flag as u8;
+    }
+
+    fn shl_vx(&mut self, x: u8, y: u8) {
+        let vx = self.shift_source(x, y);
+        self.set_with_flag(self.shift_target(x, y), vx << 1, vx & 0x80 != 0)
+    }
+
+    fn subn_vx_vy(&mut self, x: u8, y: u8) {
+        let (result, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+        self.set_with_flag(x, result, !borrow)
+    }
+
+    fn shr_vx(&mut self, x: u8, y: u8) {
+        let vx = self.shift_source(x, y);
+        self.set_with_flag(self.shift_target(x, y), vx >> 1, vx & 1 != 0)
+    }
+
+    fn sub_vx_vy(&mut self, x: u8, y: u8) {
+        let (result, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+        self.set_with_flag(x, result, !borrow)
+    }
+
+    fn add_vx_vy(&mut self, x: u8, y: u8) {
+        let (result, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+        self.set_with_flag(x, result, carry)
+    }
+
+    fn se_vx_vy(&mut self, x: u8, y: u8) {
+        if self.v[x as usize] == self.v[y as usize] {
+            self.pc += 2
+        }
+    }
+
+    fn sne_vx_byte(&mut self, x: u8, k1: u8, k2: u8) {
+        if self.v[x as usize] != to_byte(k1, k2) {
+            self.pc += 2
+        }
+    }
+
+    fn se_vx_byte(&mut self, x: u8, k1: u8, k2: u8) {
+        if self.v[x as usize] == to_byte(k1, k2) {
+            self.pc += 2
+        }
+    }
+
+    // Stack errors leave PC at the failing instruction and the stack untouched, so a
+    // front-end can inspect the state or reset.
+    fn call_addr(&mut self, a: u8, b: u8, c: u8) -> Result<(), ExecutionError> {
+        if self.sp == self.stack.len() {
+            self.pc -= 2;
+            return Err(ExecutionError::StackOverflow { pc: self.pc });
+        }
+        self.stack[self.sp] = self.pc;
+        self.sp += 1;
+        self.pc = addr(a, b, c);
+        if let Some(profile) = self.stack_profile.as_mut() {
+            if self.sp > profile.max_depth {
+                profile.max_depth = self.sp;
+                profile.max_pc = self.pc;
+                profile.trail = self.stack[..self.sp].iter().map(|ret| ret - 2).collect();
+            }
+        }
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), ExecutionError> {
+        if self.sp == 0 {
+            self.pc -= 2;
+            return Err(ExecutionError::StackUnderflow { pc: self.pc });
+        }
+        self.sp -= 1;
+        self.pc = self.stack[self.sp];
+        Ok(())
+    }
+
+    // Scroll amounts are in hi-res pixels, and the display is always low-res
+    fn scroll_amount(&self, n: u8) -> u8 {
+        match self.quirks.low_res_scroll {
+            LowResScrollQuirk::Doubled => n,
+            LowResScrollQuirk::Halved => n / 2,
+        }
+    }
+
+    fn add_i_vx(&mut self, x: u8) {
+        let sum = self.i as u32 + self.v[x as usize] as u32;
+        match self.quirks.index_overflow {
+            IndexOverflowQuirk::Unflagged => (),
+            IndexOverflowQuirk::Past0FFF => self.v[0xF] = (sum > 0xFFF) as u8,
+            IndexOverflowQuirk::Past0FFFF => self.v[0xF] = (sum > 0xFFFF) as u8,
+        }
+        self.i = sum as u16;
+        if self.quirks.add_i_wraps_12_bits {
+            self.i &= 0xFFF;
+        }
+    }
+
+    fn ld_b_vx(&mut self, x: u8) {
+        self.check_i_initialized();
+        let vx = self.v[x as usize];
+        self.write_memory(self.i_addr(0), vx / 100);
+        self.write_memory(self.i_addr(1), vx % 100 / 10);
+        self.write_memory(self.i_addr(2), vx % 10);
+    }
+
+    fn ld_i_vx(&mut self, x: u8) {
+        self.check_i_initialized();
+        let count = registers_transferred(x);
+        debug!("storing {} registers at {:03X}", count, self.i);
+        for i in 0..count {
+            self.write_memory(self.i_addr(i), self.v[i])
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(count as u16)
+        }
+    }
+
+    fn ld_vx_i(&mut self, x: u8) {
+        self.check_i_initialized();
+        let count = registers_transferred(x);
+        debug!("loading {} registers from {:03X}", count, self.i);
+        for i in 0..count {
+            self.v[i] = self.read_memory(self.i_addr(i))
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(count as u16)
+        }
+    }
+}
+
+/// Whether `rom` is for HIRES CHIP-8, going by the jump it opens with.
+pub fn is_hires_rom(rom: &[u8]) -> bool {
+    rom.starts_with(&HIRES_PREFIX)
+}
+
+/// How many registers Fx55 and Fx65 copy: V0 through Vx inclusive, so x + 1 of them
+/// and all 16 for x = F.
+pub fn registers_transferred(x: u8) -> usize {
+    x as usize + 1
+}
+
+fn format_trace_line(pc: u16, instruction: Instruction, v: &[u8; 16], i: u16, sp: usize) -> String {
+    let opcode = opcode(instruction);
+    let registers: Vec<_> = v.iter().map(|v| format!("{:02X}", v)).collect();
+    format!(
+        "{:04X} {:04X} {:<16} {} {:04X} {:X}",
+        pc,
+        opcode,
+        mnemonic(opcode),
+        registers.join(" "),
+        i,
+        sp
+    )
+}
+
+fn format_status_line(pc: u16, instruction: Instruction, cycles: u64) -> String {
+    let opcode = opcode(instruction);
+    format!(
+        "{:03X} {:04X} {:<16} {} cycles",
+        pc,
+        opcode,
+        mnemonic(opcode),
+        cycles
+    )
+}
+
+fn opcode((a, b, c, d): Instruction) -> u16 {
+    ((a as u16) << 12) + addr(b, c, d)
+}
+
+fn to_byte(a: u8, b: u8) -> u8 {
+    (a << 4) + b
+}
+
+fn addr(a: u8, b: u8, c: u8) -> u16 {
+    ((a as u16) << 8) + ((b as u16) << 4) + (c as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io::{self, sink, Read, Write};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::clock::Clock;
+
+    #[test]
+    fn ret() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.sp = 1;
+        cpu.stack[0] = 0xDDD;
+        cpu.execute_instruction((0, 0, 0xE, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0xDDD);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(
+            cpu.execute_instruction((0, 0, 0xE, 0xE)),
+            Err(super::ExecutionError::StackUnderflow { pc: 0x200 })
+        );
+        assert_eq!(cpu.sp, 0);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.stack, [0; 16]);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x22, 0x00]); // CALL 0x200
+        for _ in 0..16 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            cpu.step(),
+            Err(super::ExecutionError::StackOverflow { pc: 0x200 })
+        );
+        assert_eq!(cpu.sp, 16);
+        assert_eq!(cpu.pc, 0x200);
+
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_stack_depth(32);
+        cpu.load(&[0x22, 0x00]); // CALL 0x200
+        for _ in 0..32 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.sp, 32);
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn jp() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.execute_instruction((2, 0xA, 0xE, 0xF)).unwrap();
+        assert_eq!(cpu.pc, 0xAEF);
+        assert_eq!(cpu.sp, 1);
+        assert_eq!(cpu.stack[0], 0x202);
+    }
+
+    #[test]
+    fn nested_calls() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x22, 0x04, // CALL 0x204
+            0x00, 0x00, //
+            0x22, 0x08, // CALL 0x208
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.stack(), [0x202, 0x206]);
+        assert_eq!(cpu.sp(), 2);
+    }
+
+    #[test]
+    fn halt() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x12, 0x02, 0x12, 0x02]);
+        assert!(!cpu.run_frame().unwrap());
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.cycles(), 2);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn call() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.execute_instruction((1, 0xA, 0xE, 0xF)).unwrap();
+        assert_eq!(cpu.pc, 0xAEF);
+    }
+
+    #[test]
+    fn se_vx_byte() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.pc, 0x200);
+        cpu.v[1] = 0xEF;
+        cpu.v[2] = 0xAA;
+        cpu.execute_instruction((3, 1, 0xE, 0xF)).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+        cpu.execute_instruction((3, 2, 0xD, 0xD)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn sne_vx_byte() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.pc, 0x200);
+        cpu.v[1] = 0xEF;
+        cpu.v[2] = 0xAA;
+        cpu.execute_instruction((4, 1, 0xE, 0xF)).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        cpu.execute_instruction((4, 2, 0xD, 0xD)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn se_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.pc, 0x200);
+        cpu.v[1] = 0xEF;
+        cpu.v[2] = 0xAA;
+        cpu.v[10] = 0xAA;
+        cpu.execute_instruction((5, 2, 10, 0)).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+        cpu.execute_instruction((5, 1, 2, 0)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn ld_vx_byte() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.execute_instruction((6, 2, 0xE, 0xA)).unwrap();
+        assert_eq!(cpu.v[2], 0xEA);
+    }
+
+    #[test]
+    fn add_vx_byte() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0x22;
+        cpu.execute_instruction((7, 2, 0x4, 0x5)).unwrap();
+        assert_eq!(cpu.v[2], 0x67);
+    }
+
+    #[test]
+    fn add_vx_byte_wraps() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // Modulo 256, with no carry: VF keeps whatever it held
+        for &flag in &[0, 1, 0x5A] {
+            cpu.v[2] = 0xFF;
+            cpu.v[0xF] = flag;
+            cpu.execute_instruction((7, 2, 0x0, 0x2)).unwrap();
+            assert_eq!((cpu.v[2], cpu.v[0xF]), (0x01, flag));
+        }
+        cpu.v[2] = 0x80;
+        cpu.execute_instruction((7, 2, 0x8, 0x0)).unwrap();
+        assert_eq!(cpu.v[2], 0);
+
+        // Adding to VF itself is plain arithmetic too
+        cpu.v[0xF] = 0xFE;
+        cpu.execute_instruction((7, 0xF, 0x0, 0x3)).unwrap();
+        assert_eq!(cpu.v[0xF], 0x01);
+    }
+
+    #[test]
+    fn ld_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[3] = 0xEE;
+        cpu.execute_instruction((8, 2, 3, 0)).unwrap();
+        assert_eq!(cpu.v[2], 0xEE);
+    }
+
+    #[test]
+    fn or_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0b1100_1001;
+        cpu.v[9] = 0b1000_0101;
+        cpu.execute_instruction((8, 2, 9, 1)).unwrap();
+        assert_eq!(cpu.v[2], 0b1100_1101);
+    }
+
+    #[test]
+    fn and_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0b1100_1001;
+        cpu.v[9] = 0b1000_0101;
+        cpu.execute_instruction((8, 2, 9, 2)).unwrap();
+        assert_eq!(cpu.v[2], 0b1000_0001);
+    }
+
+    #[test]
+    fn xor_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0b1100_1001;
+        cpu.v[9] = 0b1000_0101;
+        cpu.execute_instruction((8, 2, 9, 3)).unwrap();
+        assert_eq!(cpu.v[2], 0b0100_1100);
+    }
+
+    #[test]
+    fn add_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0xAA;
+        cpu.v[9] = 0x12;
+        cpu.execute_instruction((8, 2, 9, 4)).unwrap();
+        assert_eq!(cpu.v[2], 0xBC);
+        assert_eq!(cpu.v[0xf], 0);
+
+        cpu.v[2] = 0xFF;
+        cpu.v[9] = 0xFF;
+        cpu.execute_instruction((8, 2, 9, 4)).unwrap();
+        assert_eq!(cpu.v[2], 0xFE);
+        assert_eq!(cpu.v[0xf], 1);
+    }
+
+    #[test]
+    fn sub_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0x0F;
+        cpu.v[9] = 0xFF;
+        cpu.execute_instruction((8, 2, 9, 5)).unwrap();
+        assert_eq!(cpu.v[2], 0x10);
+        assert_eq!(cpu.v[0xf], 0);
+
+        cpu.v[2] = 0xFF;
+        cpu.v[9] = 0x0F;
+        cpu.execute_instruction((8, 2, 9, 5)).unwrap();
+        assert_eq!(cpu.v[2], 0xF0);
+        assert_eq!(cpu.v[0xf], 1);
+    }
+
+    #[test]
+    fn shr_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0b0001_0001;
+        cpu.execute_instruction((8, 2, 9, 6)).unwrap();
+        assert_eq!(cpu.v[2], 0b0000_1000);
+        assert_eq!(cpu.v[0xf], 1);
+
+        cpu.v[2] = 0b0001_0000;
+        cpu.execute_instruction((8, 2, 9, 6)).unwrap();
+        assert_eq!(cpu.v[2], 0b0000_1000);
+        assert_eq!(cpu.v[0xf], 0);
+    }
+
+    #[test]
+    fn subn_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[9] = 0x0F;
+        cpu.v[2] = 0xFF;
+        cpu.execute_instruction((8, 2, 9, 7)).unwrap();
+        assert_eq!(cpu.v[2], 0x10);
+        assert_eq!(cpu.v[0xf], 0);
+
+        cpu.v[9] = 0xFF;
+        cpu.v[2] = 0x0F;
+        cpu.execute_instruction((8, 2, 9, 7)).unwrap();
+        assert_eq!(cpu.v[2], 0xF0);
+        assert_eq!(cpu.v[0xf], 1);
+    }
+
+    #[test]
+    fn shl_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[2] = 0b0001_0001;
+        cpu.execute_instruction((8, 2, 9, 0xE)).unwrap();
+        assert_eq!(cpu.v[2], 0b0010_0010);
+        assert_eq!(cpu.v[0xf], 0);
+
+        cpu.v[2] = 0b1001_0001;
+        cpu.execute_instruction((8, 2, 9, 0xE)).unwrap();
+        assert_eq!(cpu.v[2], 0b0010_0010);
+        assert_eq!(cpu.v[0xf], 1);
+    }
+
+    #[test]
+    fn arithmetic_boundaries() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // (Vx, Vy, operation) -> (Vx, VF)
+        let mut run = |vx, vy, op| {
+            cpu.v[2] = vx;
+            cpu.v[9] = vy;
+            cpu.v[0xF] = 0xAA;
+            cpu.execute_instruction((8, 2, 9, op)).unwrap();
+            (cpu.v[2], cpu.v[0xF])
+        };
+        assert_eq!(run(0xFE, 0x01, 4), (0xFF, 0));
+        assert_eq!(run(0xFF, 0x01, 4), (0x00, 1));
+        assert_eq!(run(0x80, 0x80, 4), (0x00, 1));
+        assert_eq!(run(0x01, 0x01, 5), (0x00, 1));
+        assert_eq!(run(0x00, 0x01, 5), (0xFF, 0));
+        assert_eq!(run(0x01, 0x01, 7), (0x00, 1));
+        assert_eq!(run(0x01, 0x00, 7), (0xFF, 0));
+        assert_eq!(run(0x01, 0x00, 6), (0x00, 1));
+        assert_eq!(run(0xFE, 0x00, 6), (0x7F, 0));
+        assert_eq!(run(0x80, 0x00, 0xE), (0x00, 1));
+        assert_eq!(run(0x7F, 0x00, 0xE), (0xFE, 0));
+
+        cpu.v[3] = 0xFF;
+        cpu.v[0xF] = 0xAA;
+        cpu.execute_instruction((7, 3, 0, 2)).unwrap();
+        assert_eq!((cpu.v[3], cpu.v[0xF]), (0x01, 0xAA));
+
+        // With VF as the destination, the flag overwrites the result
+        cpu.v[0xF] = 0xFF;
+        cpu.v[1] = 0x01;
+        cpu.execute_instruction((8, 0xF, 1, 4)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn arithmetic_into_vf() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // (VF, Vy, operation) -> VF: the flag, never the result of the arithmetic
+        let mut run = |vf, vy, op| {
+            cpu.v[0xF] = vf;
+            cpu.v[1] = vy;
+            cpu.execute_instruction((8, 0xF, 1, op)).unwrap();
+            cpu.v[0xF]
+        };
+        assert_eq!(run(0x10, 0x20, 4), 0);
+        assert_eq!(run(0xF0, 0x20, 4), 1);
+        assert_eq!(run(0x30, 0x20, 5), 1);
+        assert_eq!(run(0x10, 0x20, 5), 0);
+        assert_eq!(run(0x10, 0x20, 7), 1);
+        assert_eq!(run(0x30, 0x20, 7), 0);
+        // Equal operands don't borrow
+        assert_eq!(run(0x20, 0x20, 5), 1);
+        assert_eq!(run(0x20, 0x20, 7), 1);
+    }
+
+    #[test]
+    fn skip_key_uses_low_nibble() {
+        use crate::backend::KeyEvent;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.terminal_mut().handle_key_event(KeyEvent::Down(5));
+        cpu.v[3] = 0x25;
+        cpu.pc = 0x200;
+        cpu.execute_instruction((0xE, 3, 9, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+        cpu.execute_instruction((0xE, 3, 0xA, 1)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+
+        cpu.v[3] = 0x26;
+        cpu.execute_instruction((0xE, 3, 9, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0x208);
+        cpu.execute_instruction((0xE, 3, 0xA, 1)).unwrap();
+        assert_eq!(cpu.pc, 0x20C);
+    }
+
+    #[test]
+    fn skp() {
+        use crate::backend::KeyEvent;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[1] = 0xA;
+        cpu.pc = 0x200;
+        cpu.execute_instruction((0xE, 1, 9, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.terminal_mut().handle_key_event(KeyEvent::Down(0xA));
+        cpu.execute_instruction((0xE, 1, 9, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+
+        cpu.terminal_mut().handle_key_event(KeyEvent::Up(0xA));
+        cpu.execute_instruction((0xE, 1, 9, 0xE)).unwrap();
+        assert_eq!(cpu.pc, 0x208);
+    }
+
+    #[test]
+    fn sknp() {
+        use crate::backend::KeyEvent;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[1] = 0xA;
+        cpu.pc = 0x200;
+        cpu.execute_instruction((0xE, 1, 0xA, 1)).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        cpu.terminal_mut().handle_key_event(KeyEvent::Down(0xA));
+        cpu.execute_instruction((0xE, 1, 0xA, 1)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+
+        // Another key held doesn't count
+        cpu.terminal_mut().handle_key_event(KeyEvent::Up(0xA));
+        cpu.terminal_mut().handle_key_event(KeyEvent::Down(0xB));
+        cpu.execute_instruction((0xE, 1, 0xA, 1)).unwrap();
+        assert_eq!(cpu.pc, 0x20A);
+    }
+
+    #[test]
+    fn ld_vx_k() {
+        use crate::backend::KeyEvent;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0xF4, 0x0A]); // LD V4, K
+        for _ in 0..3 {
+            cpu.step().unwrap();
+            assert_eq!((cpu.pc, cpu.v[4]), (0x200, 0));
+        }
+        cpu.terminal_mut().handle_key_event(KeyEvent::Down(0xC));
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.v[4]), (0x202, 0xC));
+    }
+
+    #[test]
+    fn ld_vx_k_cancelled() {
+        use termion::event::Key;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0xF4, 0x0A]); // LD V4, K
+        cpu.v[4] = 7;
+        cpu.terminal_mut().set_cancel_key(Some(Key::Ctrl('k')));
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        cpu.terminal_mut().handle_key(Key::Ctrl('k'));
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.v[4]), (0x202, 7));
+        assert!(cpu.terminal().pressed_keys().next().is_none());
+    }
+
+    #[test]
+    fn cls() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.terminal.draw_sprite(10, 10, &[0xFF, 0xFF]);
+        cpu.terminal.draw_sprite(60, 31, &[0x81]);
+        cpu.execute_instruction((0, 0, 0xE, 0)).unwrap();
+        assert_eq!(cpu.terminal.framebuffer(), [0; 32]);
+    }
+
+    #[test]
+    fn ld_i_addr_then_drw() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0xA2,
+            0x08, // LD I, sprite
+            0xD0,
+            0x02, // DRW V0, V0, 2
+            0xD0,
+            0x02, // DRW V0, V0, 2
+            0x12,
+            0x06, // JP 0x206
+            0b1100_0000,
+            0b0110_0000, // sprite
+        ]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.i, 0x208);
+        cpu.step().unwrap();
+        let drawn = cpu.terminal.framebuffer();
+        assert_eq!(drawn[..3], [0b11 << 62, 0b11 << 61, 0]);
+        assert_eq!(cpu.v[0xF], 0);
+        // Drawing it again erases it and reports the collision
+        cpu.step().unwrap();
+        assert_eq!(cpu.terminal.framebuffer(), [0; 32]);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn drw_font_glyph() {
+        use crate::testing::assert_display_matches;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // The font is ordinary memory, so I can point at a glyph directly, here A
+        cpu.load(&[
+            0xA0, 0x32, // LD I, 0x032
+            0xD0, 0x05, // DRW V0, V0, 5
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_display_matches(
+            cpu.terminal(),
+            "
+            ####
+            #..#
+            ####
+            #..#
+            #..#
+            ",
+        );
+    }
+
+    #[test]
+    fn shift_variants() {
+        use crate::quirks::{Quirks, ShiftQuirk};
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // (quirk, operation) -> (V2, V9, VF), shifting with V2 = 0x81 and V9 = 0x42
+        let mut run = |shift, op| {
+            cpu.set_quirks(Quirks {
+                shift,
+                ..Quirks::default()
+            });
+            cpu.v[2] = 0x81;
+            cpu.v[9] = 0x42;
+            cpu.execute_instruction((8, 2, 9, op)).unwrap();
+            (cpu.v[2], cpu.v[9], cpu.v[0xF])
+        };
+        assert_eq!(run(ShiftQuirk::ModernVx, 6), (0x40, 0x42, 1));
+        assert_eq!(run(ShiftQuirk::ModernVx, 0xE), (0x02, 0x42, 1));
+        assert_eq!(run(ShiftQuirk::LegacyVy, 6), (0x21, 0x42, 0));
+        assert_eq!(run(ShiftQuirk::LegacyVy, 0xE), (0x84, 0x42, 0));
+        assert_eq!(run(ShiftQuirk::LegacyVyToVy, 6), (0x81, 0x21, 0));
+        assert_eq!(run(ShiftQuirk::LegacyVyToVy, 0xE), (0x81, 0x84, 0));
+    }
+
+    #[test]
+    fn shift_vf() {
+        use crate::quirks::{Quirks, ShiftQuirk};
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // SHR VF and SHL VF leave the shifted-out bit in VF, not the shifted value,
+        // whichever register the shift reads
+        for &shift in &[
+            ShiftQuirk::ModernVx,
+            ShiftQuirk::LegacyVy,
+            ShiftQuirk::LegacyVyToVy,
+        ] {
+            cpu.set_quirks(Quirks {
+                shift,
+                ..Quirks::default()
+            });
+            let mut run = |vf, op| {
+                cpu.v[0xF] = vf;
+                cpu.execute_instruction((8, 0xF, 0xF, op)).unwrap();
+                cpu.v[0xF]
+            };
+            assert_eq!(run(0b1000_0001, 6), 1);
+            assert_eq!(run(0b1000_0010, 6), 0);
+            assert_eq!(run(0b1000_0001, 0xE), 1);
+            assert_eq!(run(0b0100_0001, 0xE), 0);
+        }
+
+        // Shifting another register into VF still ends with the flag
+        cpu.set_quirks(Quirks {
+            shift: ShiftQuirk::LegacyVy,
+            ..Quirks::default()
+        });
+        cpu.v[3] = 0b0000_0010;
+        cpu.execute_instruction((8, 0xF, 3, 6)).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.v[3] = 0b1000_0000;
+        cpu.execute_instruction((8, 0xF, 3, 0xE)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn sne_vx_vy() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.pc, 0x200);
+        cpu.v[1] = 0xEF;
+        cpu.v[2] = 0xAA;
+        cpu.v[10] = 0xAA;
+        cpu.execute_instruction((9, 2, 10, 0)).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        cpu.execute_instruction((9, 1, 2, 0)).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn ld_i_addr() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.execute_instruction((0xA, 0xA, 0xB, 0xC)).unwrap();
+        assert_eq!(cpu.i, 0xABC);
+    }
+
+    #[test]
+    fn jp_v0_addr() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[0] = 0x11;
+        cpu.execute_instruction((0xB, 0xA, 0xB, 0xC)).unwrap();
+        assert_eq!(cpu.pc, 0xACD);
+    }
+
+    #[test]
+    fn ld_vx_dt() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.dt = 0x11;
+        cpu.execute_instruction((0xF, 4, 0, 7)).unwrap();
+        assert_eq!(cpu.v[4], 0x11);
+    }
+
+    #[test]
+    fn ld_dt_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0x11;
+        cpu.execute_instruction((0xF, 4, 1, 5)).unwrap();
+        assert_eq!(cpu.dt, 0x11);
+    }
+
+    #[test]
+    fn ld_st_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0x11;
+        cpu.execute_instruction((0xF, 4, 1, 8)).unwrap();
+        assert_eq!(cpu.st, 0x11);
+    }
+
+    #[test]
+    fn set_timers() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_delay_timer(42);
+        cpu.set_sound_timer(7);
+        // LD V3, DT
+        cpu.execute_instruction((0xF, 3, 0, 7)).unwrap();
+        assert_eq!(cpu.v[3], 42);
+        assert!(cpu.is_beeping());
+
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer(), cpu.sound_timer()), (41, 6));
+    }
+
+    #[test]
+    fn timer_hz() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // (timer Hz, frames) -> delay timer counted down from 200
+        let mut run = |hz, frames| {
+            cpu.set_timer_hz(hz);
+            cpu.dt = 200;
+            for _ in 0..frames {
+                cpu.tick_timers();
+            }
+            200 - cpu.dt
+        };
+        assert_eq!(run(60, 30), 30);
+        assert_eq!(run(120, 30), 60);
+        assert_eq!(run(30, 30), 15);
+        assert_eq!(run(45, 60), 45);
+        assert_eq!(run(600, 30), 200);
+    }
+
+    #[test]
+    fn is_beeping() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert!(!cpu.is_beeping());
+        cpu.v[4] = 2;
+        cpu.execute_instruction((0xF, 4, 1, 8)).unwrap();
+        assert!(cpu.is_beeping());
+        cpu.tick_timers();
+        assert!(cpu.is_beeping());
+        cpu.tick_timers();
+        assert!(!cpu.is_beeping());
+    }
+
+    #[test]
+    fn add_i_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0x11;
+        cpu.i = 0xAA;
+        cpu.execute_instruction((0xF, 4, 1, 0xE)).unwrap();
+        assert_eq!(cpu.i, 0xBB);
+    }
+
+    #[test]
+    fn index_overflow() {
+        use crate::quirks::{IndexOverflowQuirk, Quirks};
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        let mut add = |quirk, i| {
+            cpu.set_quirks(Quirks {
+                index_overflow: quirk,
+                add_i_wraps_12_bits: false,
+                ..Quirks::default()
+            });
+            cpu.v[1] = 1;
+            cpu.v[0xF] = 0xAA;
+            cpu.i = i;
+            cpu.execute_instruction((0xF, 1, 1, 0xE)).unwrap();
+            cpu.v[0xF]
+        };
+        assert_eq!(add(IndexOverflowQuirk::Unflagged, 0xFFF), 0xAA);
+        assert_eq!(add(IndexOverflowQuirk::Past0FFF, 0xFFE), 0);
+        assert_eq!(add(IndexOverflowQuirk::Past0FFF, 0xFFF), 1);
+        assert_eq!(add(IndexOverflowQuirk::Past0FFFF, 0xFFF), 0);
+        assert_eq!(add(IndexOverflowQuirk::Past0FFFF, 0xFFFE), 0);
+        assert_eq!(add(IndexOverflowQuirk::Past0FFFF, 0xFFFF), 1);
+    }
+
+    #[test]
+    fn sprite_heights() {
+        use crate::quirks::Quirks;
+        use crate::testing::assert_display_matches;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_quirks(Quirks::default());
+        for (i, byte) in cpu.memory[0x300..0x320].iter_mut().enumerate() {
+            *byte = if i % 2 == 0 { 0x80 } else { 0x01 };
+        }
+        cpu.i = 0x300;
+        // DRW V0, V1, n at (0, 0)
+        let draw = |cpu: &mut super::CPU<&[u8], io::Sink>, n| {
+            cpu.clear_display();
+            cpu.execute_instruction((0xD, 0, 1, n))
+        };
+
+        draw(&mut cpu, 1).unwrap();
+        assert_display_matches(cpu.terminal(), "#");
+        // Each byte is a row of its own
+        draw(&mut cpu, 15).unwrap();
+        let rows: Vec<_> = (0..15)
+            .map(|y| if y % 2 == 0 { "#" } else { ".......#" })
+            .collect();
+        assert_display_matches(cpu.terminal(), &rows.join("\n"));
+
+        // n = 0 is 16 rows of 16 pixels, two bytes to a row
+        draw(&mut cpu, 0).unwrap();
+        let row = "#..............#\n";
+        assert_display_matches(cpu.terminal(), &row.repeat(16));
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.execute_instruction((0xD, 0, 1, 0)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        assert_display_matches(cpu.terminal(), "");
+
+        // Strict base CHIP-8 only has 1 to 15 rows
+        cpu.set_strict(true);
+        draw(&mut cpu, 1).unwrap();
+        draw(&mut cpu, 15).unwrap();
+        cpu.pc = 0x200;
+        assert_eq!(
+            draw(&mut cpu, 0),
+            Err(super::ExecutionError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0xD010
+            })
+        );
+        assert_display_matches(cpu.terminal(), "");
+    }
+
+    #[test]
+    fn large_sprite_at_end_of_memory() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.memory[0xFF0..].fill(0xFF);
+        cpu.i = 0xFF0;
+        cpu.execute_instruction((0xD, 0, 0, 0)).unwrap();
+        // Eight rows from the end of memory, then eight from the font at its start
+        let rows = cpu.terminal.framebuffer();
+        assert_eq!(rows[..8], [0xFFFF << 48; 8]);
+        assert_eq!(rows[8], 0xF090 << 48);
+    }
+
+    #[test]
+    fn draw_origin_and_clipping() {
+        use crate::quirks::Quirks;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.memory[0x300..0x304].copy_from_slice(&[0xF0; 4]);
+        cpu.i = 0x300;
+        let mut draw = |wrap_draw_origin, clip_sprites, x, y, n| {
+            cpu.set_quirks(Quirks {
+                wrap_draw_origin,
+                clip_sprites_x: clip_sprites,
+                clip_sprites_y: clip_sprites,
+                ..Quirks::default()
+            });
+            cpu.terminal.reset_display();
+            cpu.v[1] = x;
+            cpu.v[2] = y;
+            cpu.execute_instruction((0xD, 1, 2, n)).unwrap();
+            cpu.terminal.framebuffer()
+        };
+
+        let mut expected = [0; 32];
+        expected[8] = 0xF0 << 50;
+        assert_eq!(draw(true, false, 70, 40, 1), expected);
+        assert_eq!(draw(false, false, 70, 40, 1), [0; 32]);
+        assert_eq!(draw(false, false, 6, 40, 1), [0; 32]);
+
+        // Off the bottom right corner, wrapping onto the other edges or not
+        let mut expected = [0; 32];
+        expected[31] = 0b11;
+        assert_eq!(draw(true, true, 62, 31, 2), expected);
+        expected[31] |= 0b11 << 62;
+        expected[0] = expected[31];
+        assert_eq!(draw(true, false, 62, 31, 2), expected);
+    }
+
+    #[test]
+    fn offscreen_sprite_collision() {
+        use crate::quirks::Quirks;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.memory[0x300] = 0xF0;
+        cpu.i = 0x300;
+        cpu.terminal.draw_sprite(8, 4, &[0xF0]);
+        let drawn = cpu.terminal.framebuffer();
+        cpu.v[1] = 200;
+        cpu.v[2] = 100;
+
+        // Clipped and not wrapped onto the display, nothing is drawn to collide with
+        cpu.set_quirks(Quirks {
+            wrap_draw_origin: false,
+            ..Quirks::default()
+        });
+        cpu.v[0xF] = 1;
+        cpu.execute_instruction((0xD, 1, 2, 1)).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+        assert_eq!(cpu.terminal.framebuffer(), drawn);
+
+        // Wrapped, (200, 100) is (8, 4), right on top of the sprite already there
+        cpu.set_quirks(Quirks {
+            wrap_draw_origin: true,
+            clip_sprites_x: false,
+            clip_sprites_y: false,
+            ..Quirks::default()
+        });
+        cpu.execute_instruction((0xD, 1, 2, 1)).unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        assert_eq!(cpu.terminal.framebuffer(), [0; 32]);
+    }
+
+    #[test]
+    fn clipping_per_axis() {
+        use crate::quirks::Quirks;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.memory[0x300..0x302].copy_from_slice(&[0xF0; 2]);
+        cpu.i = 0x300;
+        // Two rows of four pixels from (62, 31), over both the right and bottom edges
+        cpu.v[1] = 62;
+        cpu.v[2] = 31;
+        let mut draw = |clip_sprites_x, clip_sprites_y| {
+            cpu.set_quirks(Quirks {
+                clip_sprites_x,
+                clip_sprites_y,
+                ..Quirks::default()
+            });
+            cpu.terminal.reset_display();
+            cpu.execute_instruction((0xD, 1, 2, 2)).unwrap();
+            let rows = cpu.terminal.framebuffer();
+            (rows[31], rows[0])
+        };
+
+        let (right, wrapped) = (0b11, 0b11 << 62);
+        assert_eq!(draw(false, false), (right | wrapped, right | wrapped));
+        assert_eq!(draw(true, false), (right, right));
+        assert_eq!(draw(false, true), (right | wrapped, 0));
+        assert_eq!(draw(true, true), (right, 0));
+    }
+
+    #[test]
+    fn low_res_scroll() {
+        use crate::quirks::{LowResScrollQuirk, Quirks};
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        let mut scroll = |quirk, instruction| {
+            cpu.set_quirks(Quirks {
+                low_res_scroll: quirk,
+                ..Quirks::default()
+            });
+            cpu.terminal.reset_display();
+            cpu.terminal.draw_sprite(8, 0, &[0b1000_0000]);
+            cpu.execute_instruction(instruction).unwrap();
+            let pixels = cpu.terminal.framebuffer();
+            let row = pixels.iter().position(|&line| line != 0).unwrap();
+            (row, pixels[row].leading_zeros())
+        };
+        assert_eq!(scroll(LowResScrollQuirk::Doubled, (0, 0, 0xC, 3)), (3, 8));
+        assert_eq!(scroll(LowResScrollQuirk::Halved, (0, 0, 0xC, 4)), (2, 8));
+        assert_eq!(scroll(LowResScrollQuirk::Halved, (0, 0, 0xC, 3)), (1, 8));
+        assert_eq!(
+            scroll(LowResScrollQuirk::Doubled, (0, 0, 0xF, 0xB)),
+            (0, 12)
+        );
+        assert_eq!(scroll(LowResScrollQuirk::Halved, (0, 0, 0xF, 0xB)), (0, 10));
+        assert_eq!(scroll(LowResScrollQuirk::Halved, (0, 0, 0xF, 0xC)), (0, 6));
+    }
+
+    #[test]
+    fn add_i_vx_past_4k() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0x20;
+        cpu.i = 0xFF0;
+        cpu.execute_instruction((0xF, 4, 1, 0xE)).unwrap();
+        assert_eq!(cpu.i, 0x010);
+
+        cpu.set_quirks(crate::quirks::Quirks {
+            add_i_wraps_12_bits: false,
+            ..crate::quirks::Quirks::default()
+        });
+        cpu.i = 0xFF0;
+        cpu.execute_instruction((0xF, 4, 1, 0xE)).unwrap();
+        assert_eq!(cpu.i, 0x1010);
+        cpu.i = 0xFFF0;
+        cpu.execute_instruction((0xF, 4, 1, 0xE)).unwrap();
+        assert_eq!(cpu.i, 0x0010);
+    }
+
+    #[test]
+    fn state_json() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x60, 0x2A, 0x22, 0x06, 0x00, 0x00, 0xA1, 0x23]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let json = cpu.state_json();
+        assert!(json.starts_with(
+            "{\"pc\": 520, \"i\": 291, \"v\": [42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], \
+             \"stack\": [516], \"delay_timer\": 0, \"sound_timer\": 0, \"cycles\": 3, \
+             \"halted\": false, \"display_hash\": \""
+        ));
+        assert!(json.ends_with(&format!("{:016x}\"}}", cpu.terminal.framebuffer_hash())));
+    }
+
+    #[test]
+    fn save_state() {
+        use crate::quirks::Quirks;
+
+        let r: &[u8] = b"";
+        let mut saved = super::CPU::new(r, sink());
+        saved.set_quirks(Quirks::cosmac());
+        saved.set_cycles_per_frame(20);
+        saved.load(&[
+            0x60, 0x81, // LD V0, 0x81
+            0x61, 0x42, // LD V1, 0x42
+            0x22, 0x08, // CALL 0x208
+            0x00, 0x00, // SYS 0
+            0xF0, 0x29, // LD F, V0
+            0xD0, 0x05, // DRW V0, V0, 5
+        ]);
+        for _ in 0..5 {
+            saved.step().unwrap();
+        }
+        saved.dt = 30;
+        let state = saved.save_state();
+
+        let mut restored = super::CPU::new(r, sink());
+        restored.set_cycles_per_frame(5);
+        restored.load(&[0x12, 0x00]);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.quirks, Quirks::cosmac());
+        assert_eq!(restored.cycles_per_frame, 20);
+        assert_eq!(
+            (restored.pc, restored.i, restored.dt, restored.cycles),
+            (0x20C, 5, 30, 5)
+        );
+        assert_eq!(restored.stack(), [0x206]);
+        assert_eq!(restored.memory[..], saved.memory[..]);
+        assert_eq!(
+            restored.terminal.framebuffer(),
+            saved.terminal.framebuffer()
+        );
+        assert_eq!(restored.save_state(), state);
+
+        // Both shift the COSMAC way, Vx = Vy >> 1
+        saved.execute_instruction((8, 0, 1, 6)).unwrap();
+        restored.execute_instruction((8, 0, 1, 6)).unwrap();
+        assert_eq!(restored.v, saved.v);
+        assert_eq!(restored.v[0], 0x21);
+
+        // Both planes keep every row of a hires display
+        saved.terminal.set_display_height(64);
+        saved.terminal.set_row(40, 0xF0);
+        let mut plane2 = vec![0; 64];
+        plane2[63] = 0x0F;
+        saved.terminal.set_second_plane_rows(&plane2);
+        restored.load_state(&saved.save_state()).unwrap();
+        assert_eq!(restored.terminal.rows(), saved.terminal.rows());
+        assert_eq!(restored.terminal.second_plane_rows(), &plane2[..]);
+    }
+
+    #[test]
+    fn bad_save_state() {
+        use super::StateError;
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        let state = cpu.save_state();
+        cpu.v[3] = 7;
+        assert_eq!(cpu.load_state(b"C8"), Err(StateError::NotAState));
+        assert_eq!(cpu.load_state(&state[1..]), Err(StateError::NotAState));
+        let mut newer = state.clone();
+        newer[4] = 2;
+        assert_eq!(
+            cpu.load_state(&newer),
+            Err(StateError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::Corrupt)
+        );
+        let mut bad_quirk = state.clone();
+        bad_quirk[5] = 9;
+        assert_eq!(cpu.load_state(&bad_quirk), Err(StateError::Corrupt));
+
+        // Past the quirks, speed and V0-VF come I, whether it was set, PC, the stack
+        // depth and SP, then the stack itself
+        let i = 5 + crate::quirks::QUIRKS_BYTES + 4 + 16;
+        let (pc, depth, stack) = (i + 3, i + 5, i + 9);
+        let patched = |at: usize, value: u16| {
+            let mut patched = state.clone();
+            patched[at..at + 2].copy_from_slice(&value.to_be_bytes());
+            patched
+        };
+        for bad in &[
+            patched(i, 0x1000),
+            patched(pc, 0xFFF),
+            patched(pc, 0xFFFF),
+            patched(stack, 0xFFF),
+            patched(depth, 0),
+            patched(depth, super::MAX_STACK_DEPTH as u16 + 1),
+        ] {
+            assert_eq!(cpu.load_state(bad), Err(StateError::Corrupt));
+        }
+        assert_eq!(cpu.v[3], 7);
+        for good in &[patched(i, 0xFFF), patched(pc, 0xFFE), patched(stack, 0xFFE)] {
+            assert_eq!(cpu.load_state(good), Ok(()));
+        }
+        assert_eq!(
+            StateError::UnsupportedVersion(2).to_string(),
+            "save state version 2 can't be read, only version 1"
+        );
+    }
+
+    #[test]
+    fn debug_dump() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x60, 0x2A, 0x22, 0x06, 0x00, 0x00, 0xA1, 0x23]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        cpu.v[0xF] = 1;
+        cpu.dt = 0x3C;
+        let dump = cpu.debug_dump();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "PC 208  I 123  SP 1  DT 3C  ST 00",
+                "V0-V7 2A 00 00 00 00 00 00 00",
+                "V8-VF 00 00 00 00 00 00 00 01",
+                "stack 204",
+                "cycles 3",
+            ]
+        );
+        assert_eq!(
+            lines[5],
+            format!("display {:016x}", cpu.terminal.framebuffer_hash())
+        );
+        assert!(lines[6].starts_with("quirks Quirks { shift: ModernVx"));
+    }
+
+    #[test]
+    fn stack_profile() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // Three nested CALLs, each returning, then a jump to itself
+        cpu.load(&[
+            0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x22, 0x0C, 0x00, 0xEE, 0x00, 0x00, 0x22, 0x12,
+            0x00, 0xEE, 0x00, 0x00, 0x00, 0xEE,
+        ]);
+        assert!(cpu.stack_profile().is_none());
+        cpu.set_stack_profile(true);
+        cpu.step_frame().unwrap();
+        cpu.step_frame().unwrap();
+
+        let profile = cpu.stack_profile().unwrap();
+        assert_eq!(profile.max_depth, 3);
+        assert_eq!(profile.max_pc, 0x212);
+        assert_eq!(profile.trail, [0x200, 0x206, 0x20C]);
+        assert_eq!(profile.depths, [0, 0]);
+        assert_eq!(
+            profile.to_string(),
+            "max stack depth 3 at 212, called from 200 > 206 > 20C\n\
+             2 frames, mean depth at frame end 0.0\n"
+        );
+    }
+
+    #[test]
+    fn font() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.font().len(), 80);
+        assert_eq!(cpu.font(), &super::FONT[..]);
+
+        cpu.draw_font();
+        let zero: Vec<u64> = cpu.terminal.framebuffer()[..6]
+            .iter()
+            .map(|line| line >> 60)
+            .collect();
+        assert_eq!(zero, [0b1111, 0b1001, 0b1001, 0b1001, 0b1111, 0]);
+    }
+
+    #[test]
+    fn ld_f_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0xA;
+        cpu.execute_instruction((0xF, 4, 2, 9)).unwrap();
+        assert_eq!(cpu.memory[cpu.i as usize], 0xF0);
+        assert_eq!(cpu.memory[cpu.i as usize + 1], 0x90);
+        assert_eq!(cpu.memory[cpu.i as usize + 2], 0xF0);
+        assert_eq!(cpu.memory[cpu.i as usize + 3], 0x90);
+        assert_eq!(cpu.memory[cpu.i as usize + 4], 0x90);
+
+        cpu.v[4] = 0xBA;
+        cpu.execute_instruction((0xF, 4, 2, 9)).unwrap();
+        assert_eq!(cpu.memory[cpu.i as usize], 0xF0);
+        assert_eq!(cpu.memory[cpu.i as usize + 1], 0x90);
+        assert_eq!(cpu.memory[cpu.i as usize + 2], 0xF0);
+        assert_eq!(cpu.memory[cpu.i as usize + 3], 0x90);
+        assert_eq!(cpu.memory[cpu.i as usize + 4], 0x90);
+    }
+
+    #[test]
+    fn ld_b_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[4] = 0xFE;
+        cpu.i = 0x100;
+        cpu.execute_instruction((0xF, 4, 3, 3)).unwrap();
+        assert_eq!(cpu.memory[0x100], 2);
+        assert_eq!(cpu.memory[0x101], 5);
+        assert_eq!(cpu.memory[0x102], 4);
+    }
+
+    #[test]
+    fn ld_i_vx() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v[0] = 0x12;
+        cpu.v[1] = 0x34;
+        cpu.v[2] = 0x56;
+        cpu.v[3] = 0x78;
+        cpu.i = 0x100;
+        cpu.execute_instruction((0xF, 3, 5, 5)).unwrap();
+        assert_eq!(cpu.memory[0x100], 0x12);
+        assert_eq!(cpu.memory[0x101], 0x34);
+        assert_eq!(cpu.memory[0x102], 0x56);
+        assert_eq!(cpu.memory[0x103], 0x78);
+    }
+
+    #[test]
+    fn ld_vx_i() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.memory[0x100] = 0x12;
+        cpu.memory[0x101] = 0x34;
+        cpu.memory[0x102] = 0x56;
+        cpu.memory[0x103] = 0x78;
+        cpu.i = 0x100;
+        cpu.execute_instruction((0xF, 3, 6, 5)).unwrap();
+        assert_eq!(cpu.v[0], 0x12);
+        assert_eq!(cpu.v[1], 0x34);
+        assert_eq!(cpu.v[2], 0x56);
+        assert_eq!(cpu.v[3], 0x78);
     }
 
-    fn sne_vx_vy(&mut self, x: u8, y: u8) {
-        if self.v[x as usize] != self.v[y as usize] {
-            self.pc += 2
+    #[test]
+    fn i_past_end_of_memory() {
+        use crate::quirks::Quirks;
+
+        for &wraps in &[true, false] {
+            let r: &[u8] = b"";
+            let mut cpu = super::CPU::new(r, sink());
+            cpu.set_quirks(Quirks {
+                add_i_wraps_12_bits: wraps,
+                load_store_increments_i: true,
+                ..Quirks::default()
+            });
+            cpu.v[0] = 0x12;
+            cpu.v[1] = 0x34;
+            cpu.i = 0xFFF;
+            cpu.execute_instruction((0xF, 1, 5, 5)).unwrap();
+            assert_eq!((cpu.memory[0xFFF], cpu.memory[0]), (0x12, 0x34));
+            assert_eq!(cpu.i, 0x1001);
+
+            cpu.i = 0xFFF;
+            cpu.execute_instruction((0xF, 1, 6, 5)).unwrap();
+            assert_eq!(cpu.v[..2], [0x12, 0x34]);
+
+            // Past 0FFF without the quirk, reading from the start of memory
+            cpu.memory[1] = 0x56;
+            cpu.memory[2] = 0x78;
+            cpu.i = 0xFFE;
+            cpu.v[2] = 3;
+            cpu.execute_instruction((0xF, 2, 1, 0xE)).unwrap();
+            assert_eq!(cpu.i, if wraps { 0x001 } else { 0x1001 });
+            cpu.execute_instruction((0xF, 1, 6, 5)).unwrap();
+            assert_eq!(cpu.v[..2], [0x56, 0x78]);
+
+            cpu.v[4] = 255;
+            cpu.i = 0xFFF;
+            cpu.execute_instruction((0xF, 4, 3, 3)).unwrap();
+            assert_eq!((cpu.memory[0xFFF], cpu.memory[0], cpu.memory[1]), (2, 5, 5));
+
+            cpu.memory[0xFFF] = 0xFF;
+            cpu.memory[0] = 0x81;
+            cpu.execute_instruction((0xD, 5, 5, 2)).unwrap();
+            assert_eq!(cpu.terminal.framebuffer()[..2], [0xFF << 56, 0x81 << 56]);
+
+            // I near FFFF moves on without overflowing
+            cpu.i = 0xFFFF;
+            cpu.execute_instruction((0xF, 1, 5, 5)).unwrap();
+            assert_eq!(cpu.i, 1);
         }
     }
 
-    fn shl_vx(&mut self, x: u8) {
-        let vx = self.v[x as usize];
-        self.v[0xF] = if vx & 128 == 128 { 1 } else { 0 };
-        self.v[x as usize] = vx << 1
+    #[test]
+    fn load_store_boundaries() {
+        assert_eq!(super::registers_transferred(0), 1);
+        assert_eq!(super::registers_transferred(0xF), 16);
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.v = [0xAA; 16];
+        cpu.i = 0x300;
+        cpu.execute_instruction((0xF, 0, 5, 5)).unwrap();
+        assert_eq!(cpu.memory[0x300..0x302], [0xAA, 0]);
+
+        cpu.v = [0xBB; 16];
+        cpu.execute_instruction((0xF, 0xF, 5, 5)).unwrap();
+        assert_eq!(cpu.memory[0x300..0x310], [0xBB; 16]);
+        assert_eq!(cpu.memory[0x310], 0);
+
+        cpu.memory[0x300..0x310].copy_from_slice(&[0xCC; 16]);
+        cpu.v = [0; 16];
+        cpu.execute_instruction((0xF, 0, 6, 5)).unwrap();
+        assert_eq!(cpu.v[..2], [0xCC, 0]);
+        cpu.execute_instruction((0xF, 0xF, 6, 5)).unwrap();
+        assert_eq!(cpu.v, [0xCC; 16]);
     }
 
-    fn subn_vx_vy(&mut self, x: u8, y: u8) {
-        let vx = self.v[x as usize];
-        let vy = self.v[y as usize];
-        self.v[0xF] = if vy >= vx { 1 } else { 0 };
-        self.v[x as usize] = vy.wrapping_sub(vx)
+    #[test]
+    fn cosmac_quirks() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_quirks(crate::quirks::Quirks::cosmac());
+        cpu.v[0xF] = 1;
+        cpu.execute_instruction((8, 2, 9, 1)).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.v[2] = 0b0000_0001;
+        cpu.v[9] = 0b0000_0110;
+        cpu.execute_instruction((8, 2, 9, 6)).unwrap();
+        assert_eq!(cpu.v[2], 0b0000_0011);
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.execute_instruction((8, 2, 9, 0xE)).unwrap();
+        assert_eq!(cpu.v[2], 0b0000_1100);
+
+        cpu.i = 0x300;
+        cpu.execute_instruction((0xF, 3, 5, 5)).unwrap();
+        assert_eq!(cpu.i, 0x304);
+        cpu.execute_instruction((0xF, 1, 6, 5)).unwrap();
+        assert_eq!(cpu.i, 0x306);
     }
 
-    fn shr_vx(&mut self, x: u8) {
-        let vx = self.v[x as usize];
-        self.v[0xF] = if vx & 1 == 1 { 1 } else { 0 };
-        self.v[x as usize] = vx >> 1
+    #[test]
+    fn self_modifying_code() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        // Write JP 0xABC to 0x300, then jump there
+        cpu.v[0] = 0x1A;
+        cpu.v[1] = 0xBC;
+        cpu.i = 0x300;
+        cpu.execute_instruction((0xF, 1, 5, 5)).unwrap();
+        cpu.execute_instruction((1, 3, 0, 0)).unwrap();
+        assert_eq!(cpu.pc, 0x300);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xABC);
     }
 
-    fn sub_vx_vy(&mut self, x: u8, y: u8) {
-        let vx = self.v[x as usize];
-        let vy = self.v[y as usize];
-        self.v[0xF] = if vx >= vy { 1 } else { 0 };
-        self.v[x as usize] = vx.wrapping_sub(vy)
+    #[test]
+    fn step_back() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_rewind_depth(2);
+        cpu.load(&[
+            0x60, 0x01, // LD V0, 1
+            0xA3, 0x00, // LD I, 0x300
+            0xF0, 0x55, // LD [I], V0
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory[0x300], 1);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!((cpu.v[0], cpu.i), (1, 0x300));
+        assert_eq!(cpu.memory[0x300], 0);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!((cpu.v[0], cpu.i), (1, 0));
+        // Only two steps are kept
+        assert!(!cpu.step_back());
+        assert_eq!(cpu.cycles(), 1);
     }
 
-    fn add_vx_vy(&mut self, x: u8, y: u8) {
-        let vx = self.v[x as usize] as u16;
-        let vy = self.v[y as usize] as u16;
-        self.v[0xF] = if vx + vy > 255 { 1 } else { 0 };
-        self.v[x as usize] = (vx + vy % 256) as u8
+    /// Input arriving one byte per poll, as typed keys do.
+    struct Typed {
+        bytes: Vec<u8>,
+        // Whether the last read returned a byte, so this one ends the poll
+        ended_poll: bool,
     }
 
-    fn se_vx_vy(&mut self, x: u8, y: u8) {
-        if self.v[x as usize] == self.v[y as usize] {
-            self.pc += 2
+    impl Typed {
+        fn new(bytes: &[u8]) -> Self {
+            Typed {
+                bytes: bytes.to_vec(),
+                ended_poll: false,
+            }
         }
     }
 
-    fn sne_vx_byte(&mut self, x: u8, k1: u8, k2: u8) {
-        if self.v[x as usize] != to_byte(k1, k2) {
-            self.pc += 2
+    impl Read for Typed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.ended_poll || self.bytes.is_empty() {
+                self.ended_poll = false;
+                return Ok(0);
+            }
+            self.ended_poll = true;
+            buf[0] = self.bytes.remove(0);
+            Ok(1)
         }
     }
 
-    fn se_vx_byte(&mut self, x: u8, k1: u8, k2: u8) {
-        if self.v[x as usize] == to_byte(k1, k2) {
-            self.pc += 2
+    #[test]
+    fn wait_for_key_ignores_unmapped_keys() {
+        let mut cpu = super::CPU::new(Typed::new(b"xz5"), sink());
+        cpu.load(&[0xF3, 0x0A]); // LD V3, K
+        for _ in 0..2 {
+            cpu.step().unwrap();
+            assert_eq!(cpu.pc, 0x200);
         }
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.v[3]), (0x202, 5));
+
+        let mut cpu = super::CPU::new(Typed::new(b"x\x03"), sink());
+        cpu.load(&[0xF3, 0x0A]);
+        assert_eq!(cpu.run_frame(), Ok(true));
+        assert_eq!(cpu.run_frame(), Ok(false));
+        assert!(cpu.terminal.exit);
     }
 
-    fn call_addr(&mut self, a: u8, b: u8, c: u8) {
-        self.stack[self.sp as usize] = self.pc;
-        self.sp += 1;
-        self.pc = addr(a, b, c)
-    }
+    struct SlowClock(Cell<Duration>);
 
-    fn ret(&mut self) {
-        self.sp -= 1;
-        self.pc = self.stack[self.sp as usize];
+    impl Clock for SlowClock {
+        // Every instruction seems to take a millisecond
+        fn now(&self) -> Duration {
+            self.0.set(self.0.get() + Duration::from_millis(1));
+            self.0.get()
+        }
     }
 
-    fn ld_b_vx(&mut self, x: u8) {
-        let vx = self.v[x as usize];
-        self.memory[self.i as usize] = vx / 100;
-        self.memory[self.i as usize + 1] = vx % 100 / 10;
-        self.memory[self.i as usize + 2] = vx % 10;
+    #[test]
+    fn frame_time_budget() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x60, 0x01, 0x12, 0x00]); // LD V0, 1; JP 0x200
+        cpu.set_cycles_per_frame(100);
+        cpu.set_clock(Box::new(SlowClock(Cell::new(Duration::ZERO))));
+        assert_eq!(cpu.execute_frame().unwrap(), 100);
+        assert_eq!(cpu.carried_cycles(), 0);
+
+        cpu.set_frame_time_budget(Some(Duration::from_millis(5)));
+        assert_eq!(cpu.execute_frame().unwrap(), 4);
+        assert_eq!(cpu.carried_cycles(), 96);
+        // Still slow: the backlog is capped at one frame
+        assert_eq!(cpu.execute_frame().unwrap(), 4);
+        assert_eq!(cpu.carried_cycles(), 100);
+
+        cpu.set_frame_time_budget(None);
+        assert_eq!(cpu.execute_frame().unwrap(), 200);
+        assert_eq!(cpu.carried_cycles(), 0);
     }
 
-    fn ld_i_vx(&mut self, x: u8) {
-        for i in 0..=(x as usize) {
-            self.memory[self.i as usize + i] = self.v[i]
+    #[test]
+    fn clear_display() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.terminal.set_anti_flicker(true);
+        cpu.load(&[
+            0x60, 0x05, // LD V0, 5
+            0xA0, 0x00, // LD I, 0
+            0xD0, 0x05, // DRW V0, V0, 5
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
         }
+        cpu.present();
+        assert_ne!(cpu.terminal.framebuffer(), [0; 32]);
+
+        cpu.clear_display();
+        assert_eq!(cpu.terminal.framebuffer(), [0; 32]);
+        assert_eq!(cpu.terminal.to_ascii().trim(), "");
+        assert_eq!((cpu.v[0], cpu.i, cpu.pc), (5, 0, 0x206));
+        assert_eq!(cpu.memory[0x200..0x202], [0x60, 0x05]);
     }
 
-    fn ld_vx_i(&mut self, x: u8) {
-        for i in 0..=(x as usize) {
-            self.v[i] = self.memory[self.i as usize + i]
+    #[test]
+    fn cls_clears_selected_planes() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0xF3, 0x01, // PLANE 3
+            0xA2, 0x0A, // LD I, sprite
+            0xD0, 0x01, // DRW V0, V0, 1
+            0xF1, 0x01, // PLANE 1
+            0x00, 0xE0, // CLS
+            0xFF, 0x81, // sprite: 0xFF for plane 1, 0x81 for plane 2
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
         }
-    }
-}
+        assert_eq!(cpu.terminal.framebuffer()[0], 0xFF << 56);
+        assert_eq!(cpu.terminal.second_plane()[0], 0x81 << 56);
 
-fn to_byte(a: u8, b: u8) -> u8 {
-    (a << 4) + b
-}
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.terminal.framebuffer(), [0; 32]);
+        assert_eq!(cpu.terminal.second_plane()[0], 0x81 << 56);
 
-fn addr(a: u8, b: u8, c: u8) -> u16 {
-    ((a as u16) << 8) + ((b as u16) << 4) + (c as u16)
-}
+        cpu.set_strict(true);
+        cpu.pc = 0x200;
+        assert!(cpu.step().is_err());
+    }
 
-#[cfg(test)]
-mod tests {
     #[test]
-    fn ret() {
+    fn display_wait() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.sp = 1;
-        cpu.stack[0] = 0xDDD;
-        cpu.execute_instruction((0, 0, 0xE, 0xE));
-        assert_eq!(cpu.pc, 0xDDD);
+        let mut cpu = super::CPU::new(r, sink());
+        let rom = [
+            0xD0, 0x05, // DRW V0, V0, 5
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x12, 0x04, // JP 0x204
+        ];
+        cpu.load(&rom);
+        assert_eq!(cpu.execute_frame().unwrap(), 3);
+
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_quirks(crate::quirks::Quirks {
+            display_wait: true,
+            ..crate::quirks::Quirks::default()
+        });
+        cpu.load(&rom);
+        assert_eq!(cpu.execute_frame().unwrap(), 1);
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.draws_last_frame(), 1);
+        assert_eq!(cpu.execute_frame().unwrap(), 2);
+        assert!(cpu.halted);
     }
 
     #[test]
-    fn jp() {
+    fn weighted_timing() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.execute_instruction((2, 0xA, 0xE, 0xF));
-        assert_eq!(cpu.pc, 0xAEF);
-        assert_eq!(cpu.sp, 1);
-        assert_eq!(cpu.stack[0], 0x202);
+        let mut cpu = super::CPU::new(r, sink());
+        // LD V0, 1 over and over
+        cpu.load(&[0x60, 0x01].repeat(64));
+        assert_eq!(cpu.execute_frame().unwrap(), 10);
+        cpu.pc = 0x200;
+        cpu.set_timing(super::Timing::Weighted);
+        assert_eq!(cpu.execute_frame().unwrap(), 10);
+
+        let mut cpu = super::CPU::new(r, sink());
+        // DRW V0, V0, 4 over and over
+        cpu.load(&[0xD0, 0x04].repeat(64));
+        assert_eq!(cpu.execute_frame().unwrap(), 10);
+        cpu.pc = 0x200;
+        cpu.set_timing(super::Timing::Weighted);
+        assert_eq!(cpu.execute_frame().unwrap(), 2);
     }
 
     #[test]
-    fn call() {
+    fn uninitialized_i() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.execute_instruction((1, 0xA, 0xE, 0xF));
-        assert_eq!(cpu.pc, 0xAEF);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.execute_instruction((0xD, 0, 0, 5)).unwrap();
+        assert_eq!(cpu.last_warning(), None);
+
+        cpu.set_diagnostics(super::Diagnostics {
+            uninitialized_i: true,
+            ..Default::default()
+        });
+        cpu.execute_instruction((0xD, 0, 0, 5)).unwrap();
+        assert_eq!(
+            cpu.last_warning(),
+            Some(super::Warning::UninitializedI { pc: 0x202 })
+        );
+
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_diagnostics(super::Diagnostics {
+            uninitialized_i: true,
+            ..Default::default()
+        });
+        cpu.execute_instruction((0xA, 3, 0, 0)).unwrap();
+        cpu.execute_instruction((0xD, 0, 0, 5)).unwrap();
+        cpu.execute_instruction((0xF, 3, 5, 5)).unwrap();
+        assert_eq!(cpu.last_warning(), None);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Fails every write, as a log on a full disk would
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn se_vx_byte() {
+    fn instruction_log() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        assert_eq!(cpu.pc, 0x200);
-        cpu.v[1] = 0xEF;
-        cpu.v[2] = 0xAA;
-        cpu.execute_instruction((3, 1, 0xE, 0xF));
-        assert_eq!(cpu.pc, 0x204);
-        cpu.execute_instruction((3, 2, 0xD, 0xD));
-        assert_eq!(cpu.pc, 0x206);
+        let mut cpu = super::CPU::new(r, sink());
+        let log = SharedBuffer::default();
+        cpu.set_instruction_log(Some(Box::new(log.clone())));
+        cpu.load(&[
+            0x60, 0x01, // LD V0, 1
+            0xA3, 0x00, // LD I, 0x300
+            0x22, 0x08, // CALL 0x208
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let zeros = " 00".repeat(15);
+        assert_eq!(
+            String::from_utf8(log.0.borrow().clone()).unwrap(),
+            format!(
+                "0200 6001 LD V0, 0x01      00{z} 0000 0\n\
+                 0202 A300 LD I, 0x300      01{z} 0000 0\n\
+                 0204 2208 CALL 0x208       01{z} 0300 0\n",
+                z = zeros
+            )
+        );
     }
 
     #[test]
-    fn sne_vx_byte() {
+    fn instruction_log_full() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        assert_eq!(cpu.pc, 0x200);
-        cpu.v[1] = 0xEF;
-        cpu.v[2] = 0xAA;
-        cpu.execute_instruction((4, 1, 0xE, 0xF));
-        assert_eq!(cpu.pc, 0x202);
-        cpu.execute_instruction((4, 2, 0xD, 0xD));
-        assert_eq!(cpu.pc, 0x206);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_instruction_log(Some(Box::new(FullDisk)));
+        cpu.load(&[0x60, 0x01, 0x61, 0x02]); // LD V0, 1; LD V1, 2
+        cpu.step().unwrap();
+        assert!(cpu.instruction_log.is_none());
+        cpu.step().unwrap();
+        assert_eq!(cpu.v[..2], [1, 2]);
     }
 
     #[test]
-    fn se_vx_vy() {
+    fn draw_log() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        assert_eq!(cpu.pc, 0x200);
-        cpu.v[1] = 0xEF;
-        cpu.v[2] = 0xAA;
-        cpu.v[10] = 0xAA;
-        cpu.execute_instruction((5, 2, 10, 0));
-        assert_eq!(cpu.pc, 0x204);
-        cpu.execute_instruction((5, 1, 2, 0));
-        assert_eq!(cpu.pc, 0x206);
+        let mut cpu = super::CPU::new(r, sink());
+        let log = SharedBuffer::default();
+        cpu.set_draw_log(Some(Box::new(log.clone())));
+        cpu.load(&[
+            0x60, 0x08, // LD V0, 8
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x00, 0xE0, // CLS
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(log.0.borrow().clone()).unwrap(),
+            "202 sprite 8,8 5 rows\n204 clear planes 1\n"
+        );
     }
 
     #[test]
-    fn ld_vx_byte() {
+    fn draw_log_full() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.execute_instruction((6, 2, 0xE, 0xA));
-        assert_eq!(cpu.v[2], 0xEA);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_draw_log(Some(Box::new(FullDisk)));
+        cpu.load(&[
+            0xD0, 0x05, // DRW V0, V0, 5
+            0xD0, 0x05, // DRW V0, V0, 5
+        ]);
+        cpu.step().unwrap();
+        assert!(cpu.draw_log.is_none());
+        cpu.step().unwrap();
+        assert_eq!(cpu.terminal.framebuffer()[0], 0);
     }
 
     #[test]
-    fn add_vx_byte() {
+    fn display_backend() {
+        use crate::backend::Display;
+        use crate::terminal::DrawEvent;
+
+        // D for each draw, then the framebuffer's top row when presented
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl Display for Recorder {
+            fn draw(&mut self, _event: &DrawEvent) {
+                self.0.borrow_mut().push("D".to_string());
+            }
+
+            fn present(&mut self, rows: &[u64]) {
+                self.0.borrow_mut().push(format!("{:X}", rows[0]));
+            }
+        }
+
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0x22;
-        cpu.execute_instruction((7, 2, 0x4, 0x5));
-        assert_eq!(cpu.v[2], 0x67);
+        let mut cpu = super::CPU::new(r, sink());
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_display(Some(Box::new(Recorder(calls.clone()))));
+        cpu.set_cycles_per_frame(4);
+        cpu.load(&[
+            0xD0, 0x01, // DRW V0, V0, 1
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x12, 0x00, // JP 0x200
+        ]);
+        cpu.run_frame().unwrap();
+        cpu.run_frame().unwrap();
+        // Three draws a frame, each frame presented once they're all done
+        assert_eq!(
+            *calls.borrow(),
+            ["D", "D", "D", "F000000000000000", "D", "D", "D", "0"]
+        );
     }
 
     #[test]
-    fn ld_vx_vy() {
+    fn recent_instructions() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[3] = 0xEE;
-        cpu.execute_instruction((8, 2, 3, 0));
-        assert_eq!(cpu.v[2], 0xEE);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x01, // LD V0, 1
+            0x70, 0x01, // ADD V0, 1
+            0x22, 0x08, // CALL 0x208
+            0x00, 0x00, // SYS 0
+            0xFF, 0xFF, // unknown
+        ]);
+        while cpu.step().is_ok() {}
+        assert_eq!(
+            cpu.recent_instructions().collect::<Vec<_>>(),
+            [
+                (0x200, 0x6001),
+                (0x202, 0x7001),
+                (0x204, 0x2208),
+                (0x208, 0xFFFF)
+            ]
+        );
+
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x12, 0x00]); // JP 0x200, which counts as a halt but keeps stepping
+        for _ in 0..100 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            cpu.recent_instructions().count(),
+            super::RECENT_INSTRUCTIONS
+        );
     }
 
     #[test]
-    fn or_vx_vy() {
+    fn breakpoint() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0b1100_1001;
-        cpu.v[9] = 0b1000_0101;
-        cpu.execute_instruction((8, 2, 9, 1));
-        assert_eq!(cpu.v[2], 0b1100_1101);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x01, // LD V0, 1
+            0x61, 0x02, // LD V1, 2
+            0x62, 0x03, // LD V2, 3
+            0x12, 0x06, // JP 0x206
+        ]);
+        cpu.add_breakpoint(0x202);
+        assert!(cpu.run_until_breakpoint().unwrap());
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(cpu.v[..3], [1, 0, 0]);
+
+        // Continuing runs the instruction under the breakpoint
+        cpu.remove_breakpoint(0x202);
+        cpu.add_breakpoint(0x204);
+        assert!(cpu.run_until_breakpoint().unwrap());
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(cpu.v[..3], [1, 2, 0]);
+
+        assert!(!cpu.run_until_breakpoint().unwrap());
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.v[..3], [1, 2, 3]);
     }
 
     #[test]
-    fn and_vx_vy() {
+    fn breakpoint_after_delay() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0b1100_1001;
-        cpu.v[9] = 0b1000_0101;
-        cpu.execute_instruction((8, 2, 9, 2));
-        assert_eq!(cpu.v[2], 0b1000_0001);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x05, // LD V0, 5
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x04, // JP 0x204
+            0x12, 0x0A, // JP 0x20A
+        ]);
+        cpu.add_breakpoint(0x20A);
+        assert!(cpu.run_until_breakpoint().unwrap());
+        assert_eq!(cpu.delay_timer(), 0);
+
+        // Looping forever, until the quit key is read between frames
+        let mut cpu = super::CPU::new(&b"\x03"[..], sink());
+        cpu.load(&[
+            0x70, 0x01, // ADD V0, 1
+            0x12, 0x00, // JP 0x200
+        ]);
+        assert!(!cpu.run_until_breakpoint().unwrap());
+        assert!(cpu.terminal().exit);
     }
 
     #[test]
-    fn xor_vx_vy() {
+    fn set_pc() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0b1100_1001;
-        cpu.v[9] = 0b1000_0101;
-        cpu.execute_instruction((8, 2, 9, 3));
-        assert_eq!(cpu.v[2], 0b0100_1100);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x01, // LD V0, 1
+            0x12, 0x02, // JP 0x202
+            0x61, 0x02, // LD V1, 2
+            0x00, 0xEE, // RET
+        ]);
+        cpu.set_pc(0x204).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc(), 0x206);
+        assert_eq!(cpu.v[..2], [0, 2]);
+
+        assert!(cpu.set_pc(0xFFE).is_ok());
+        assert!(cpu.set_pc(0xFFF).is_err());
+        assert_eq!(cpu.pc(), 0xFFE);
     }
 
     #[test]
-    fn add_vx_vy() {
+    fn step_over() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0xAA;
-        cpu.v[9] = 0x12;
-        cpu.execute_instruction((8, 2, 9, 4));
-        assert_eq!(cpu.v[2], 0xBC);
-        assert_eq!(cpu.v[0xf], 0);
-
-        cpu.v[2] = 0xFF;
-        cpu.v[9] = 0xFF;
-        cpu.execute_instruction((8, 2, 9, 4));
-        assert_eq!(cpu.v[2], 0xFE);
-        assert_eq!(cpu.v[0xf], 1);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x22, 0x08, // CALL 0x208
+            0x61, 0x02, // LD V1, 2
+            0x12, 0x04, // JP 0x204
+            0x00, 0x00, //
+            0x60, 0x07, // LD V0, 7
+            0x22, 0x0E, // CALL 0x20E
+            0x00, 0xEE, // RET
+            0x62, 0x05, // LD V2, 5
+            0x00, 0xEE, // RET
+        ]);
+        assert!(!cpu.step_over(100).unwrap());
+        assert_eq!(cpu.pc(), 0x202);
+        assert!(cpu.stack().is_empty());
+        assert_eq!(cpu.v[..3], [7, 0, 5]);
+
+        // Anything else is a single step
+        assert!(!cpu.step_over(100).unwrap());
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(cpu.v[1], 2);
+
+        // A breakpoint in the subroutine stops it there
+        cpu.pc = 0x200;
+        cpu.add_breakpoint(0x20E);
+        assert!(cpu.step_over(100).unwrap());
+        assert_eq!(cpu.pc(), 0x20E);
+        assert_eq!(cpu.stack(), [0x202, 0x20C]);
     }
 
     #[test]
-    fn sub_vx_vy() {
+    fn step_over_delay_and_endless_loop() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0x0F;
-        cpu.v[9] = 0xFF;
-        cpu.execute_instruction((8, 2, 9, 5));
-        assert_eq!(cpu.v[2], 0x10);
-        assert_eq!(cpu.v[0xf], 0);
-
-        cpu.v[2] = 0xFF;
-        cpu.v[9] = 0x0F;
-        cpu.execute_instruction((8, 2, 9, 5));
-        assert_eq!(cpu.v[2], 0xF0);
-        assert_eq!(cpu.v[0xf], 1);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[
+            0x22, 0x06, // CALL 0x206
+            0x22, 0x12, // CALL 0x212
+            0x00, 0x00, //
+            0x60, 0x05, // LD V0, 5
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x0A, // JP 0x20A
+            0x00, 0xEE, // RET
+            0x70, 0x01, // ADD V0, 1
+            0x12, 0x12, // JP 0x212
+        ]);
+        // The delay timer runs out while stepping over the subroutine
+        assert!(!cpu.step_over(10_000).unwrap());
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(cpu.delay_timer(), 0);
+
+        // Never returns, so gives up after the steps it was allowed
+        let cycles = cpu.cycles();
+        assert!(!cpu.step_over(1000).unwrap());
+        assert_eq!(cpu.stack(), [0x204]);
+        assert_eq!(cpu.cycles() - cycles, 1000);
     }
 
     #[test]
-    fn shr_vx_vy() {
+    fn status_line() {
+        assert_eq!(
+            super::format_status_line(0x2A4, (0xD, 1, 2, 5), 1234),
+            "2A4 D125 DRW V1, V2, 5    1234 cycles"
+        );
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0b0001_0001;
-        cpu.execute_instruction((8, 2, 9, 6));
-        assert_eq!(cpu.v[2], 0b0000_1000);
-        assert_eq!(cpu.v[0xf], 1);
-
-        cpu.v[2] = 0b0001_0000;
-        cpu.execute_instruction((8, 2, 9, 6));
-        assert_eq!(cpu.v[2], 0b0000_1000);
-        assert_eq!(cpu.v[0xf], 0);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x60, 0x01, 0x12, 0x02]);
+        cpu.step().unwrap();
+        assert!(cpu.status_line().starts_with("202 1202 JP "));
+        assert!(cpu.status_line().ends_with(" 1 cycles"));
+        assert!(cpu.status_line().len() <= 64);
     }
 
     #[test]
-    fn subn_vx_vy() {
+    fn power_on_display() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[9] = 0x0F;
-        cpu.v[2] = 0xFF;
-        cpu.execute_instruction((8, 2, 9, 7));
-        assert_eq!(cpu.v[2], 0x10);
-        assert_eq!(cpu.v[0xf], 0);
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(cpu.terminal().framebuffer(), [0; 32]);
+        assert_eq!(cpu.terminal().second_plane(), [0; 32]);
+
+        cpu.load(&[0xA0, 0x00, 0xD0, 0x05]); // LD I, 0; DRW V0, V0, 5
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_ne!(cpu.terminal().framebuffer(), [0; 32]);
+        cpu.reset();
+        assert_eq!(cpu.terminal().framebuffer(), [0; 32]);
+        assert_eq!((cpu.pc(), cpu.i()), (0x200, 0));
+
+        // The ROM is still loaded, so it runs the same way again
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.terminal().framebuffer()[0], 0xF0 << 56);
+
+        // Back to the ROM's display size and first plane, whatever they were left at
+        cpu.terminal.set_display_height(super::MAX_DISPLAY_HEIGHT);
+        cpu.terminal.select_planes(3);
+        cpu.reset();
+        assert_eq!(cpu.terminal().dimensions(), (64, 32));
+        assert_eq!(cpu.terminal().selected_planes(), 1);
+
+        cpu.load(&super::HIRES_PREFIX);
+        cpu.terminal.set_display_height(super::DISPLAY_HEIGHT);
+        cpu.reset();
+        assert_eq!(cpu.terminal().dimensions(), (64, 64));
+        assert_eq!(cpu.pc(), super::HIRES_START);
+    }
 
-        cpu.v[9] = 0xFF;
-        cpu.v[2] = 0x0F;
-        cpu.execute_instruction((8, 2, 9, 7));
-        assert_eq!(cpu.v[2], 0xF0);
-        assert_eq!(cpu.v[0xf], 1);
+    #[test]
+    fn preload() {
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.preload(0x800, &[0x12, 0x34, 0x56]).unwrap();
+        cpu.i = 0x800;
+        // LD V2, [I]
+        cpu.execute_instruction((0xF, 2, 6, 5)).unwrap();
+        assert_eq!(cpu.v[..3], [0x12, 0x34, 0x56]);
+
+        assert!(cpu.preload(0xFFE, &[1, 2]).is_ok());
+        assert!(cpu.preload(0xFFE, &[1, 2, 3]).is_err());
+        assert_eq!(cpu.memory[0xFFE..], [1, 2]);
     }
 
     #[test]
-    fn shl_vx_vy() {
+    fn mapped_display() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[2] = 0b0001_0001;
-        cpu.execute_instruction((8, 2, 9, 0xE));
-        assert_eq!(cpu.v[2], 0b0010_0010);
-        assert_eq!(cpu.v[0xf], 0);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.terminal.draw_sprite(8, 1, &[0xA5]);
+        cpu.set_mapped_display(Some(0xF00));
+        // Row 1 starts 8 bytes in; its second byte holds pixels 8 to 15
+        cpu.i = 0xF08;
+        cpu.execute_instruction((0xF, 2, 6, 5)).unwrap();
+        assert_eq!(cpu.v[..3], [0, 0xA5, 0]);
+
+        cpu.v[0] = 0xFF;
+        cpu.i = 0xFF8;
+        cpu.execute_instruction((0xF, 0, 5, 5)).unwrap();
+        assert_eq!(cpu.terminal.rows()[31], 0xFF << 56);
+        assert_eq!(cpu.memory[0xFF8], 0);
+
+        // Outside the window memory is as usual
+        cpu.memory[0xEFF] = 0x42;
+        cpu.i = 0xEFF;
+        cpu.execute_instruction((0xF, 0, 6, 5)).unwrap();
+        assert_eq!(cpu.v[0], 0x42);
+    }
 
-        cpu.v[2] = 0b1001_0001;
-        cpu.execute_instruction((8, 2, 9, 0xE));
-        assert_eq!(cpu.v[2], 0b0010_0010);
-        assert_eq!(cpu.v[0xf], 1);
+    #[test]
+    fn seed_source() {
+        use super::SeedSource;
+
+        let pong = [0x6A, 0x02, 0xC0, 0xFF];
+        let other = [0x6A, 0x03, 0xC0, 0xFF];
+        let seed = SeedSource::RomHash.seed(&pong);
+        assert!(seed.is_some());
+        assert_eq!(SeedSource::RomHash.seed(&[0x6A, 0x02, 0xC0, 0xFF]), seed);
+        assert_ne!(SeedSource::RomHash.seed(&other), seed);
+        assert_eq!(SeedSource::Fixed(7).seed(&pong), Some(7));
+        assert_eq!(SeedSource::Entropy.seed(&pong), None);
+
+        // The same seed gives the same random numbers
+        let rolls = |seed| {
+            let r: &[u8] = b"";
+            let mut cpu = super::CPU::new(r, sink());
+            cpu.set_seed(seed);
+            (0..8)
+                .map(|_| {
+                    cpu.execute_instruction((0xC, 0, 0xF, 0xF)).unwrap();
+                    cpu.v[0]
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rolls(seed.unwrap()), rolls(seed.unwrap()));
+        assert_ne!(rolls(seed.unwrap()), rolls(seed.unwrap() + 1));
     }
 
     #[test]
-    fn sne_vx_vy() {
+    fn hires_rom() {
+        let mut rom = vec![0x12, 0x60];
+        rom.resize(0x60, 0);
+        // LD V0, 40; DRW V0, V0, 1; CLS (HIRES)
+        rom.extend_from_slice(&[0x60, 40, 0xD0, 0x01, 0x02, 0x30]);
+        assert!(super::is_hires_rom(&rom));
+        assert!(!super::is_hires_rom(&[0x12, 0x40]));
+
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&rom);
+        assert_eq!(cpu.terminal.dimensions(), (64, 64));
+        assert_eq!(cpu.pc, 0x260);
+        cpu.i = 0;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.terminal.rows()[40], 0xF0 << 16);
+        cpu.step().unwrap();
+        assert!(cpu.terminal.rows().iter().all(|&row| row == 0));
+
+        let r: &[u8] = b"";
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_hires_detection(false);
+        cpu.load(&rom);
+        assert_eq!(cpu.terminal.dimensions(), (64, 32));
         assert_eq!(cpu.pc, 0x200);
-        cpu.v[1] = 0xEF;
-        cpu.v[2] = 0xAA;
-        cpu.v[10] = 0xAA;
-        cpu.execute_instruction((9, 2, 10, 0));
-        assert_eq!(cpu.pc, 0x202);
-        cpu.execute_instruction((9, 1, 2, 0));
-        assert_eq!(cpu.pc, 0x206);
     }
 
     #[test]
-    fn ld_i_addr() {
+    fn apply_patch() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.execute_instruction((0xA, 0xA, 0xB, 0xC));
-        assert_eq!(cpu.i, 0xABC);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x60, 0x01, 0x61, 0x02]);
+        let patch = super::Patch::parse_text("201: 05\n202: 0x12\n203: 02").unwrap();
+        cpu.apply_patch(&patch);
+        assert_eq!(cpu.memory[0x200..0x204], [0x60, 0x05, 0x12, 0x02]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.v[0], 5);
+        assert!(cpu.halted);
     }
 
     #[test]
-    fn jp_v0_addr() {
+    fn past_rom_end() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[0] = 0x11;
-        cpu.execute_instruction((0xB, 0xA, 0xB, 0xC));
-        assert_eq!(cpu.pc, 0xACD);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_diagnostics(super::Diagnostics {
+            past_rom_end: true,
+            ..Default::default()
+        });
+        cpu.load(&[0x60, 0x01, 0x61, 0x02]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.last_warning(), None);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.last_warning(),
+            Some(super::Warning::PastRomEnd {
+                pc: 0x202,
+                target: 0x204
+            })
+        );
     }
 
     #[test]
-    fn ld_vx_dt() {
+    fn below_program() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.dt = 0x11;
-        cpu.execute_instruction((0xF, 4, 0, 7));
-        assert_eq!(cpu.v[4], 0x11);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_diagnostics(super::Diagnostics {
+            below_program: true,
+            ..Default::default()
+        });
+        cpu.load(&[0x22, 0x04, 0x00, 0x00, 0x21, 0x00]); // CALL 0x204; CALL 0x100
+        cpu.step().unwrap();
+        assert_eq!(cpu.last_warning(), None);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(
+            cpu.last_warning(),
+            Some(super::Warning::BelowProgram {
+                pc: 0x204,
+                target: 0x100
+            })
+        );
     }
 
     #[test]
-    fn ld_dt_vx() {
+    fn zero_opcode() {
+        use super::ZeroOpcode;
+
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[4] = 0x11;
-        cpu.execute_instruction((0xF, 4, 1, 5));
-        assert_eq!(cpu.dt, 0x11);
+        let mut cpu = super::CPU::new(r, sink());
+        // Runs off its end into zeroed memory
+        cpu.load(&[0x60, 0x01]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.halted), (0x204, false));
+
+        cpu.reset();
+        cpu.set_zero_opcode(ZeroOpcode::Halt);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.halted), (0x202, true));
+        assert!(!cpu.run_frame().unwrap());
+
+        cpu.reset();
+        cpu.set_zero_opcode(ZeroOpcode::Error);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(super::ExecutionError::UnknownOpcode {
+                pc: 0x202,
+                opcode: 0
+            })
+        );
+        assert_eq!((cpu.pc, cpu.halted), (0x202, false));
     }
 
     #[test]
-    fn ld_st_vx() {
+    fn protect_reserved() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[4] = 0x11;
-        cpu.execute_instruction((0xF, 4, 1, 8));
-        assert_eq!(cpu.st, 0x11);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.load(&[0x10, 0x10]); // JP 0x010
+        cpu.step().unwrap();
+        // Half way into the 3 glyph, whose bytes 10 F0 decode as JP 0x0F0
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0F0);
+
+        cpu.reset();
+        cpu.set_protect_reserved(true);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(super::ExecutionError::ReservedFetch { pc: 0x010 })
+        );
+        assert_eq!(cpu.pc, 0x010);
+        assert_eq!(
+            super::ExecutionError::ReservedFetch { pc: 0x010 }.to_string(),
+            "fetching at 010, below the program at 200"
+        );
     }
 
     #[test]
-    fn add_i_vx() {
+    fn run_off_end_of_memory() {
+        use super::ExecutionError::FetchOutOfMemory;
+
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[4] = 0x11;
-        cpu.i = 0xAA;
-        cpu.execute_instruction((0xF, 4, 1, 0xE));
-        assert_eq!(cpu.i, 0xBB);
+        let mut cpu = super::CPU::new(r, sink());
+        // Then zeroes, each a NOP, all the way to the end of memory
+        cpu.load(&[0x60, 0x01]); // LD V0, 1
+        let result = (0..super::MEMORY).map(|_| cpu.step()).find(Result::is_err);
+        assert_eq!(result, Some(Err(FetchOutOfMemory { pc: 0x1000 })));
+        assert_eq!(cpu.pc, 0x1000);
+        assert!(cpu.trace_line().starts_with("1000"));
+        assert!(cpu.status_line().contains("1000"));
+
+        cpu.reload(&[0x1F, 0xFF]); // JP 0xFFF
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(FetchOutOfMemory { pc: 0xFFF }));
+        assert_eq!(
+            FetchOutOfMemory { pc: 0xFFF }.to_string(),
+            "fetching at FFF, past the end of memory"
+        );
     }
 
     #[test]
-    fn ld_f_vx() {
+    fn odd_pc() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[4] = 0xA;
-        cpu.execute_instruction((0xF, 4, 2, 9));
-        assert_eq!(cpu.memory[cpu.i as usize], 0xF0);
-        assert_eq!(cpu.memory[cpu.i as usize + 1], 0x90);
-        assert_eq!(cpu.memory[cpu.i as usize + 2], 0xF0);
-        assert_eq!(cpu.memory[cpu.i as usize + 3], 0x90);
-        assert_eq!(cpu.memory[cpu.i as usize + 4], 0x90);
-
-        cpu.v[4] = 0xBA;
-        cpu.execute_instruction((0xF, 4, 2, 9));
-        assert_eq!(cpu.memory[cpu.i as usize], 0xF0);
-        assert_eq!(cpu.memory[cpu.i as usize + 1], 0x90);
-        assert_eq!(cpu.memory[cpu.i as usize + 2], 0xF0);
-        assert_eq!(cpu.memory[cpu.i as usize + 3], 0x90);
-        assert_eq!(cpu.memory[cpu.i as usize + 4], 0x90);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.set_diagnostics(super::Diagnostics {
+            odd_pc: true,
+            ..Default::default()
+        });
+        cpu.load(&[0x13, 0x01]); // JP 0x301
+        cpu.memory[0x300..0x304].copy_from_slice(&[0x00, 0x61, 0x23, 0x00]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x301);
+        assert_eq!(
+            cpu.last_warning(),
+            Some(super::Warning::OddPc {
+                pc: 0x200,
+                target: 0x301
+            })
+        );
+
+        // The fetch straddles two words: 0x61 0x23 is LD V1, 0x23
+        assert_eq!(cpu.read_instruction(), (6, 1, 2, 3));
+        cpu.step().unwrap();
+        assert_eq!(cpu.v[1], 0x23);
+        assert_eq!(cpu.pc, 0x303);
     }
 
     #[test]
-    fn ld_b_vx() {
+    fn strict() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[4] = 0xFE;
-        cpu.i = 0x100;
-        cpu.execute_instruction((0xF, 4, 3, 3));
-        assert_eq!(cpu.memory[0x100], 2);
-        assert_eq!(cpu.memory[0x101], 5);
-        assert_eq!(cpu.memory[0x102], 4);
+        let mut cpu = super::CPU::new(r, sink());
+        cpu.terminal.draw_sprite(0, 0, &[0xFF]);
+        cpu.set_strict(true);
+        assert_eq!(
+            cpu.execute_instruction((0, 0, 0xC, 2)),
+            Err(super::ExecutionError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x00C2
+            })
+        );
+        assert!(cpu.execute_instruction((0, 0, 0xF, 0xB)).is_err());
+        assert!(cpu.execute_instruction((0, 0, 0xF, 0xC)).is_err());
+
+        cpu.set_strict(false);
+        cpu.execute_instruction((0, 0, 0xC, 2)).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.terminal.to_pbm()[9 + 2 * 8], 0xFF);
     }
 
     #[test]
-    fn ld_i_vx() {
+    fn unknown_opcode() {
         let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.v[0] = 0x12;
-        cpu.v[1] = 0x34;
-        cpu.v[2] = 0x56;
-        cpu.v[3] = 0x78;
-        cpu.i = 0x100;
-        cpu.execute_instruction((0xF, 3, 5, 5));
-        assert_eq!(cpu.memory[0x100], 0x12);
-        assert_eq!(cpu.memory[0x101], 0x34);
-        assert_eq!(cpu.memory[0x102], 0x56);
-        assert_eq!(cpu.memory[0x103], 0x78);
+        let mut cpu = super::CPU::new(r, sink());
+        assert_eq!(
+            cpu.execute_instruction((8, 1, 2, 8)),
+            Err(super::ExecutionError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x8128
+            })
+        );
+        assert_eq!(cpu.pc, 0x200);
+        assert!(cpu.execute_instruction((8, 1, 2, 0xD)).is_err());
+
+        cpu.set_lenient(true);
+        cpu.v[1] = 0x12;
+        cpu.execute_instruction((8, 1, 2, 8)).unwrap();
+        cpu.execute_instruction((8, 1, 2, 0xD)).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(cpu.v[1], 0x12);
     }
 
     #[test]
-    fn ld_vx_i() {
-        let r: &[u8] = b"";
-        let mut cpu = super::CPU::new(r);
-        cpu.memory[0x100] = 0x12;
-        cpu.memory[0x101] = 0x34;
-        cpu.memory[0x102] = 0x56;
-        cpu.memory[0x103] = 0x78;
-        cpu.i = 0x100;
-        cpu.execute_instruction((0xF, 3, 6, 5));
-        assert_eq!(cpu.v[0], 0x12);
-        assert_eq!(cpu.v[1], 0x34);
-        assert_eq!(cpu.v[2], 0x56);
-        assert_eq!(cpu.v[3], 0x78);
+    fn opcode() {
+        assert_eq!(super::opcode((0xD, 1, 2, 0xF)), 0xD12F);
     }
 
     #[test]
@@ -0,0 +1,303 @@
+use std::fs;
+use std::io::{Read, Write};
+
+use crate::cpu::{Trace, TraceEnd, CPU};
+use crate::disassembler::mnemonic;
+use crate::terminal::DISPLAY_HEIGHT;
+
+// Eight columns of 32 bytes fill the display
+const MEMORY_VIEW_BYTES: usize = 256;
+// How far `trace` looks before deciding the address isn't coming
+const TRACE_MAX_STEPS: usize = 1_000_000;
+
+/// A command typed at the `:` prompt while a ROM runs.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `clear X Y W H`: blanks part of the display to see what gets redrawn.
+    ClearRegion { x: u8, y: u8, w: u8, h: u8 },
+    /// `stack`: shows the return addresses of the CALLs in progress.
+    Stack,
+    /// `memview ADDR`: draws the memory from ADDR, in hex, as a bitmap.
+    MemView { addr: u16 },
+    /// `trace ADDR [FILE]`: runs until PC reaches ADDR, in hex, writing the
+    /// instructions that led there to FILE.
+    TraceTo { addr: u16, log: Option<String> },
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("clear") => {
+                let args: Vec<u8> = words
+                    .map(|word| word.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "usage: clear X Y W H".to_string())?;
+                match args[..] {
+                    [x, y, w, h] => Ok(Command::ClearRegion { x, y, w, h }),
+                    _ => Err("usage: clear X Y W H".to_string()),
+                }
+            }
+            Some("stack") => Ok(Command::Stack),
+            Some("memview") => {
+                let addr = match (words.next(), words.next()) {
+                    (Some(addr), None) => u16::from_str_radix(addr, 16).ok(),
+                    _ => None,
+                };
+                match addr {
+                    Some(addr) if addr < 0x1000 => Ok(Command::MemView { addr }),
+                    _ => Err("usage: memview ADDR, in hex below 1000".to_string()),
+                }
+            }
+            Some("trace") => {
+                let addr = words
+                    .next()
+                    .and_then(|addr| u16::from_str_radix(addr, 16).ok());
+                let log = words.next().map(str::to_string);
+                match addr {
+                    Some(addr) if addr < 0x1000 && words.next().is_none() => {
+                        Ok(Command::TraceTo { addr, log })
+                    }
+                    _ => Err("usage: trace ADDR [FILE], ADDR in hex below 1000".to_string()),
+                }
+            }
+            Some(other) => Err(format!("unknown command `{}`", other)),
+            None => Err("empty command".to_string()),
+        }
+    }
+
+    pub fn execute<R: Read, W: Write>(&self, cpu: &mut CPU<R, W>) {
+        match *self {
+            Command::TraceTo { addr, ref log } => {
+                let status = match cpu.trace_to(addr, TRACE_MAX_STEPS) {
+                    Ok(trace) => match log {
+                        Some(path) => match fs::write(path, trace_lines(&trace)) {
+                            Ok(()) => trace_status(addr, &trace),
+                            Err(e) => format!("cannot write {}: {}", path, e),
+                        },
+                        None => trace_status(addr, &trace),
+                    },
+                    Err(e) => format!("trace stopped: {}", e),
+                };
+                cpu.terminal_mut().set_status(status);
+            }
+            Command::ClearRegion { x, y, w, h } => cpu.terminal_mut().clear_region(x, y, w, h),
+            Command::Stack => {
+                let status = if cpu.stack().is_empty() {
+                    "stack: empty".to_string()
+                } else {
+                    let chain: Vec<_> = cpu.stack().iter().map(|a| format!("{:03X}", a)).collect();
+                    format!("stack: {}", chain.join(" > "))
+                };
+                cpu.terminal_mut().set_status(status);
+            }
+            Command::MemView { addr } => show_memory(cpu, addr),
+        }
+    }
+}
+
+/// A line summing up how a `trace` went.
+fn trace_status(addr: u16, trace: &Trace) -> String {
+    let steps = trace.steps.len();
+    match trace.end {
+        TraceEnd::Reached => {
+            let (from, _) = trace.steps[steps - 1];
+            format!(
+                "reached {:03X} after {} steps, from {:03X}",
+                addr, steps, from
+            )
+        }
+        TraceEnd::Halted => format!("{:03X} not reached, halted after {} steps", addr, steps),
+        TraceEnd::Quit => format!("{:03X} not reached, quit after {} steps", addr, steps),
+        TraceEnd::OutOfSteps => format!("{:03X} not reached in {} steps", addr, steps),
+    }
+}
+
+/// The instructions a `trace` ran, a line each as `PC  OPCODE  MNEMONIC`.
+pub fn trace_lines(trace: &Trace) -> String {
+    trace
+        .steps
+        .iter()
+        .map(|&(pc, opcode)| format!("{:03X}  {:04X}  {}\n", pc, opcode, mnemonic(opcode)))
+        .collect()
+}
+
+/// Replaces the display with the memory from `addr`, one byte per row of 8 pixels,
+/// filling columns of 32 bytes left to right so sprites show the way they are drawn.
+/// The view stays on until the ROM draws over it; `memory_view` keeps its address
+/// for scrolling.
+pub fn show_memory<R: Read, W: Write>(cpu: &mut CPU<R, W>, addr: u16) {
+    let start = addr as usize;
+    let end = (start + MEMORY_VIEW_BYTES).min(cpu.memory().len());
+    let rows = memory_rows(&cpu.memory()[start..end]);
+    cpu.clear_display();
+    cpu.terminal_mut().blit(0, 0, &rows);
+    cpu.terminal_mut()
+        .set_status(format!("memory {:03X}-{:03X}", start, end - 1));
+    cpu.set_memory_view(Some(addr));
+}
+
+/// Lays out up to 256 bytes as display rows: byte `i` is row `i % 32`, in the
+/// `i / 32`th column of 8 pixels.
+pub fn memory_rows(bytes: &[u8]) -> [u64; 32] {
+    let mut rows = [0; DISPLAY_HEIGHT];
+    for (i, &byte) in bytes.iter().take(MEMORY_VIEW_BYTES).enumerate() {
+        let column = (i / DISPLAY_HEIGHT) as u32;
+        rows[i % DISPLAY_HEIGHT] |= (byte as u64) << (56 - 8 * column);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+
+    use super::{memory_rows, trace_lines, trace_status, Command};
+    use crate::cpu::{TraceEnd, CPU};
+    use crate::testing::assert_display_matches;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Command::parse(" clear 8 4  8 8"),
+            Ok(Command::ClearRegion {
+                x: 8,
+                y: 4,
+                w: 8,
+                h: 8
+            })
+        );
+        assert!(Command::parse("clear 8 4 8").is_err());
+        assert!(Command::parse("clear 8 4 8 x").is_err());
+        assert_eq!(Command::parse("stack"), Ok(Command::Stack));
+        assert_eq!(
+            Command::parse("memview 2A0"),
+            Ok(Command::MemView { addr: 0x2A0 })
+        );
+        assert!(Command::parse("memview").is_err());
+        assert!(Command::parse("memview 1000").is_err());
+        assert!(Command::parse("memview 200 300").is_err());
+        assert_eq!(
+            Command::parse("trace 2a0"),
+            Ok(Command::TraceTo {
+                addr: 0x2A0,
+                log: None
+            })
+        );
+        assert_eq!(
+            Command::parse("trace 2A0 trace.txt"),
+            Ok(Command::TraceTo {
+                addr: 0x2A0,
+                log: Some("trace.txt".to_string())
+            })
+        );
+        assert!(Command::parse("trace").is_err());
+        assert!(Command::parse("trace 1000").is_err());
+        assert!(Command::parse("trace 200 a b").is_err());
+        assert!(Command::parse("zap").is_err());
+        assert!(Command::parse("").is_err());
+    }
+
+    #[test]
+    fn trace_to() {
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x03, // LD V0, 3
+            0x22, 0x08, // CALL 0x208
+            0x12, 0x04, // JP 0x204
+            0x00, 0x00, // SYS 0
+            0x70, 0x01, // ADD V0, 1
+            0x00, 0xEE, // RET
+        ]);
+        let trace = cpu.trace_to(0x208, 100).unwrap();
+        assert_eq!(trace.end, TraceEnd::Reached);
+        assert_eq!(trace.steps, [(0x200, 0x6003), (0x202, 0x2208)]);
+        assert_eq!(cpu.pc(), 0x208);
+        assert_eq!(
+            trace_status(0x208, &trace),
+            "reached 208 after 2 steps, from 202"
+        );
+        assert_eq!(
+            trace_lines(&trace),
+            "200  6003  LD V0, 0x03\n202  2208  CALL 0x208\n"
+        );
+
+        // The subroutine returns into the halting loop, never getting to 300
+        let trace = cpu.trace_to(0x300, 100).unwrap();
+        assert_eq!(trace.end, TraceEnd::Halted);
+        assert_eq!(
+            trace_status(0x300, &trace),
+            "300 not reached, halted after 3 steps"
+        );
+    }
+
+    #[test]
+    fn trace_through_delay() {
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.load(&[
+            0x60, 0x05, // LD V0, 5
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x04, // JP 0x204
+            0x61, 0x01, // LD V1, 1
+        ]);
+        let trace = cpu.trace_to(0x20A, 1000).unwrap();
+        assert_eq!(trace.end, TraceEnd::Reached);
+        assert_eq!(cpu.delay_timer(), 0);
+    }
+
+    #[test]
+    fn trace_out_of_steps() {
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.load(&[0x70, 0x01, 0x12, 0x00]); // ADD V0, 1; JP 0x200
+        let trace = cpu.trace_to(0x300, 10).unwrap();
+        assert_eq!(trace.end, TraceEnd::OutOfSteps);
+        assert_eq!(trace.steps.len(), 10);
+        assert_eq!(trace_status(0x300, &trace), "300 not reached in 10 steps");
+    }
+
+    #[test]
+    fn memory_to_rows() {
+        let mut bytes = [0; 64];
+        bytes[0] = 0xF0;
+        bytes[1] = 0x90;
+        bytes[31] = 0x01;
+        bytes[32] = 0xAA;
+        let rows = memory_rows(&bytes);
+        assert_eq!(rows[0], 0xF0AA << 48);
+        assert_eq!(rows[1], 0x90 << 56);
+        assert_eq!(rows[31], 0x01 << 56);
+        assert!(rows[2..31].iter().all(|&row| row == 0));
+
+        // The last of 256 bytes goes in the bottom right corner, and any more are left out
+        let rows = memory_rows(&[0x81; 257]);
+        assert!(rows.iter().all(|&row| row == 0x8181_8181_8181_8181));
+    }
+
+    #[test]
+    fn memview() {
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.load(&[0x3C, 0x42, 0x42, 0x3C]);
+        cpu.terminal_mut().draw_sprite(40, 20, &[0xFF]);
+        Command::MemView { addr: 0x200 }.execute(&mut cpu);
+        assert_display_matches(
+            cpu.terminal(),
+            "
+            ..####..
+            .#....#.
+            .#....#.
+            ..####..
+            ",
+        );
+        assert_eq!(cpu.memory_view(), Some(0x200));
+
+        // The last bytes of memory don't fill the display
+        Command::MemView { addr: 0xFFF }.execute(&mut cpu);
+        assert_display_matches(cpu.terminal(), "");
+    }
+}
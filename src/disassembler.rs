@@ -0,0 +1,73 @@
+/// The assembly for one opcode, in the mnemonics of Cowgod's technical reference.
+/// Words that aren't instructions, usually sprite data, come out as `DW`.
+pub fn mnemonic(opcode: u16) -> String {
+    let x = (opcode >> 8) & 0xF;
+    let y = (opcode >> 4) & 0xF;
+    let n = opcode & 0xF;
+    let kk = opcode & 0xFF;
+    let nnn = opcode & 0xFFF;
+    match (opcode >> 12, x, y, n) {
+        (0, 0, 0xE, 0) => "CLS".to_string(),
+        (0, 0, 0xE, 0xE) => "RET".to_string(),
+        (0, 0, 0xC, n) => format!("SCD {}", n),
+        (0, 0, 0xF, 0xB) => "SCR".to_string(),
+        (0, 0, 0xF, 0xC) => "SCL".to_string(),
+        (0, _, _, _) => format!("SYS 0x{:03X}", nnn),
+        (1, _, _, _) => format!("JP 0x{:03X}", nnn),
+        (2, _, _, _) => format!("CALL 0x{:03X}", nnn),
+        (3, _, _, _) => format!("SE V{:X}, 0x{:02X}", x, kk),
+        (4, _, _, _) => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        (5, _, _, 0) => format!("SE V{:X}, V{:X}", x, y),
+        (6, _, _, _) => format!("LD V{:X}, 0x{:02X}", x, kk),
+        (7, _, _, _) => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        (8, _, _, 0) => format!("LD V{:X}, V{:X}", x, y),
+        (8, _, _, 1) => format!("OR V{:X}, V{:X}", x, y),
+        (8, _, _, 2) => format!("AND V{:X}, V{:X}", x, y),
+        (8, _, _, 3) => format!("XOR V{:X}, V{:X}", x, y),
+        (8, _, _, 4) => format!("ADD V{:X}, V{:X}", x, y),
+        (8, _, _, 5) => format!("SUB V{:X}, V{:X}", x, y),
+        (8, _, _, 6) => format!("SHR V{:X}, V{:X}", x, y),
+        (8, _, _, 7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (9, _, _, 0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("LD I, 0x{:03X}", nnn),
+        (0xB, _, _, _) => format!("JP V0, 0x{:03X}", nnn),
+        (0xC, _, _, _) => format!("RND V{:X}, 0x{:02X}", x, kk),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 1) => format!("SKNP V{:X}", x),
+        (0xF, _, 0, 1) => format!("PLANE {}", x),
+        (0xF, _, 0, 7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 1, 5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 1, 8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 2, 9) => format!("LD F, V{:X}", x),
+        (0xF, _, 3, 3) => format!("LD B, V{:X}", x),
+        (0xF, _, 5, 5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 6, 5) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mnemonic;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(mnemonic(0x00E0), "CLS");
+        assert_eq!(mnemonic(0x00C4), "SCD 4");
+        assert_eq!(mnemonic(0x0123), "SYS 0x123");
+        assert_eq!(mnemonic(0x120A), "JP 0x20A");
+        assert_eq!(mnemonic(0x3A0F), "SE VA, 0x0F");
+        assert_eq!(mnemonic(0x812E), "SHL V1, V2");
+        assert_eq!(mnemonic(0xB300), "JP V0, 0x300");
+        assert_eq!(mnemonic(0xD12F), "DRW V1, V2, 15");
+        assert_eq!(mnemonic(0xE4A1), "SKNP V4");
+        assert_eq!(mnemonic(0xF565), "LD V5, [I]");
+        assert_eq!(mnemonic(0xF201), "PLANE 2");
+        assert_eq!(mnemonic(0x5121), "DW 0x5121");
+        assert_eq!(mnemonic(0xFFFF), "DW 0xFFFF");
+    }
+}
@@ -0,0 +1,102 @@
+//! A window backend built on minifb, enabled by the `gui` feature.
+
+use minifb::{Key, KeyRepeat, WindowOptions};
+
+use crate::backend::{Display, KeyEvent, Keypad};
+use crate::terminal::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+const LIT: u32 = 0x00FF_FFFF;
+const UNLIT: u32 = 0;
+
+pub struct Window {
+    window: minifb::Window,
+    scale: usize,
+    buffer: Vec<u32>,
+}
+
+impl Window {
+    /// Opens a window showing each CHIP-8 pixel as a `scale` by `scale` square.
+    pub fn new(title: &str, scale: usize) -> Result<Self, String> {
+        if scale == 0 {
+            return Err("the scale must be at least 1".to_string());
+        }
+        let (width, height) = (DISPLAY_WIDTH * scale, DISPLAY_HEIGHT * scale);
+        let window = minifb::Window::new(title, width, height, WindowOptions::default())
+            .map_err(|e| e.to_string())?;
+        Ok(Window {
+            window,
+            scale,
+            buffer: vec![UNLIT; width * height],
+        })
+    }
+}
+
+impl Display for Window {
+    /// Shows the frame stretched over the window, so a HIRES display's 64 rows fit in
+    /// a window opened for 32.
+    fn present(&mut self, rows: &[u64]) {
+        let (width, height) = (DISPLAY_WIDTH * self.scale, rows.len() * self.scale);
+        self.buffer.resize(width * height, UNLIT);
+        for (y, pixels) in self.buffer.chunks_mut(width).enumerate() {
+            let line = rows[y / self.scale];
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                let lit = line & (1 << (DISPLAY_WIDTH - 1 - x / self.scale)) != 0;
+                *pixel = if lit { LIT } else { UNLIT };
+            }
+        }
+        self.window
+            .update_with_buffer(&self.buffer, width, height)
+            .unwrap();
+    }
+}
+
+impl Keypad for Window {
+    fn poll_keys(&mut self) -> Vec<KeyEvent> {
+        let pressed = self.window.get_keys_pressed(KeyRepeat::No);
+        let released = self.window.get_keys_released();
+        pressed
+            .into_iter()
+            .filter_map(|key| map_key(key).map(KeyEvent::Down))
+            .chain(
+                released
+                    .into_iter()
+                    .filter_map(|key| map_key(key).map(KeyEvent::Up)),
+            )
+            .collect()
+    }
+
+    fn quit_requested(&self) -> bool {
+        !self.window.is_open() || self.window.is_key_down(Key::Escape)
+    }
+}
+
+/// The usual layout for physical keyboards: the left 4x4 block of keys stands in for
+/// the COSMAC VIP keypad.
+///
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// Q W E R  ->  4 5 6 D
+/// A S D F      7 8 9 E
+/// Z X C V      A 0 B F
+/// ```
+fn map_key(key: Key) -> Option<u8> {
+    match key {
+        Key::Key1 => Some(0x1),
+        Key::Key2 => Some(0x2),
+        Key::Key3 => Some(0x3),
+        Key::Key4 => Some(0xC),
+        Key::Q => Some(0x4),
+        Key::W => Some(0x5),
+        Key::E => Some(0x6),
+        Key::R => Some(0xD),
+        Key::A => Some(0x7),
+        Key::S => Some(0x8),
+        Key::D => Some(0x9),
+        Key::F => Some(0xE),
+        Key::Z => Some(0xA),
+        Key::X => Some(0x0),
+        Key::C => Some(0xB),
+        Key::V => Some(0xF),
+        _ => None,
+    }
+}
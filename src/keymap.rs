@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+/// Which keyboard character presses which keypad key.
+pub type Keymap = HashMap<char, u8>;
+
+/// Keys 0-9 and a-f press the keypad key with the same hex digit.
+pub fn default_keymap() -> Keymap {
+    (0..16u8)
+        .map(|key| (std::char::from_digit(key as u32, 16).unwrap(), key))
+        .collect()
+}
+
+/// Parses `CHAR = KEY` lines, KEY being a hex digit. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn parse(text: &str) -> Result<Keymap, String> {
+    let mut keymap = Keymap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = || format!("line {}: expected `CHAR = KEY`, got `{}`", n + 1, line);
+        let (c, key) = line.split_once('=').ok_or_else(error)?;
+        let mut chars = c.trim().chars();
+        let c = match (chars.next(), chars.next()) {
+            (Some(c), None) => c,
+            _ => return Err(error()),
+        };
+        let key = u8::from_str_radix(key.trim(), 16)
+            .ok()
+            .filter(|&key| key < 16)
+            .ok_or_else(error)?;
+        keymap.insert(c, key);
+    }
+    Ok(keymap)
+}
+
+/// The keypad keys no character presses, in ascending order.
+pub fn unmapped_keys(keymap: &Keymap) -> Vec<u8> {
+    (0..16)
+        .filter(|key| !keymap.values().any(|k| k == key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_keymap, parse, unmapped_keys};
+
+    #[test]
+    fn parse_keymap() {
+        let keymap = parse(
+            "# Left hand block\n\
+             1 = 1\n\
+             q = 4\n\
+             \n\
+             v = F\n",
+        )
+        .unwrap();
+        assert_eq!(keymap.len(), 3);
+        assert_eq!(keymap[&'1'], 0x1);
+        assert_eq!(keymap[&'q'], 0x4);
+        assert_eq!(keymap[&'v'], 0xF);
+        assert_eq!(unmapped_keys(&keymap).len(), 13);
+        assert!(!unmapped_keys(&keymap).contains(&4));
+
+        assert!(parse("q 4").is_err());
+        assert!(parse("qq = 4").is_err());
+        assert!(parse("q = 10").is_err());
+    }
+
+    #[test]
+    fn default() {
+        let keymap = default_keymap();
+        assert_eq!(keymap[&'0'], 0);
+        assert_eq!(keymap[&'a'], 0xA);
+        assert!(unmapped_keys(&keymap).is_empty());
+    }
+}
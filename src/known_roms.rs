@@ -0,0 +1,84 @@
+//! ROMs recognized by a checksum of their contents, with the quirks each needs, so
+//! they run correctly without `--platform`.
+
+use crate::quirks::Quirks;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01B3;
+
+pub struct KnownRom {
+    pub title: &'static str,
+    /// `checksum` of the whole ROM file.
+    pub checksum: u64,
+    pub quirks: fn() -> Quirks,
+}
+
+// Add a game with the checksum of a known good dump, as printed by `checksum`
+const KNOWN_ROMS: &[KnownRom] = &[KnownRom {
+    title: "Maze [David Winter]",
+    checksum: 0x25E9_6E10_86CE_43CB,
+    quirks: Quirks::cosmac,
+}];
+
+/// A 64-bit FNV-1a hash, used for ROM checksums and framebuffer hashes.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A 64-bit FNV-1a hash of the ROM, which identifies a dump exactly: a single byte
+/// patched or a padded file gives a different checksum.
+pub fn checksum(rom: &[u8]) -> u64 {
+    fnv1a(rom.iter().copied())
+}
+
+pub fn lookup(rom: &[u8]) -> Option<&'static KnownRom> {
+    let checksum = checksum(rom);
+    KNOWN_ROMS.iter().find(|known| known.checksum == checksum)
+}
+
+/// The quirks a recognized ROM needs, or `fallback` for any other.
+pub fn quirks_for(rom: &[u8], fallback: Quirks) -> Quirks {
+    lookup(rom).map_or(fallback, |known| (known.quirks)())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_is_fnv1a() {
+        assert_eq!(checksum(b""), FNV_OFFSET_BASIS);
+        assert_eq!(checksum(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+
+    #[test]
+    fn known_rom_gets_its_quirks() {
+        let maze = [
+            0xA2, 0x1E, // LD I, 0x21E
+            0xC2, 0x01, // RND V2, 0x01
+            0x32, 0x01, // SE V2, 0x01
+            0xA2, 0x1A, // LD I, 0x21A
+            0xD0, 0x14, // DRW V0, V1, 4
+            0x70, 0x04, // ADD V0, 4
+            0x30, 0x40, // SE V0, 64
+            0x12, 0x00, // JP 0x200
+            0x60, 0x00, // LD V0, 0
+            0x71, 0x04, // ADD V1, 4
+            0x31, 0x20, // SE V1, 32
+            0x12, 0x00, // JP 0x200
+            0x12, 0x18, // JP 0x218
+            0x80, 0x40, 0x20, 0x10, // `\` sprite
+            0x20, 0x40, 0x80, 0x10, // `/` sprite
+        ];
+        let known = lookup(&maze).unwrap();
+        assert_eq!(known.title, "Maze [David Winter]");
+        assert_eq!(quirks_for(&maze, Quirks::default()), Quirks::cosmac());
+
+        let mut patched = maze.to_vec();
+        patched[0] ^= 1;
+        assert!(lookup(&patched).is_none());
+        assert_eq!(quirks_for(&patched, Quirks::default()), Quirks::default());
+    }
+}
@@ -0,0 +1,24 @@
+#[cfg(feature = "zip")]
+pub mod archive;
+pub mod backend;
+pub mod clock;
+pub mod compat;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod keymap;
+pub mod known_roms;
+pub mod patch;
+pub mod quirks;
+pub mod rom;
+pub mod script;
+pub mod speed;
+pub mod splash;
+pub mod suite;
+pub mod terminal;
+pub mod testing;
+pub mod trace;
+pub mod watch;
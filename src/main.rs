@@ -1,32 +1,552 @@
-use std::fs::File;
-use std::io::Read;
-use std::time::{Duration, SystemTime};
-use std::{env, thread};
+use std::fs::{self, File};
+use std::io::{self, stdout, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::{env, process};
 
+use log::{LevelFilter, Log, Metadata, Record};
 use termion::async_stdin;
+use termion::raw::IntoRawMode;
 
-mod cpu;
-mod terminal;
+use chip8::clock::{FrameTimer, RateMeter, SystemClock};
+use chip8::compat;
+use chip8::config::{self, Config, USAGE};
+use chip8::cpu::{self, Diagnostics, ExecutionError, CPU};
+use chip8::debugger::{self, Command};
+use chip8::disassembler::mnemonic;
+use chip8::keymap;
+use chip8::known_roms;
+use chip8::patch::Patch;
+use chip8::rom;
+use chip8::script;
+use chip8::speed::AutoSpeed;
+use chip8::splash;
+use chip8::suite;
+use chip8::terminal::Control;
+use chip8::trace;
+use chip8::watch::RomWatcher;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        eprint!("{}: {}\r\n", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+// How far Ctrl+O runs a subroutine before giving up on it returning
+const STEP_OVER_MAX_STEPS: u64 = 1_000_000;
 
 fn main() {
-    let mut cpu = cpu::CPU::new(async_stdin());
-
-    let args: Vec<String> = env::args().collect();
-    let file = &args[1];
-    let mut buf = [0; 3584];
-    let mut rom = File::open(file).unwrap();
-    let _ = rom.read(&mut buf).unwrap();
-    cpu.load(&buf);
-    let mut time = SystemTime::now();
-    let mut update_timers = false;
-
-    while cpu.tick(update_timers) {
-        update_timers = false;
-        thread::sleep(Duration::from_micros(200));
-        let new_time = SystemTime::now();
-        if new_time.duration_since(time).unwrap().as_micros() > 16667 {
-            time = new_time;
-            update_timers = true;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::parse_args(&args) {
+        Ok(config) if config.help => {
+            println!("{}", USAGE);
+            return;
+        }
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    let rom_config = format!("{}.toml", config.rom);
+    let config = match fs::read_to_string(&rom_config) {
+        Ok(text) if !config.rom.is_empty() => config::parse_rom_config(&text)
+            .and_then(|base| config::parse_args_with(base, &args))
+            .unwrap_or_else(|message| {
+                eprintln!("error: {}: {}", rom_config, message);
+                process::exit(2);
+            }),
+        _ => config,
+    };
+
+    if config.warn {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Warn);
+    }
+
+    if config.dump_font {
+        let input: &[u8] = &[];
+        let mut cpu = cpu::CPU::new(input, io::sink());
+        cpu.draw_font();
+        for line in cpu.terminal().to_ascii().lines().take(11) {
+            println!("{}", line.trim_end());
+        }
+        return;
+    }
+
+    if config.test_suite {
+        print!("{}", suite::report());
+        return;
+    }
+
+    if let Some(dir) = &config.compat_report {
+        let max_cycles = config.max_cycles.unwrap_or(compat::DEFAULT_MAX_CYCLES);
+        match compat::report(
+            Path::new(dir),
+            max_cycles,
+            config.stable_frames,
+            config.quirks,
+        ) {
+            Ok(report) => print!("{}", report),
+            Err(e) => {
+                eprintln!("error: cannot read {}: {}", dir, e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(path) = &config.script {
+        run_script(&config, path);
+        return;
+    }
+
+    let rom = open_rom(&config.rom);
+    let buf = &rom[..];
+    let overlays = read_overlays(&config);
+    let keymap = config.keymap_file.as_ref().map(|path| {
+        let keymap = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| keymap::parse(&text))
+            .unwrap_or_else(|e| {
+                eprintln!("error: cannot read keymap {}: {}", path, e);
+                process::exit(1);
+            });
+        let unmapped = keymap::unmapped_keys(&keymap);
+        if !unmapped.is_empty() {
+            let keys: Vec<_> = unmapped.iter().map(|key| format!("{:X}", key)).collect();
+            eprintln!("warning: {} maps nothing to keys {}", path, keys.join(", "));
+        }
+        keymap
+    });
+
+    // Frames run at --fps-cap a second
+    let frame = Duration::from_secs(1) / config.fps_cap;
+    let create_log = |path: &String| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("error: cannot create {}: {}", path, e);
+            process::exit(1);
+        });
+        Box::new(BufWriter::new(file)) as Box<dyn Write>
+    };
+    let instruction_log = config.instruction_log.as_ref().map(create_log);
+    let draw_log = config.draw_log.as_ref().map(create_log);
+
+    if let Some(path) = &config.compare_trace {
+        let reference = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("error: cannot read {}: {}", path, e);
+            process::exit(1);
+        });
+        let input: &[u8] = &[];
+        let mut cpu = cpu::CPU::new(input, io::sink());
+        configure(&mut cpu, &config, 60);
+        load_rom(&mut cpu, &config, buf, &overlays);
+        match trace::compare(&mut cpu, &reference, config.compare_registers) {
+            Ok(steps) => println!("matches the reference for all {} steps", steps),
+            Err(divergence) => {
+                println!("{}", divergence);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let watcher = config.watch.then(|| watch_rom(&config.rom));
+    let mut cpu = cpu::CPU::new(async_stdin(), stdout().into_raw_mode().unwrap());
+    configure(&mut cpu, &config, config.fps_cap);
+    cpu.set_frame_time_budget(Some(frame));
+    // Render schedules count renders out of 60 frames, whatever the frame rate
+    cpu.set_render_fps(config.render_fps * 60 / config.fps_cap);
+    cpu.set_rewind_depth(config.rewind);
+    cpu.set_instruction_log(instruction_log);
+    cpu.set_draw_log(draw_log);
+    cpu.set_stack_profile(config.stack_profile);
+    cpu.set_diagnostics(Diagnostics {
+        uninitialized_i: config.warn,
+        odd_pc: config.warn,
+        past_rom_end: config.warn,
+        below_program: config.warn,
+    });
+    cpu.terminal_mut().set_anti_flicker(config.anti_flicker);
+    cpu.terminal_mut()
+        .set_double_buffering(config.double_buffer);
+    cpu.terminal_mut().set_keypad_panel(config.tui);
+    cpu.terminal_mut().set_quit_key(config.quit_key);
+    cpu.terminal_mut().set_cancel_key(config.cancel_key);
+    if let Some(keymap) = keymap {
+        cpu.terminal_mut().set_keymap(keymap);
+    }
+    cpu.terminal_mut().set_brightness_ramp(config.ramp.clone());
+    cpu.terminal_mut().set_plane_palette(config.palette);
+    if config.on_char.is_some() || config.off_char.is_some() {
+        let off = config.off_char.unwrap_or(config.ramp[0]);
+        let on = config.on_char.unwrap_or(config.ramp[config.ramp.len() - 1]);
+        cpu.terminal_mut().set_pixel_chars(off, on);
+    }
+    load_rom(&mut cpu, &config, buf, &overlays);
+
+    if config.splash {
+        show_splash(&mut cpu, &config.rom);
+    }
+    let reason = if cpu.terminal().exit {
+        StopReason::Quit
+    } else {
+        run(&mut cpu, &config, &overlays, watcher.as_ref())
+    };
+
+    // Reported once out of raw mode
+    let mut write_errors = Vec::new();
+    if let Some(path) = &config.screenshot_on_exit {
+        if let Err(e) = cpu.terminal().save_screenshot(Path::new(path)) {
+            write_errors.push(format!("cannot write {}: {}", path, e));
         }
     }
+    if let Some(path) = &config.dump_state {
+        if let Err(e) = fs::write(path, cpu.state_json() + "\n") {
+            write_errors.push(format!("cannot write {}: {}", path, e));
+        }
+    }
+    let recent: Vec<_> = cpu.recent_instructions().collect();
+    let dump = cpu.debug_dump();
+    let stack_profile = cpu.stack_profile().cloned();
+    // Leave raw mode before reporting
+    drop(cpu);
+    if let Some(profile) = stack_profile {
+        eprint!("{}", profile);
+    }
+    match &reason {
+        StopReason::Quit => (),
+        StopReason::Halted => eprintln!("stopped: the ROM halted"),
+        StopReason::MaxCycles => eprintln!("stopped: reached --max-cycles"),
+        StopReason::WriteFailed(message) => eprintln!("error: {}", message),
+        StopReason::Error(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("last instructions:");
+            for (pc, opcode) in recent {
+                eprintln!("  {:03X}  {:04X}  {}", pc, opcode, mnemonic(opcode));
+            }
+            eprint!("machine state:\n{}", dump);
+        }
+    }
+    for message in &write_errors {
+        eprintln!("error: {}", message);
+    }
+    if write_errors.is_empty() {
+        process::exit(reason.exit_code());
+    }
+    process::exit(1);
+}
+
+/// Shows the splash for a second, or until a key is pressed, then blanks the display
+/// for the ROM.
+fn show_splash<R: Read, W: Write>(cpu: &mut CPU<R, W>, rom: &str) {
+    splash::draw(cpu, rom);
+    cpu.present();
+    let mut timer = FrameTimer::new(Box::new(SystemClock::new()));
+    let mut frames = 0;
+    while frames < 60 && !cpu.terminal_mut().take_any_key() {
+        timer.sleep_until_next_frame();
+        frames += timer.should_tick_timers() as u32;
+    }
+    cpu.clear_display();
+    cpu.terminal_mut().clear_status();
+}
+
+/// Starts watching the ROM for `--watch`, before the terminal goes into raw mode.
+#[cfg(feature = "watch")]
+fn watch_rom(path: &str) -> RomWatcher {
+    RomWatcher::new(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot watch {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+#[cfg(not(feature = "watch"))]
+fn watch_rom(_: &str) -> RomWatcher {
+    eprintln!("error: --watch needs chip8 built with the watch feature");
+    process::exit(2);
+}
+
+/// Runs the script at `path` headlessly, on the ROM too if one was given, exiting with
+/// 1 if it fails.
+fn run_script(config: &Config, path: &str) {
+    let script = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot read {}: {}", path, e);
+        process::exit(1);
+    });
+    let input: &[u8] = &[];
+    let mut cpu = cpu::CPU::new(input, io::sink());
+    configure(&mut cpu, config, 60);
+    if !config.rom.is_empty() {
+        let rom = open_rom(&config.rom);
+        load_rom(&mut cpu, config, &rom, &read_overlays(config));
+    }
+    if let Err(e) = script::run(&mut cpu, &script) {
+        eprintln!("error: {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+/// Reads and checks the ROM at `path`, exiting with 1 if it can't be run.
+fn open_rom(path: &str) -> Vec<u8> {
+    let rom = rom::read_rom(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot open {}: {}", path, e);
+        process::exit(1);
+    });
+    if let Err(e) = rom::validate_rom(&rom) {
+        eprintln!("error: cannot run {}: {}", path, e);
+        process::exit(1);
+    }
+    rom
+}
+
+/// What `--patch` and `--preload` write over a ROM once it's loaded, read up front so
+/// a bad file is reported before the terminal goes into raw mode.
+struct Overlays {
+    patch: Option<Patch>,
+    preloads: Vec<(u16, Vec<u8>)>,
+}
+
+fn read_overlays(config: &Config) -> Overlays {
+    let patch = config.patch.as_ref().map(|path| {
+        fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| Patch::parse(&data))
+            .unwrap_or_else(|e| {
+                eprintln!("error: cannot apply {}: {}", path, e);
+                process::exit(1);
+            })
+    });
+    let preloads = config
+        .preload
+        .iter()
+        .map(|(addr, path)| {
+            let data = fs::read(path).unwrap_or_else(|e| {
+                eprintln!("error: cannot read {}: {}", path, e);
+                process::exit(1);
+            });
+            if *addr as usize + data.len() > 0x1000 {
+                eprintln!("error: {} doesn't fit in memory at {:03X}", path, addr);
+                process::exit(1);
+            }
+            (*addr, data)
+        })
+        .collect();
+    Overlays { patch, preloads }
+}
+
+/// Sets up how `cpu` runs whatever ROM it's given, as `config` says, for frames
+/// coming `fps` times a second: speed, timers, quirks and the checks on the ROM.
+fn configure<R: Read, W: Write>(cpu: &mut CPU<R, W>, config: &Config, fps: u32) {
+    // Frames run at `fps` a second, each doing 60 / fps 60Hz frames' worth of
+    // instructions and timer ticks, so the timers still take a second to count down 60
+    cpu.set_cycles_per_frame(config.speed * 60 / fps);
+    cpu.set_timer_hz(config.timer_hz * 60 / fps);
+    cpu.set_timing(config.timing);
+    cpu.set_quirks(config.quirks);
+    cpu.set_stack_depth(config.stack_depth);
+    cpu.set_lenient(config.lenient);
+    cpu.set_zero_opcode(config.zero_opcode);
+    cpu.set_strict(config.strict);
+    cpu.set_protect_reserved(config.protect_reserved);
+    cpu.set_hires_detection(config.hires);
+    cpu.set_mapped_display(config.map_display);
+}
+
+/// Loads `rom` into `cpu` from power-on, with what `config` sets for the ROM itself:
+/// the quirks it's known to need, the random seed, `overlays` and the start address.
+fn load_rom<R: Read, W: Write>(
+    cpu: &mut CPU<R, W>,
+    config: &Config,
+    rom: &[u8],
+    overlays: &Overlays,
+) {
+    if config.auto_quirks {
+        cpu.set_quirks(known_roms::quirks_for(rom, config.quirks));
+    }
+    if let Some(seed) = config.seed_source.seed(rom) {
+        cpu.set_seed(seed);
+    }
+    cpu.reload(rom);
+    if let Some(patch) = &overlays.patch {
+        cpu.apply_patch(patch);
+    }
+    for (addr, data) in &overlays.preloads {
+        // Checked to fit when the file was read
+        cpu.preload(*addr, data).unwrap();
+    }
+    if let Some(start) = config.start {
+        // The address was checked with the arguments
+        cpu.set_pc(start).unwrap();
+    }
+}
+
+/// Why the emulator stopped running a ROM.
+#[derive(Debug)]
+enum StopReason {
+    /// The user pressed the quit key.
+    Quit,
+    /// The ROM jumped to itself.
+    Halted,
+    MaxCycles,
+    Error(ExecutionError),
+    /// A file asked for while running, such as a screenshot, couldn't be written.
+    WriteFailed(String),
+}
+
+impl StopReason {
+    /// 0 when the user or ROM ended things, 1 on errors and 3 for the cycle limit.
+    /// 2 is left for bad arguments.
+    fn exit_code(&self) -> i32 {
+        match self {
+            StopReason::Quit | StopReason::Halted => 0,
+            StopReason::Error(_) | StopReason::WriteFailed(_) => 1,
+            StopReason::MaxCycles => 3,
+        }
+    }
+}
+
+/// Runs frames at 60Hz, handling the control keys between them, until the user stops,
+/// or the ROM does under `--max-cycles` or `--screenshot-on-exit`. With a `watcher`,
+/// the ROM is loaded again, `overlays` and all, whenever its file changes.
+fn run<R: Read, W: Write>(
+    cpu: &mut CPU<R, W>,
+    config: &Config,
+    overlays: &Overlays,
+    watcher: Option<&RomWatcher>,
+) -> StopReason {
+    let mut paused = false;
+    let mut auto_speed = AutoSpeed::new(cpu.cycles_per_frame());
+    let mut timer = FrameTimer::with_fps(Box::new(SystemClock::new()), config.fps_cap);
+    let mut meter = RateMeter::new(Box::new(SystemClock::new()), cpu.cycles());
+
+    loop {
+        if !timer.should_tick_timers() {
+            timer.sleep_until_next_frame();
+            continue;
+        }
+        if let Some(watcher) = watcher {
+            match watcher.read_if_changed() {
+                Ok(Some(rom)) => {
+                    load_rom(cpu, config, &rom, overlays);
+                    cpu.terminal_mut()
+                        .set_status(format!("reloaded {}", config.rom));
+                }
+                Ok(None) => (),
+                Err(e) => cpu
+                    .terminal_mut()
+                    .set_status(format!("cannot reload {}: {}", config.rom, e)),
+            }
+        }
+        let rates = meter.frame(cpu.cycles());
+        if config.status_line || config.show_stats {
+            let mut status = Vec::new();
+            if config.status_line {
+                status.push(cpu.status_line());
+            }
+            if let Some(rates) = rates.filter(|_| config.show_stats) {
+                status.push(rates.to_string());
+            }
+            cpu.terminal_mut().set_live_status(Some(status.join("  ")));
+        }
+        let result = if paused {
+            // Keep reading input and showing single steps, without running frames
+            cpu.terminal_mut().poll_input();
+            cpu.present();
+            Ok(!cpu.terminal().exit)
+        } else {
+            cpu.run_frame()
+        };
+        match result {
+            Ok(true) => (),
+            Ok(false) if cpu.terminal().exit => return StopReason::Quit,
+            // Only stop for runs that want the end result; otherwise leave the final
+            // screen up until the user quits
+            Ok(false) if config.max_cycles.is_some() || config.screenshot_on_exit.is_some() => {
+                return StopReason::Halted
+            }
+            Ok(false) => cpu.present(),
+            Err(e) => return StopReason::Error(e),
+        }
+        if config.auto_speed && !paused {
+            let speed = auto_speed.adjust(cpu.cycles_per_frame(), cpu.draws_last_frame());
+            cpu.set_cycles_per_frame(speed);
+        }
+        if config.max_cycles.is_some_and(|max| cpu.cycles() >= max) {
+            return StopReason::MaxCycles;
+        }
+        for control in cpu.terminal_mut().take_controls() {
+            match control {
+                Control::Screenshot => {
+                    if let Some(path) = &config.screenshot {
+                        if let Err(e) = cpu.terminal().save_screenshot(Path::new(path)) {
+                            return StopReason::WriteFailed(format!(
+                                "cannot write {}: {}",
+                                path, e
+                            ));
+                        }
+                    }
+                }
+                Control::Pause => {
+                    paused = !paused;
+                    cpu.set_memory_view(None);
+                }
+                Control::ScrollMemory(delta) => {
+                    if let Some(addr) = cpu.memory_view() {
+                        let addr = (addr as i32 + delta as i32).clamp(0, 0xFFF);
+                        debugger::show_memory(cpu, addr as u16);
+                        cpu.present();
+                    }
+                }
+                Control::Step if paused => {
+                    if let Err(e) = cpu.step() {
+                        return StopReason::Error(e);
+                    }
+                }
+                Control::StepBack if paused => {
+                    cpu.step_back();
+                }
+                Control::StepOver if paused => {
+                    if let Err(e) = cpu.step_over(STEP_OVER_MAX_STEPS) {
+                        return StopReason::Error(e);
+                    }
+                }
+                Control::Step | Control::StepBack | Control::StepOver => (),
+                Control::Command(line) => match Command::parse(&line) {
+                    Ok(command) => {
+                        command.execute(cpu);
+                        cpu.present();
+                    }
+                    Err(message) => cpu.terminal_mut().set_status(message),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chip8::cpu::ExecutionError;
+
+    use super::StopReason;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(StopReason::Quit.exit_code(), 0);
+        assert_eq!(StopReason::Halted.exit_code(), 0);
+        let error = ExecutionError::StackUnderflow { pc: 0x200 };
+        assert_eq!(StopReason::Error(error).exit_code(), 1);
+        assert_eq!(StopReason::MaxCycles.exit_code(), 3);
+        assert_eq!(StopReason::WriteFailed(String::new()).exit_code(), 1);
+    }
 }
@@ -0,0 +1,139 @@
+use std::convert::TryInto;
+
+use crate::cpu::MEMORY;
+
+// ROMs are loaded here, so IPS file offsets are relative to it
+const ROM_START: usize = 0x200;
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+
+/// Byte overrides applied to memory after a ROM is loaded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Patch {
+    pub writes: Vec<(u16, u8)>,
+}
+
+impl Patch {
+    /// Reads an IPS patch if `data` starts with the IPS header, and a text patch
+    /// otherwise.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.starts_with(IPS_HEADER) {
+            Self::parse_ips(data)
+        } else {
+            let text = std::str::from_utf8(data).map_err(|_| "patch is not valid text")?;
+            Self::parse_text(text)
+        }
+    }
+
+    /// Parses `ADDR: BYTE` lines, both in hex and with an optional `0x`, where ADDR
+    /// is a memory address. Blank lines and anything after a `#` are ignored.
+    pub fn parse_text(text: &str) -> Result<Self, String> {
+        let mut writes = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: expected `ADDR: BYTE`, got `{}`", n + 1, line);
+            let (addr, byte) = line.split_once(':').ok_or_else(error)?;
+            let addr = parse_hex(addr).ok_or_else(error)?;
+            let byte = parse_hex(byte)
+                .and_then(|byte| byte.try_into().ok())
+                .ok_or_else(error)?;
+            if addr as usize >= MEMORY {
+                return Err(format!(
+                    "line {}: address {:X} is out of memory",
+                    n + 1,
+                    addr
+                ));
+            }
+            writes.push((addr, byte));
+        }
+        Ok(Patch { writes })
+    }
+
+    /// Parses an IPS patch. Its offsets count from the start of the ROM file, so 0
+    /// patches address 0x200.
+    pub fn parse_ips(data: &[u8]) -> Result<Self, String> {
+        let truncated = || "IPS patch is truncated".to_string();
+        let mut rest = data
+            .strip_prefix(IPS_HEADER)
+            .ok_or_else(|| "missing IPS header".to_string())?;
+        let mut writes = Vec::new();
+        while rest != IPS_FOOTER {
+            let record = rest.get(..5).ok_or_else(truncated)?;
+            let offset = u32::from_be_bytes([0, record[0], record[1], record[2]]) as usize;
+            let size = u16::from_be_bytes([record[3], record[4]]) as usize;
+            rest = &rest[5..];
+            let bytes = if size == 0 {
+                // Run-length encoded: a 16-bit count and the byte to repeat
+                let run = rest.get(..3).ok_or_else(truncated)?;
+                rest = &rest[3..];
+                vec![run[2]; u16::from_be_bytes([run[0], run[1]]) as usize]
+            } else {
+                let bytes = rest.get(..size).ok_or_else(truncated)?.to_vec();
+                rest = &rest[size..];
+                bytes
+            };
+            if ROM_START + offset + bytes.len() > MEMORY {
+                return Err(format!("IPS record at {:X} is out of memory", offset));
+            }
+            for (i, byte) in bytes.into_iter().enumerate() {
+                writes.push(((ROM_START + offset + i) as u16, byte));
+            }
+        }
+        Ok(Patch { writes })
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u16::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Patch;
+
+    #[test]
+    fn text() {
+        let patch = Patch::parse_text(
+            "# Skip the title screen\n\
+             0x200: 0x12\n\
+             201: 4E  # JP 0x24E\n\
+             \n",
+        )
+        .unwrap();
+        assert_eq!(patch.writes, [(0x200, 0x12), (0x201, 0x4E)]);
+
+        assert!(Patch::parse_text("200 12").is_err());
+        assert!(Patch::parse_text("200: 100").is_err());
+        assert!(Patch::parse_text("1000: 00").is_err());
+    }
+
+    #[test]
+    fn ips() {
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0, 0, 2, 0, 2, 0xAB, 0xCD]);
+        ips.extend_from_slice(&[0, 0, 0x10, 0, 0, 0, 3, 0xEE]);
+        ips.extend_from_slice(b"EOF");
+        let patch = Patch::parse(&ips).unwrap();
+        assert_eq!(
+            patch.writes,
+            [
+                (0x202, 0xAB),
+                (0x203, 0xCD),
+                (0x210, 0xEE),
+                (0x211, 0xEE),
+                (0x212, 0xEE)
+            ]
+        );
+
+        assert!(Patch::parse(&ips[..ips.len() - 3]).is_err());
+        assert!(Patch::parse(b"PATCH\0\x0E\0\0\x01\0EOF").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+/// Which registers the 8xy6/8xyE shifts read from and write to. VF gets the
+/// shifted-out bit in every case.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShiftQuirk {
+    /// COSMAC VIP: Vx = Vy shifted.
+    LegacyVy,
+    /// CHIP-48 and SUPER-CHIP: Vx = Vx shifted, Vy is ignored.
+    ModernVx,
+    /// Vy = Vy shifted, Vx is left alone. Only a few early documents describe
+    /// this, for ROMs written against them.
+    LegacyVyToVy,
+}
+
+/// Whether ADD I, Vx (Fx1E) reports overflow in VF, and past which address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexOverflowQuirk {
+    /// VF is left alone, as on the COSMAC VIP and most interpreters.
+    Unflagged,
+    /// VF = 1 when I goes past 0xFFF, the end of 4KB memory, as the Amiga interpreter
+    /// did and Spaceflight 2091! relies on; otherwise VF = 0.
+    Past0FFF,
+    /// VF = 1 only when I overflows its full 16 bits past 0xFFFF; otherwise VF = 0.
+    Past0FFFF,
+}
+
+/// How far the SUPER-CHIP scrolls (00Cn, 00FB, 00FC) move the 64x32 low-res display.
+///
+/// SUPER-CHIP counts scroll amounts in 128x64 hi-res pixels, even in low-res mode,
+/// so on real hardware a low-res scroll by n moved the picture by n / 2 pixels, and
+/// an odd n by a half pixel that the low-res display can't show. Most interpreters
+/// instead double the amount in low-res mode so it counts whole low-res pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LowResScrollQuirk {
+    /// Scroll by n low-res pixels, as Octo and most modern interpreters do.
+    Doubled,
+    /// Scroll by n / 2 low-res pixels, as SUPER-CHIP 1.1 on the HP-48 did. Odd
+    /// amounts round down, dropping the half pixel.
+    Halved,
+}
+
+/// How many bytes `Quirks::to_bytes` takes.
+pub const QUIRKS_BYTES: usize = 10;
+
+/// Behaviour that differs between CHIP-8 interpreters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    pub shift: ShiftQuirk,
+    /// 8xy1/8xy2/8xy3 reset VF to 0, as on the COSMAC VIP.
+    pub vf_reset: bool,
+    /// Fx55/Fx65 leave I pointing past the last register copied, as on the COSMAC VIP.
+    pub load_store_increments_i: bool,
+    /// DRW waits for the next 60Hz frame after the first one in a frame, as the COSMAC
+    /// VIP only drew during vertical blank.
+    pub display_wait: bool,
+    /// ADD I, Vx wraps I within the 12 bit address space of a 4KB machine. XO-CHIP
+    /// turns this off for its 16 bit addresses.
+    pub add_i_wraps_12_bits: bool,
+    /// DRW starts sprites at (Vx % 64, Vy % 32). Without it a sprite whose origin is
+    /// off the display isn't drawn at all.
+    pub wrap_draw_origin: bool,
+    /// DRW cuts sprites off at the right edge instead of wrapping them around to the
+    /// left, as on the COSMAC VIP. This is separate from where the sprite starts,
+    /// which `wrap_draw_origin` decides.
+    pub clip_sprites_x: bool,
+    /// DRW cuts sprites off at the bottom edge instead of wrapping them around to the
+    /// top, as on the COSMAC VIP.
+    pub clip_sprites_y: bool,
+    pub index_overflow: IndexOverflowQuirk,
+    pub low_res_scroll: LowResScrollQuirk,
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub fn cosmac() -> Self {
+        Quirks {
+            shift: ShiftQuirk::LegacyVy,
+            vf_reset: true,
+            load_store_increments_i: true,
+            display_wait: true,
+            add_i_wraps_12_bits: true,
+            wrap_draw_origin: true,
+            clip_sprites_x: true,
+            clip_sprites_y: true,
+            index_overflow: IndexOverflowQuirk::Unflagged,
+            low_res_scroll: LowResScrollQuirk::Doubled,
+        }
+    }
+
+    /// Every combination of the quirks the test suite checks, with the rest at their
+    /// defaults.
+    pub fn combinations() -> Vec<Self> {
+        let mut combinations = Vec::new();
+        for &shift in &[ShiftQuirk::LegacyVy, ShiftQuirk::ModernVx] {
+            for &vf_reset in &[false, true] {
+                for &load_store_increments_i in &[false, true] {
+                    combinations.push(Quirks {
+                        shift,
+                        vf_reset,
+                        load_store_increments_i,
+                        ..Quirks::default()
+                    });
+                }
+            }
+        }
+        combinations
+    }
+
+    /// The quirks a byte each, in the order the fields are declared, for save states.
+    pub fn to_bytes(&self) -> [u8; QUIRKS_BYTES] {
+        [
+            self.shift as u8,
+            self.vf_reset as u8,
+            self.load_store_increments_i as u8,
+            self.display_wait as u8,
+            self.add_i_wraps_12_bits as u8,
+            self.wrap_draw_origin as u8,
+            self.clip_sprites_x as u8,
+            self.clip_sprites_y as u8,
+            self.index_overflow as u8,
+            self.low_res_scroll as u8,
+        ]
+    }
+
+    /// Reads back what `to_bytes` wrote, or `None` if a byte doesn't fit its quirk.
+    pub fn from_bytes(bytes: [u8; QUIRKS_BYTES]) -> Option<Self> {
+        let flag = |byte| match byte {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        Some(Quirks {
+            shift: match bytes[0] {
+                0 => ShiftQuirk::LegacyVy,
+                1 => ShiftQuirk::ModernVx,
+                2 => ShiftQuirk::LegacyVyToVy,
+                _ => return None,
+            },
+            vf_reset: flag(bytes[1])?,
+            load_store_increments_i: flag(bytes[2])?,
+            display_wait: flag(bytes[3])?,
+            add_i_wraps_12_bits: flag(bytes[4])?,
+            wrap_draw_origin: flag(bytes[5])?,
+            clip_sprites_x: flag(bytes[6])?,
+            clip_sprites_y: flag(bytes[7])?,
+            index_overflow: match bytes[8] {
+                0 => IndexOverflowQuirk::Unflagged,
+                1 => IndexOverflowQuirk::Past0FFF,
+                2 => IndexOverflowQuirk::Past0FFFF,
+                _ => return None,
+            },
+            low_res_scroll: match bytes[9] {
+                0 => LowResScrollQuirk::Doubled,
+                1 => LowResScrollQuirk::Halved,
+                _ => return None,
+            },
+        })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: ShiftQuirk::ModernVx,
+            vf_reset: false,
+            load_store_increments_i: false,
+            display_wait: false,
+            add_i_wraps_12_bits: true,
+            wrap_draw_origin: true,
+            clip_sprites_x: false,
+            clip_sprites_y: false,
+            index_overflow: IndexOverflowQuirk::Unflagged,
+            low_res_scroll: LowResScrollQuirk::Doubled,
+        }
+    }
+}
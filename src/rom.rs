@@ -0,0 +1,137 @@
+//! Checks on a ROM file made before running it, so a bad file is reported up front
+//! rather than failing partway into loading.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+#[cfg(feature = "zip")]
+use std::fs::File;
+
+#[cfg(feature = "zip")]
+use crate::archive;
+use crate::cpu::{is_hires_rom, MEMORY};
+use crate::known_roms::checksum;
+
+/// The most a ROM can hold: memory from 0x200 to the end of 4KB.
+pub const MAX_ROM_SIZE: usize = MEMORY - 0x200;
+
+/// What `validate_rom` found out about a ROM.
+#[derive(Debug, PartialEq)]
+pub struct RomInfo {
+    pub len: usize,
+    /// Opens with HIRES CHIP-8's jump, so `CPU::load` would set up a 64x64 display.
+    pub hires: bool,
+    /// As used to recognize known ROMs.
+    pub checksum: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    Empty,
+    TooLarge { len: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Empty => write!(f, "the ROM is empty"),
+            RomError::TooLarge { len } => write!(
+                f,
+                "the ROM is {} bytes, more than the {} that fit in memory",
+                len, MAX_ROM_SIZE
+            ),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+/// Reads the ROM at `path`, which with the `zip` feature can also name an entry in a
+/// zip archive as `games.zip:pong.ch8`.
+pub fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "zip")]
+    if let Some((path, entry)) = archive::split_entry(path) {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        return archive::read_entry(file, entry);
+    }
+    fs::read(path).map_err(|e| e.to_string())
+}
+
+/// Checks that `bytes` can be loaded as a ROM, without loading it.
+pub fn validate_rom(bytes: &[u8]) -> Result<RomInfo, RomError> {
+    match bytes.len() {
+        0 => Err(RomError::Empty),
+        len if len > MAX_ROM_SIZE => Err(RomError::TooLarge { len }),
+        len => Ok(RomInfo {
+            len,
+            hires: is_hires_rom(bytes),
+            checksum: checksum(bytes),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_rom() {
+        let rom = [0x12, 0x60, 0x00, 0xE0];
+        assert_eq!(
+            validate_rom(&rom),
+            Ok(RomInfo {
+                len: 4,
+                hires: true,
+                checksum: checksum(&rom),
+            })
+        );
+        assert!(!validate_rom(&[0x00, 0xE0]).unwrap().hires);
+        assert!(validate_rom(&[0; MAX_ROM_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn empty_rom() {
+        assert_eq!(validate_rom(&[]), Err(RomError::Empty));
+    }
+
+    #[test]
+    fn oversized_rom() {
+        assert_eq!(
+            validate_rom(&[0; MAX_ROM_SIZE + 1]),
+            Err(RomError::TooLarge {
+                len: MAX_ROM_SIZE + 1
+            })
+        );
+        assert_eq!(
+            RomError::TooLarge { len: 4000 }.to_string(),
+            "the ROM is 4000 bytes, more than the 3584 that fit in memory"
+        );
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn rom_in_an_archive() {
+        use std::env;
+        use std::io::Write;
+        use zip::write::{FileOptions, ZipWriter};
+
+        let path = env::temp_dir().join(format!("chip8-rom-{}.zip", std::process::id()));
+        let mut writer = ZipWriter::new(File::create(&path).unwrap());
+        writer
+            .start_file("pong.ch8", FileOptions::default())
+            .unwrap();
+        writer.write_all(&[0x12, 0x00]).unwrap();
+        writer.finish().unwrap();
+
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            read_rom(&format!("{}:pong.ch8", path)),
+            Ok(vec![0x12, 0x00])
+        );
+        assert_eq!(
+            read_rom(&format!("{}:tetris.ch8", path)),
+            Err("no tetris.ch8 in the archive".to_string())
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
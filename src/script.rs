@@ -0,0 +1,250 @@
+//! Scripts that drive the emulator without a display, for `--script`: a command a
+//! line to load a ROM, run it, press keys, take screenshots and check the display,
+//! for repeatable demos and tests of ROMs.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::backend::KeyEvent;
+use crate::cpu::CPU;
+use crate::rom::{read_rom, validate_rom};
+
+/// A line of a script. Blank lines and lines starting with `#` aren't commands.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `load FILE`: loads the ROM in FILE and starts it over.
+    Load(String),
+    /// `run N`: runs N frames, fewer if the ROM halts.
+    Run(u32),
+    /// `press KEY`: holds down the keypad key KEY, in hex, until `release KEY`.
+    Press(u8),
+    /// `release KEY`: lets go of KEY.
+    Release(u8),
+    /// `screenshot FILE`: writes the display to FILE, as `--screenshot` does.
+    Screenshot(String),
+    /// `assert_pixel X Y on|off`: fails the script unless the pixel is lit or unlit.
+    AssertPixel { x: usize, y: usize, on: bool },
+}
+
+/// Where and why a script stopped.
+#[derive(Debug, PartialEq)]
+pub struct ScriptError {
+    /// The 1-based line of the command that failed.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl Command {
+    /// Parses a line, which is `None` if it has no command.
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words[..] {
+            ["load", file] => Command::Load(file.to_string()),
+            ["load", ..] => return Err("usage: load FILE".to_string()),
+            ["run", frames] => Command::Run(
+                frames
+                    .parse()
+                    .map_err(|_| "usage: run FRAMES".to_string())?,
+            ),
+            ["run", ..] => return Err("usage: run FRAMES".to_string()),
+            ["press", key] => Command::Press(parse_key(key)?),
+            ["release", key] => Command::Release(parse_key(key)?),
+            ["press", ..] | ["release", ..] => {
+                return Err(format!("usage: {} KEY, in hex", words[0]))
+            }
+            ["screenshot", file] => Command::Screenshot(file.to_string()),
+            ["screenshot", ..] => return Err("usage: screenshot FILE".to_string()),
+            ["assert_pixel", x, y, state] => {
+                let on = match state {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("usage: assert_pixel X Y on|off".to_string()),
+                };
+                match (x.parse(), y.parse()) {
+                    (Ok(x), Ok(y)) => Command::AssertPixel { x, y, on },
+                    _ => return Err("usage: assert_pixel X Y on|off".to_string()),
+                }
+            }
+            ["assert_pixel", ..] => return Err("usage: assert_pixel X Y on|off".to_string()),
+            [other, ..] => return Err(format!("unknown command `{}`", other)),
+            [] => unreachable!(),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute<R: Read, W: Write>(&self, cpu: &mut CPU<R, W>) -> Result<(), String> {
+        match *self {
+            Command::Load(ref path) => {
+                let rom = read_rom(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+                validate_rom(&rom).map_err(|e| format!("cannot run {}: {}", path, e))?;
+                cpu.reload(&rom);
+            }
+            Command::Run(frames) => {
+                for _ in 0..frames {
+                    if cpu.step_frame().map_err(|e| e.to_string())?.halted {
+                        break;
+                    }
+                }
+            }
+            Command::Press(key) => cpu.terminal_mut().handle_key_event(KeyEvent::Down(key)),
+            Command::Release(key) => cpu.terminal_mut().handle_key_event(KeyEvent::Up(key)),
+            Command::Screenshot(ref path) => cpu
+                .terminal()
+                .save_screenshot(Path::new(path))
+                .map_err(|e| format!("cannot write {}: {}", path, e))?,
+            Command::AssertPixel { x, y, on } => {
+                let (width, height) = cpu.terminal().dimensions();
+                if x >= width || y >= height {
+                    return Err(format!(
+                        "pixel {},{} is off the {}x{} display",
+                        x, y, width, height
+                    ));
+                }
+                let lit = cpu.terminal().rows()[y] >> (63 - x) & 1 == 1;
+                if lit != on {
+                    let state = |lit| if lit { "on" } else { "off" };
+                    return Err(format!(
+                        "pixel {},{} is {}, expected {}",
+                        x,
+                        y,
+                        state(lit),
+                        state(on)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_key(key: &str) -> Result<u8, String> {
+    match u8::from_str_radix(key, 16) {
+        Ok(key) if key < 16 => Ok(key),
+        _ => Err(format!("`{}` is not a keypad key, 0 to F", key)),
+    }
+}
+
+/// Runs `script` on `cpu` a line at a time, stopping at the first line that fails.
+pub fn run<R: Read, W: Write>(cpu: &mut CPU<R, W>, script: &str) -> Result<(), ScriptError> {
+    for (i, line) in script.lines().enumerate() {
+        let error = |message| ScriptError {
+            line: i + 1,
+            message,
+        };
+        if let Some(command) = Command::parse(line).map_err(error)? {
+            command.execute(cpu).map_err(error)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::sink;
+
+    use super::{run, Command, ScriptError};
+    use crate::cpu::CPU;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("  # a comment"), Ok(None));
+        assert_eq!(Command::parse(""), Ok(None));
+        assert_eq!(
+            Command::parse("load game.ch8"),
+            Ok(Some(Command::Load("game.ch8".to_string())))
+        );
+        assert_eq!(Command::parse("run 600"), Ok(Some(Command::Run(600))));
+        assert_eq!(Command::parse("press a"), Ok(Some(Command::Press(0xA))));
+        assert_eq!(Command::parse("release F"), Ok(Some(Command::Release(0xF))));
+        assert_eq!(
+            Command::parse("assert_pixel 10 10 on"),
+            Ok(Some(Command::AssertPixel {
+                x: 10,
+                y: 10,
+                on: true
+            }))
+        );
+        assert!(Command::parse("run").is_err());
+        assert!(Command::parse("run -1").is_err());
+        assert!(Command::parse("press 10").is_err());
+        assert!(Command::parse("assert_pixel 10 10 lit").is_err());
+        assert_eq!(
+            Command::parse("jump 200"),
+            Err("unknown command `jump`".to_string())
+        );
+    }
+
+    #[test]
+    fn run_script() {
+        let dir = env::temp_dir();
+        let rom = dir.join(format!("chip8-script-{}.ch8", std::process::id()));
+        let shot = dir.join(format!("chip8-script-{}.pbm", std::process::id()));
+        fs::write(
+            &rom,
+            [
+                0xF0, 0x0A, // LD V0, K
+                0xF0, 0x29, // LD F, V0
+                0xD1, 0x15, // DRW V1, V1, 5
+                0x12, 0x06, // JP 0x206
+            ],
+        )
+        .unwrap();
+        let script = format!(
+            "# Draws the glyph for the key pressed
+            load {}
+            run 2
+            assert_pixel 0 0 off
+
+            press 5
+            run 1
+            release 5
+            assert_pixel 3 0 on
+            assert_pixel 4 0 off
+            screenshot {}
+            run 600
+            ",
+            rom.display(),
+            shot.display()
+        );
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        assert_eq!(run(&mut cpu, &script), Ok(()));
+        assert_eq!(cpu.registers()[0], 5);
+        assert!(cpu.is_halted());
+        assert!(cpu.terminal().pressed_keys().next().is_none());
+        assert_eq!(fs::read(&shot).unwrap(), cpu.terminal().to_pbm());
+
+        assert_eq!(
+            run(&mut cpu, "run 1\n\nassert_pixel 0 3 on\nrun 1"),
+            Err(ScriptError {
+                line: 3,
+                message: "pixel 0,3 is off, expected on".to_string()
+            })
+        );
+        assert_eq!(
+            run(&mut cpu, "assert_pixel 64 0 on"),
+            Err(ScriptError {
+                line: 1,
+                message: "pixel 64,0 is off the 64x32 display".to_string()
+            })
+        );
+        assert!(run(&mut cpu, &format!("load {}.missing", rom.display())).is_err());
+        fs::remove_file(&rom).unwrap();
+        fs::remove_file(&shot).unwrap();
+    }
+}
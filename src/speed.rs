@@ -0,0 +1,76 @@
+// Frames without a DRW before the budget is raised, about half a second
+const QUIET_FRAMES: u32 = 30;
+const MAX_CYCLES_PER_FRAME: u32 = 1_000;
+
+/// Picks `cycles_per_frame` from how often the ROM draws. Games redraw every frame or
+/// two once they are in play, while long stretches without a DRW are usually loading
+/// or computation that is fine to speed through.
+pub struct AutoSpeed {
+    base: u32,
+    quiet: u32,
+}
+
+impl AutoSpeed {
+    /// `base` is the speed used while the ROM draws regularly, and the lowest it goes.
+    pub fn new(base: u32) -> Self {
+        AutoSpeed { base, quiet: 0 }
+    }
+
+    /// Returns the speed for the next frame given the current one and how many sprites
+    /// the last frame drew. Each half second without drawing raises the speed by half,
+    /// and each frame that draws brings it a sixteenth of the way back to the base.
+    pub fn adjust(&mut self, current: u32, draws: u32) -> u32 {
+        if draws > 0 {
+            self.quiet = 0;
+            let excess = current.saturating_sub(self.base);
+            return current - (excess / 16).max(1).min(excess);
+        }
+        self.quiet += 1;
+        if self.quiet < QUIET_FRAMES {
+            return current;
+        }
+        self.quiet = 0;
+        (current + (current / 2).max(1)).min(MAX_CYCLES_PER_FRAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoSpeed, MAX_CYCLES_PER_FRAME, QUIET_FRAMES};
+
+    fn run(tuner: &mut AutoSpeed, mut speed: u32, draws: &[u32]) -> u32 {
+        for &n in draws {
+            speed = tuner.adjust(speed, n);
+        }
+        speed
+    }
+
+    #[test]
+    fn drawing_every_frame_keeps_the_base_speed() {
+        let mut tuner = AutoSpeed::new(10);
+        assert_eq!(run(&mut tuner, 10, &[1, 3, 1, 2, 1, 1]), 10);
+    }
+
+    #[test]
+    fn quiet_frames_raise_the_speed() {
+        let mut tuner = AutoSpeed::new(10);
+        let quiet = [0; QUIET_FRAMES as usize];
+        assert_eq!(run(&mut tuner, 10, &quiet[1..]), 10);
+        assert_eq!(tuner.adjust(10, 0), 15);
+        assert_eq!(run(&mut tuner, 15, &quiet), 22);
+        // A draw resets the streak
+        assert_eq!(run(&mut tuner, 22, &quiet[1..]), 22);
+        assert_eq!(tuner.adjust(22, 1), 21);
+        assert_eq!(run(&mut tuner, 21, &quiet[1..]), 21);
+
+        assert_eq!(run(&mut tuner, 900, &quiet), MAX_CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn drawing_again_returns_to_the_base() {
+        let mut tuner = AutoSpeed::new(10);
+        assert_eq!(tuner.adjust(330, 1), 310);
+        assert_eq!(tuner.adjust(12, 1), 11);
+        assert_eq!(run(&mut tuner, 330, &[1; 100]), 10);
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::cpu::CPU;
+
+// Glyphs are 4 pixels wide, plus a column between them
+const GLYPH_ADVANCE: u8 = 5;
+const GLYPH_HEIGHT: usize = 5;
+
+/// Where each character of `text` goes in the built-in font, starting at `x`, as
+/// `(x, digit)` pairs. Only hex digits have glyphs; anything else leaves a gap.
+pub fn glyph_positions(text: &str, x: u8) -> Vec<(u8, u8)> {
+    text.chars()
+        .zip((0..).map(|i| x.wrapping_add(i * GLYPH_ADVANCE)))
+        .filter_map(|(c, x)| c.to_digit(16).map(|digit| (x, digit as u8)))
+        .collect()
+}
+
+/// Replaces the display with "C8" in the built-in font, with the ROM's file name
+/// on the status line. Registers and memory are left alone.
+pub fn draw<R: Read, W: Write>(cpu: &mut CPU<R, W>, rom: &str) {
+    let name = Path::new(rom)
+        .file_name()
+        .map_or(rom.to_string(), |name| name.to_string_lossy().into_owned());
+    let font = cpu.font().to_vec();
+    cpu.clear_display();
+    for (x, digit) in glyph_positions("C8", 27) {
+        let glyph = &font[digit as usize * GLYPH_HEIGHT..][..GLYPH_HEIGHT];
+        cpu.terminal_mut().draw_sprite(x, 13, glyph);
+    }
+    cpu.terminal_mut().set_status(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+
+    use super::{draw, glyph_positions};
+    use crate::cpu::CPU;
+    use crate::testing::assert_display_matches;
+
+    #[test]
+    fn positions() {
+        assert_eq!(glyph_positions("C8", 27), [(27, 0xC), (32, 8)]);
+        assert_eq!(glyph_positions("0a F", 0), [(0, 0), (5, 0xA), (15, 0xF)]);
+        assert_eq!(glyph_positions("CHIP-8", 2), [(2, 0xC), (27, 8)]);
+        assert!(glyph_positions("", 0).is_empty());
+    }
+
+    #[test]
+    fn draws_c8() {
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.terminal_mut().draw_sprite(0, 0, &[0xFF]);
+        draw(&mut cpu, "roms/pong.ch8");
+        let blank = ".\n".repeat(13);
+        let text = "
+            ...........................####.####
+            ...........................#....#..#
+            ...........................#....####
+            ...........................#....#..#
+            ...........................####.####
+        ";
+        assert_display_matches(cpu.terminal(), &(blank + text));
+    }
+}
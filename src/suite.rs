@@ -0,0 +1,107 @@
+//! A built-in conformance check: test ROMs that draw a pass or fail marker for each
+//! check into the top row of the display, run headlessly under every quirk combination.
+
+use std::io::sink;
+
+use crate::cpu::CPU;
+use crate::quirks::Quirks;
+
+const FRAMES: usize = 10;
+const PASS: u8 = 0xFF;
+
+pub struct TestRom {
+    pub name: &'static str,
+    /// One entry per check, in the order the ROM reports them left to right.
+    pub checks: &'static [&'static str],
+    pub rom: &'static [u8],
+}
+
+/// Expects the COSMAC VIP behaviour of each quirk; every check draws its marker as an
+/// 8 pixel wide byte at x = 8 * check, y = 0.
+pub const QUIRKS_ROM: TestRom = TestRom {
+    name: "quirks",
+    checks: &["vf reset", "load/store increments i", "shift reads vy"],
+    rom: &[
+        0x6F, 0x01, // 200: LD VF, 1
+        0x80, 0x11, // 202: OR V0, V1
+        0x6E, 0x00, // 204: LD VE, 0
+        0x4F, 0x00, // 206: SNE VF, 0
+        0x6E, 0x01, // 208: LD VE, 1
+        0x6A, 0x00, // 20A: LD VA, 0
+        0x22, 0x34, // 20C: CALL report
+        0xA2, 0x42, // 20E: LD I, scratch
+        0x60, 0x05, // 210: LD V0, 5
+        0x61, 0x06, // 212: LD V1, 6
+        0xF1, 0x55, // 214: LD [I], V1
+        0xF0, 0x65, // 216: LD V0, [I]
+        0x6E, 0x00, // 218: LD VE, 0
+        0x40, 0x42, // 21A: SNE V0, 0x42
+        0x6E, 0x01, // 21C: LD VE, 1
+        0x6A, 0x08, // 21E: LD VA, 8
+        0x22, 0x34, // 220: CALL report
+        0x60, 0x01, // 222: LD V0, 1
+        0x61, 0x04, // 224: LD V1, 4
+        0x80, 0x16, // 226: SHR V0, V1
+        0x6E, 0x00, // 228: LD VE, 0
+        0x40, 0x02, // 22A: SNE V0, 2
+        0x6E, 0x01, // 22C: LD VE, 1
+        0x6A, 0x10, // 22E: LD VA, 16
+        0x22, 0x34, // 230: CALL report
+        0x12, 0x32, // 232: JP 0x232
+        0xA2, 0x40, // 234: report: LD I, pass
+        0x3E, 0x01, // 236: SE VE, 1
+        0xA2, 0x41, // 238: LD I, fail
+        0x6B, 0x00, // 23A: LD VB, 0
+        0xDA, 0xB1, // 23C: DRW VA, VB, 1
+        0x00, 0xEE, // 23E: RET
+        0xFF, // 240: pass
+        0x81, // 241: fail
+        0x00, 0x00, 0x42, // 242: scratch
+    ],
+};
+
+pub const TEST_ROMS: &[TestRom] = &[QUIRKS_ROM];
+
+/// Runs a test ROM headlessly and reads back whether each of its checks passed.
+pub fn run(test: &TestRom, quirks: Quirks) -> Vec<bool> {
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.set_quirks(quirks);
+    cpu.load(test.rom);
+    for _ in 0..FRAMES {
+        if cpu.run_frame().is_err() {
+            break;
+        }
+    }
+
+    let top_row = cpu.terminal().rows()[0].to_be_bytes();
+    (0..test.checks.len())
+        .map(|check| top_row[check] == PASS)
+        .collect()
+}
+
+/// Runs every bundled test ROM under every quirk combination and lists which
+/// combinations pass all of its checks.
+pub fn report() -> String {
+    let mut report = String::new();
+    for test in TEST_ROMS {
+        report += &format!("{}:\n", test.name);
+        for quirks in Quirks::combinations() {
+            let results = run(test, quirks);
+            let failed: Vec<_> = test
+                .checks
+                .iter()
+                .zip(results)
+                .filter(|&(_, passed)| !passed)
+                .map(|(&check, _)| check)
+                .collect();
+            let verdict = if failed.is_empty() {
+                "PASS".to_string()
+            } else {
+                format!("FAIL ({})", failed.join(", "))
+            };
+            report += &format!("  {:?}: {}\n", quirks, verdict);
+        }
+    }
+    report
+}
@@ -1,19 +1,194 @@
-use std::io::{stdout, Read, Stdout, Write};
-use std::process::exit;
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
 
-use termion::cursor;
 use termion::event::Key;
 use termion::input::{Keys, TermRead};
-use termion::raw::{IntoRawMode, RawTerminal};
+use termion::{color, cursor};
 
-pub struct Terminal<R: TermRead> {
-    stdout: RawTerminal<Stdout>,
+use crate::backend::KeyEvent;
+use crate::keymap::{default_keymap, Keymap};
+use crate::known_roms::fnv1a;
+
+pub const DISPLAY_WIDTH: usize = 64;
+/// The height of the display unless set otherwise with `set_display_height`.
+pub const DISPLAY_HEIGHT: usize = 32;
+/// The tallest display supported, for HIRES CHIP-8's 64x64.
+pub const MAX_DISPLAY_HEIGHT: usize = 64;
+
+// Worst case for a single pixel is a `Goto` escape (`\x1B[64;64H`, 8 bytes) followed by
+// a 3-byte `█`, so a whole frame always fits into the buffer and is written in one go.
+// The keypad panel adds four lines of 12 characters, each after a `Goto`, and the
+// status line below the display is cleared and rewritten with up to 64 characters.
+// One more byte is for the bell. With a plane palette each pixel can also need a color
+// escape (`\x1B[38;5;255m`, 11 bytes), and resetting the color afterwards takes 5.
+const FRAME_BYTES: usize =
+    64 * MAX_DISPLAY_HEIGHT * (11 + 11) + 5 + 4 * (8 + 12) + 8 + 4 + 64 * 4 + 1;
+// Terminals only report presses (and auto-repeats), never releases, so a key counts as
+// held for this many frames after it was last seen.
+const KEY_HOLD_FRAMES: u8 = 10;
+pub const DEFAULT_BRIGHTNESS_RAMP: [char; 5] = [' ', '░', '▒', '▓', '█'];
+// The COSMAC VIP keypad layout
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+const HELP: &[&str] = &[
+    "",
+    "  0-9 a-f   keypad, or as set by --keymap-file",
+    "  Ctrl+P    pause / resume",
+    "  Ctrl+N    step one instruction while paused",
+    "  Ctrl+B    step back while paused",
+    "  Ctrl+O    step over a CALL while paused",
+    "  Ctrl+S    screenshot",
+    "  Ctrl+G    gridlines every 8 pixels, with coordinates",
+    "  Up/Down   scroll :memview, PgUp/PgDn by a column",
+    "  Ctrl+C    quit, or as set by --quit-key",
+    "  :         debugger command, Esc cancels",
+    "  F1 or ?   close this help",
+    "",
+];
+
+/// A terminal color, as an index into the 256-color ANSI palette.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color(pub u8);
+
+/// What happens to key presses Fx0A hasn't taken by the end of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleKeys {
+    /// Dropped, so only keys pressed while a ROM waits satisfy Fx0A.
+    Drop,
+    /// Kept until the next Fx0A, however long that takes.
+    Keep,
+}
+
+/// Which edges cut sprites off rather than letting them wrap around to the other side.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Clip {
+    /// The right edge
+    pub x: bool,
+    /// The bottom edge
+    pub y: bool,
+}
+
+impl Clip {
+    pub const NONE: Clip = Clip { x: false, y: false };
+    pub const BOTH: Clip = Clip { x: true, y: true };
+}
+
+/// A change a ROM made to the display, recorded while `set_draw_events` is on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawEvent {
+    /// A sprite `rows` rows tall XORed on with its top left at (x, y), after the origin
+    /// wrapped, and whether it turned any pixel off
+    Sprite {
+        x: u8,
+        y: u8,
+        rows: u8,
+        collision: bool,
+    },
+    /// CLS, of the planes selected at the time
+    Clear {
+        planes: u8,
+    },
+    ScrollDown(u8),
+    ScrollRight(u8),
+    ScrollLeft(u8),
+}
+
+impl fmt::Display for DrawEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrawEvent::Sprite {
+                x,
+                y,
+                rows,
+                collision,
+            } => write!(
+                f,
+                "sprite {},{} {} rows{}",
+                x,
+                y,
+                rows,
+                if *collision { " collision" } else { "" }
+            ),
+            DrawEvent::Clear { planes } => write!(f, "clear planes {}", planes),
+            DrawEvent::ScrollDown(n) => write!(f, "scroll down {}", n),
+            DrawEvent::ScrollRight(n) => write!(f, "scroll right {}", n),
+            DrawEvent::ScrollLeft(n) => write!(f, "scroll left {}", n),
+        }
+    }
+}
+
+/// Emulator controls entered from the keyboard, as opposed to CHIP-8 keypad input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Control {
+    /// Ctrl+S
+    Screenshot,
+    /// Ctrl+P, toggles
+    Pause,
+    /// Ctrl+N, executes one instruction while paused
+    Step,
+    /// Ctrl+B, undoes one instruction while paused
+    StepBack,
+    /// Ctrl+O, runs a whole subroutine if paused at a CALL, otherwise the same as Step
+    StepOver,
+    /// The arrow keys move the debugger's memory view by a byte, Page Up and Page Down
+    /// by a column of 32
+    ScrollMemory(i16),
+    /// A line typed after `:`, for the debugger
+    Command(String),
+}
+
+pub struct Terminal<R: TermRead, W: Write> {
+    stdout: BufWriter<W>,
     stdin: Keys<R>,
-    pixels: [u64; 32],
+    // Rows past `height` stay blank
+    pixels: [u64; MAX_DISPLAY_HEIGHT],
+    height: usize,
+    // XO-CHIP's second bit plane, and which planes drawing and clearing affect
+    plane2: [u64; MAX_DISPLAY_HEIGHT],
+    planes: u8,
+    // The planes as of the last swap, which is all render shows with double buffering
+    front: ([u64; MAX_DISPLAY_HEIGHT], [u64; MAX_DISPLAY_HEIGHT]),
+    double_buffered: bool,
+    // What the last render showed, for anti-flicker blending
+    previous: [u64; MAX_DISPLAY_HEIGHT],
+    anti_flicker: bool,
+    brightness_ramp: Vec<char>,
+    plane_palette: Option<[Color; 4]>,
+    keymap: Keymap,
+    keypad_panel: bool,
     unprocessed: Vec<u8>,
+    stale_keys: StaleKeys,
+    // Frames left until each key counts as released
+    held: [u8; 16],
+    // Keys a front-end with real releases reported down
+    down: [bool; 16],
+    controls: Vec<Control>,
+    quit_key: Key,
+    cancel_key: Option<Key>,
+    // Whether the cancel key was pressed since keys were last aged
+    wait_cancelled: bool,
+    // The command being typed after `:`, which takes all keyboard input until done
+    command: Option<String>,
+    status: Option<String>,
+    live_status: Option<String>,
+    status_shown: bool,
+    sounding: bool,
+    help: bool,
+    grid: bool,
+    draw_events: Option<Vec<DrawEvent>>,
     pub exit: bool,
 }
 
+/// The pixels of a display row from left to right, starting at bit 63.
 struct BitIterator {
     n: u64,
     index: u32,
@@ -36,54 +211,494 @@ impl Iterator for BitIterator {
     }
 }
 
-impl<R: Read> Terminal<R> {
-    pub fn new(r: R) -> Self {
+impl<R: Read, W: Write> Terminal<R, W> {
+    /// `r` must never block: input is read until it runs dry, which it signals by
+    /// returning no bytes or a `WouldBlock` error, as `termion::async_stdin` does.
+    /// Nothing here waits for a key, not even Fx0A, which runs again each time
+    /// instead until one arrives.
+    pub fn new(r: R, w: W) -> Self {
         let mut term = Terminal {
-            stdout: stdout().into_raw_mode().unwrap(),
+            stdout: BufWriter::with_capacity(FRAME_BYTES, w),
             stdin: r.keys(),
-            pixels: [0; 32],
+            pixels: [0; MAX_DISPLAY_HEIGHT],
+            height: DISPLAY_HEIGHT,
+            plane2: [0; MAX_DISPLAY_HEIGHT],
+            planes: 1,
+            front: ([0; MAX_DISPLAY_HEIGHT], [0; MAX_DISPLAY_HEIGHT]),
+            double_buffered: false,
+            previous: [0; MAX_DISPLAY_HEIGHT],
+            anti_flicker: false,
+            brightness_ramp: DEFAULT_BRIGHTNESS_RAMP.to_vec(),
+            plane_palette: None,
+            keymap: default_keymap(),
+            keypad_panel: false,
             unprocessed: Vec::new(),
+            stale_keys: StaleKeys::Drop,
+            held: [0; 16],
+            down: [false; 16],
+            controls: Vec::new(),
+            quit_key: Key::Ctrl('c'),
+            cancel_key: None,
+            wait_cancelled: false,
+            command: None,
+            status: None,
+            live_status: None,
+            status_shown: false,
+            sounding: false,
+            help: false,
+            grid: false,
+            draw_events: None,
             exit: false,
         };
         term.clear();
         write!(term.stdout, "{}", cursor::Hide).unwrap();
+        term.stdout.flush().unwrap();
         term
     }
 
+    /// When enabled, a pixel that was lit in the previous frame is still drawn, so sprites
+    /// that games erase and redraw every frame don't flicker. The framebuffer itself,
+    /// and with it collision detection, is unaffected.
+    pub fn set_anti_flicker(&mut self, anti_flicker: bool) {
+        self.anti_flicker = anti_flicker;
+    }
+
+    /// When enabled, `render` shows the display as of the last `swap_buffers` rather
+    /// than as it is, so drawing that's still in progress never reaches the screen.
+    pub fn set_double_buffering(&mut self, double_buffered: bool) {
+        self.double_buffered = double_buffered;
+    }
+
+    /// Makes the display as drawn so far the one `render` shows when double buffering.
+    pub fn swap_buffers(&mut self) {
+        self.front = (self.pixels, self.plane2);
+    }
+
+    /// The characters for each pixel intensity, from off to fully lit. Must not be empty.
+    pub fn set_brightness_ramp(&mut self, ramp: Vec<char>) {
+        assert!(
+            !ramp.is_empty(),
+            "brightness ramp needs at least one character"
+        );
+        self.brightness_ramp = ramp;
+    }
+
+    /// Replaces which characters press which keypad keys.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    /// Shows the keypad to the right of the display, with held keys in brackets.
+    pub fn set_keypad_panel(&mut self, keypad_panel: bool) {
+        self.keypad_panel = keypad_panel;
+    }
+
+    /// Replaces the characters for unlit and fully lit pixels, the ends of the
+    /// brightness ramp.
+    pub fn set_pixel_chars(&mut self, off: char, on: char) {
+        self.brightness_ramp[0] = off;
+        let last = self.brightness_ramp.len() - 1;
+        self.brightness_ramp[last] = on;
+    }
+
+    /// Maps an intensity level to its character. 0 is off and anything past the end of
+    /// the ramp is fully lit.
+    fn shade(&self, level: usize) -> char {
+        self.brightness_ramp[level.min(self.brightness_ramp.len() - 1)]
+    }
+
     pub fn render(&mut self) {
-        for (y, &line) in self.pixels.iter().enumerate() {
-            for (x, bit) in BitIterator::new(line).enumerate() {
-                write!(
-                    self.stdout,
-                    "{}{}",
-                    cursor::Goto(x as u16 + 1, y as u16 + 1),
-                    if bit { '█' } else { ' ' }
-                )
-                .unwrap();
+        let cells = self.compose();
+        let states = plane_states(&self.frame()[..self.height], &self.shown().1);
+        self.previous = self.shown().0;
+        let mut current = None;
+        for (y, row) in cells.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                write!(self.stdout, "{}", cursor::Goto(x as u16 + 1, y as u16 + 1)).unwrap();
+                if let Some(palette) = self.plane_palette {
+                    let Color(wanted) = palette[states[y][x]];
+                    if current != Some(wanted) {
+                        write!(self.stdout, "{}", color::Fg(color::AnsiValue(wanted))).unwrap();
+                        current = Some(wanted);
+                    }
+                }
+                write!(self.stdout, "{}", cell).unwrap();
+            }
+        }
+        if current.is_some() {
+            write!(self.stdout, "{}", color::Fg(color::Reset)).unwrap();
+        }
+        if self.keypad_panel {
+            let pressed: Vec<u8> = self.pressed_keys().collect();
+            for (y, line) in keypad_panel(|key| pressed.contains(&key))
+                .lines()
+                .enumerate()
+            {
+                write!(self.stdout, "{}{}", cursor::Goto(67, y as u16 + 1), line).unwrap();
             }
         }
+        self.render_status_line();
         self.stdout.flush().unwrap();
     }
 
+    /// Draws the command being typed, or else the status message, below the display.
+    /// The line is only touched while there is something to show or to erase.
+    fn render_status_line(&mut self) {
+        let line = match (&self.command, &self.status) {
+            (Some(command), _) => Some(format!(":{}", command)),
+            (None, Some(status)) => Some(status.clone()),
+            (None, None) => self.live_status.clone(),
+        };
+        if line.is_some() || self.status_shown {
+            let line: String = line.as_deref().unwrap_or("").chars().take(64).collect();
+            write!(
+                self.stdout,
+                "{}{}{}",
+                cursor::Goto(1, self.height as u16 + 1),
+                termion::clear::CurrentLine,
+                line
+            )
+            .unwrap();
+        }
+        self.status_shown = line.is_some();
+    }
+
+    /// A message shown below the display until the next command is started.
+    pub fn set_status(&mut self, status: String) {
+        self.status = Some(status);
+    }
+
+    pub fn clear_status(&mut self) {
+        self.status = None;
+    }
+
+    /// A line shown below the display when there's no command or status message,
+    /// meant to be replaced every frame.
+    pub fn set_live_status(&mut self, status: Option<String>) {
+        self.live_status = status;
+    }
+
+    /// Rings the terminal bell as a sound starts, once per frame telling whether the
+    /// sound timer is running. A bell can't be held, so a sound lasting many frames
+    /// still rings only once instead of a bell every frame.
+    pub fn set_sound(&mut self, sounding: bool) {
+        if sounding && !self.sounding {
+            write!(self.stdout, "\x07").unwrap();
+        }
+        self.sounding = sounding;
+    }
+
+    /// Colors the display by plane: the foreground takes the first color where neither
+    /// plane is lit, the second where only the first plane is, the third where only
+    /// the second plane is and the fourth where both are. `None`, the default, leaves
+    /// the terminal's colors alone.
+    pub fn set_plane_palette(&mut self, palette: Option<[Color; 4]>) {
+        self.plane_palette = palette;
+    }
+
+    /// The characters for a frame of the first plane combined with the second plane.
+    /// A pixel in the first plane only is fully lit, so plain CHIP-8 looks the same
+    /// whatever the ramp. Pixels in the second plane take the ramp's third character,
+    /// or its fourth where they overlap the first.
+    fn cells(&self, first: &[u64], second: &[u64]) -> Vec<[char; 64]> {
+        let shades = [
+            self.shade(0),
+            self.shade(usize::MAX),
+            self.shade(2),
+            self.shade(3),
+        ];
+        plane_states(first, second)
+            .iter()
+            .map(|states| {
+                let mut row = [shades[0]; 64];
+                for (cell, &state) in row.iter_mut().zip(states.iter()) {
+                    *cell = shades[state];
+                }
+                row
+            })
+            .collect()
+    }
+
+    /// The characters to show, with the gridlines and help text drawn over the display
+    /// while they are toggled on. The framebuffer itself is left alone.
+    fn compose(&self) -> Vec<[char; 64]> {
+        let mut cells = self.cells(&self.frame()[..self.height], &self.shown().1);
+        if self.grid {
+            let (off, faint) = (self.shade(0), self.shade(1));
+            for (row, lines) in cells.iter_mut().zip(grid_lines().iter()) {
+                for (cell, on_line) in row.iter_mut().zip(BitIterator::new(*lines)) {
+                    if on_line && *cell == off {
+                        *cell = faint;
+                    }
+                }
+            }
+            // x along the top edge and y down the left, at each line
+            for x in (8..64).step_by(8) {
+                for (cell, digit) in cells[0][x..].iter_mut().zip(x.to_string().chars()) {
+                    *cell = digit;
+                }
+            }
+            for y in (8..self.height).step_by(8) {
+                for (cell, digit) in cells[y].iter_mut().zip(y.to_string().chars()) {
+                    *cell = digit;
+                }
+            }
+        }
+        if self.help {
+            let top = self.height.saturating_sub(HELP.len()) / 2;
+            for (row, text) in cells[top..].iter_mut().zip(HELP) {
+                let mut chars = text.chars();
+                for cell in row[4..60].iter_mut() {
+                    *cell = chars.next().unwrap_or(' ');
+                }
+            }
+        }
+        cells
+    }
+
+    /// Both planes as `render` should show them.
+    fn shown(&self) -> ([u64; MAX_DISPLAY_HEIGHT], [u64; MAX_DISPLAY_HEIGHT]) {
+        if self.double_buffered {
+            self.front
+        } else {
+            (self.pixels, self.plane2)
+        }
+    }
+
+    fn frame(&self) -> [u64; MAX_DISPLAY_HEIGHT] {
+        let mut frame = self.shown().0;
+        if self.anti_flicker {
+            for (line, previous) in frame.iter_mut().zip(self.previous.iter()) {
+                *line |= previous;
+            }
+        }
+        frame
+    }
+
+    /// Clears the selected planes, which is the whole display unless an XO-CHIP ROM
+    /// selected otherwise.
     pub fn clear(&mut self) {
+        self.record(DrawEvent::Clear {
+            planes: self.planes,
+        });
         write!(self.stdout, "{}", termion::clear::All).unwrap();
-        self.pixels = [0; 32];
-        self.stdout.flush().unwrap();
+        if self.planes & 1 != 0 {
+            self.pixels = [0; MAX_DISPLAY_HEIGHT];
+        }
+        if self.planes & 2 != 0 {
+            self.plane2 = [0; MAX_DISPLAY_HEIGHT];
+        }
+    }
+
+    /// Clears both planes and forgets the last rendered frame, so anti-flicker doesn't
+    /// carry anything over and the next render is entirely blank.
+    pub fn reset_display(&mut self) {
+        write!(self.stdout, "{}", termion::clear::All).unwrap();
+        self.pixels = [0; MAX_DISPLAY_HEIGHT];
+        self.plane2 = [0; MAX_DISPLAY_HEIGHT];
+        self.front = ([0; MAX_DISPLAY_HEIGHT], [0; MAX_DISPLAY_HEIGHT]);
+        self.previous = [0; MAX_DISPLAY_HEIGHT];
+    }
+
+    /// Starts or stops recording a `DrawEvent` for each sprite, clear and scroll, to be
+    /// collected with `take_draw_events`. Stopping drops anything not yet taken.
+    pub fn set_draw_events(&mut self, record: bool) {
+        self.draw_events = if record { Some(Vec::new()) } else { None };
+    }
+
+    /// The changes to the display since the last call, oldest first.
+    pub fn take_draw_events(&mut self) -> Vec<DrawEvent> {
+        self.draw_events.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    fn record(&mut self, event: DrawEvent) {
+        if let Some(events) = self.draw_events.as_mut() {
+            events.push(event);
+        }
     }
 
+    /// Changes how many rows the display has, from 1 to `MAX_DISPLAY_HEIGHT`, and
+    /// blanks it. Sprites wrap at, and scrolls and clipping stop at, the new bottom.
+    pub fn set_display_height(&mut self, height: usize) {
+        assert!(
+            (1..=MAX_DISPLAY_HEIGHT).contains(&height),
+            "display height must be 1 to {}",
+            MAX_DISPLAY_HEIGHT
+        );
+        self.height = height;
+        self.reset_display();
+    }
+
+    /// Which planes DRW and CLS affect, bit 0 being the first plane and bit 1 the
+    /// second, as set by XO-CHIP's `PLANE n`. Only the first is selected at start.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// XO-CHIP's second plane, laid out like `framebuffer`.
+    pub fn second_plane(&self) -> [u64; 32] {
+        self.plane2[..32].try_into().unwrap()
+    }
+
+    pub fn set_second_plane(&mut self, pixels: [u64; 32]) {
+        self.plane2[..32].copy_from_slice(&pixels);
+    }
+
+    /// Every row of the second plane, laid out like `rows`.
+    pub fn second_plane_rows(&self) -> &[u64] {
+        &self.plane2[..self.height]
+    }
+
+    /// Replaces the second plane's rows from the top, as many as are given.
+    pub fn set_second_plane_rows(&mut self, rows: &[u64]) {
+        for (line, &row) in self.plane2[..self.height].iter_mut().zip(rows) {
+            *line = row;
+        }
+    }
+
+    /// The display as text, one line per row, using the same characters as `render`.
+    pub fn to_ascii(&self) -> String {
+        let mut ascii = String::new();
+        for row in self.cells(self.rows(), &self.plane2).iter() {
+            ascii.extend(row.iter());
+            ascii.push('\n');
+        }
+        ascii
+    }
+
+    /// Writes the display to `path`, as a PBM image if it ends in `.pbm` and as text
+    /// otherwise.
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        if path.extension() == Some(OsStr::new("pbm")) {
+            fs::write(path, self.to_pbm())
+        } else {
+            fs::write(path, self.to_ascii())
+        }
+    }
+
+    /// The display size in pixels, as (width, height).
+    pub fn dimensions(&self) -> (usize, usize) {
+        (DISPLAY_WIDTH, self.height)
+    }
+
+    /// The display, one word per row with the leftmost pixel in the most significant bit.
+    /// A display taller than 32 rows is cut off at the bottom; `rows` has all of it.
+    pub fn framebuffer(&self) -> [u64; 32] {
+        self.pixels[..32].try_into().unwrap()
+    }
+
+    /// Every row of the display, laid out like `framebuffer`, whatever its height.
+    pub fn rows(&self) -> &[u64] {
+        &self.pixels[..self.height]
+    }
+
+    /// Replaces row `y` of the display, which must be within its height.
+    pub fn set_row(&mut self, y: usize, row: u64) {
+        self.rows_mut()[y] = row;
+    }
+
+    fn rows_mut(&mut self) -> &mut [u64] {
+        &mut self.pixels[..self.height]
+    }
+
+    /// Replaces the display's rows from the top, as many as are given.
+    pub fn set_rows(&mut self, rows: &[u64]) {
+        for (line, &row) in self.rows_mut().iter_mut().zip(rows) {
+            *line = row;
+        }
+    }
+
+    /// A 64-bit FNV-1a hash of the display, for comparing screens without keeping
+    /// them around. It depends only on which pixels are lit.
+    pub fn framebuffer_hash(&self) -> u64 {
+        fnv1a(self.rows().iter().flat_map(|line| line.to_be_bytes()))
+    }
+
+    pub fn set_framebuffer(&mut self, pixels: [u64; 32]) {
+        self.pixels[..32].copy_from_slice(&pixels);
+    }
+
+    fn pbm_header(&self) -> Vec<u8> {
+        format!("P4\n{} {}\n", DISPLAY_WIDTH, self.height).into_bytes()
+    }
+
+    /// Encodes the display as a binary (P4) PBM image, lit pixels being black.
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut pbm = self.pbm_header();
+        for line in self.rows() {
+            pbm.extend_from_slice(&line.to_be_bytes());
+        }
+        pbm
+    }
+
+    /// Replaces the display with a binary PBM image of the same size, as produced by
+    /// `to_pbm`.
+    pub fn load_pbm(&mut self, pbm: &[u8]) -> Result<(), String> {
+        let header = self.pbm_header();
+        if !pbm.starts_with(&header) || pbm.len() != header.len() + self.height * 8 {
+            return Err(format!("expected a 64x{} binary PBM image", self.height));
+        }
+        for (line, bytes) in self
+            .rows_mut()
+            .iter_mut()
+            .zip(pbm[header.len()..].chunks(8))
+        {
+            *line = u64::from_be_bytes(bytes.try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    /// XORs the sprite onto the display with its top left corner at (x, y). Both the
+    /// origin and the sprite itself wrap around the edges, so y = 200 draws from row 8.
+    /// With both planes selected the sprite holds the rows for the first plane followed
+    /// by as many for the second.
     pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> u8 {
-        let mut row = y as usize;
-        let mut overwritten = false;
+        self.draw_sprite_with_clip(x, y, sprite, Clip::NONE)
+    }
 
-        for &byte in sprite {
-            if row >= 32 {
-                row %= 32;
-            }
-            let new_line = self.pixels[row] ^ (u64::from_be(byte as u64).rotate_right(x as u32));
-            overwritten = overwritten || self.pixels[row] & new_line != self.pixels[row];
-            self.pixels[row] = new_line;
-            row += 1;
+    /// Like `draw_sprite`, but the sprite is cut off at the right and bottom edges
+    /// rather than wrapping around. The origin still wraps.
+    pub fn draw_sprite_clipped(&mut self, x: u8, y: u8, sprite: &[u8]) -> u8 {
+        self.draw_sprite_with_clip(x, y, sprite, Clip::BOTH)
+    }
+
+    /// Like `draw_sprite`, but cut off at whichever edges `clip` says rather than
+    /// wrapping around them.
+    pub fn draw_sprite_with_clip(&mut self, x: u8, y: u8, sprite: &[u8], clip: Clip) -> u8 {
+        self.draw_rows(x, y, sprite, 1, clip)
+    }
+
+    /// Draws a SUPER-CHIP 16x16 sprite, two bytes to a row, otherwise the same as
+    /// `draw_sprite_with_clip`.
+    pub fn draw_sprite_16(&mut self, x: u8, y: u8, sprite: &[u8], clip: Clip) -> u8 {
+        self.draw_rows(x, y, sprite, 2, clip)
+    }
+
+    /// Draws a sprite `width` bytes wide onto each selected plane.
+    fn draw_rows(&mut self, x: u8, y: u8, sprite: &[u8], width: usize, clip: Clip) -> u8 {
+        let len = sprite.len() / (self.planes.count_ones() as usize).max(1);
+        let height = self.height;
+        let mut sprite = sprite;
+        let mut overwritten = false;
+        if self.planes & 1 != 0 {
+            let plane = &mut self.pixels[..height];
+            overwritten |= xor_sprite(plane, x, y, &sprite[..len], width, clip);
+            sprite = &sprite[len..];
+        }
+        if self.planes & 2 != 0 {
+            let plane = &mut self.plane2[..height];
+            overwritten |= xor_sprite(plane, x, y, &sprite[..len], width, clip);
         }
+        self.record(DrawEvent::Sprite {
+            x: x % DISPLAY_WIDTH as u8,
+            y: (y as usize % height) as u8,
+            rows: (len / width) as u8,
+            collision: overwritten,
+        });
         if overwritten {
             1
         } else {
@@ -91,76 +706,447 @@ impl<R: Read> Terminal<R> {
         }
     }
 
-    pub fn check_if_pressed(&mut self, expected: u8) -> bool {
-        for (i, &key) in self.unprocessed.iter().enumerate() {
-            if key == expected {
-                let _: Vec<_> = self.unprocessed.drain(0..=i).collect();
-                return true;
-            }
+    /// Lights the set bits of `rows` on the display, the first row at `y` and each
+    /// row's bit 63 at column `x`. Meant for overlays of any width, not ROM graphics:
+    /// nothing is turned off, there's no collision, and whatever falls past the right
+    /// or bottom edge is dropped rather than wrapped.
+    pub fn blit(&mut self, x: u8, y: u8, rows: &[u64]) {
+        let lines = self.rows_mut().iter_mut().skip(y as usize);
+        for (line, &row) in lines.zip(rows) {
+            *line |= row.checked_shr(x as u32).unwrap_or(0);
         }
+    }
 
-        while let Some(Ok(k)) = self.stdin.next() {
-            if k == Key::Ctrl('c') {
-                self.exit = true;
+    /// Turns off the pixels in the `w` by `h` rectangle at (x, y). Unlike sprites the
+    /// rectangle doesn't wrap, anything past the edges is ignored.
+    pub fn clear_region(&mut self, x: u8, y: u8, w: u8, h: u8) {
+        let left = u64::MAX.checked_shr(x as u32).unwrap_or(0);
+        let right = u64::MAX.checked_shr(x as u32 + w as u32).unwrap_or(0);
+        let mask = left & !right;
+        for line in self.rows_mut().iter_mut().skip(y as usize).take(h as usize) {
+            *line &= !mask;
+        }
+    }
+
+    /// Scrolls by `n` pixels, doing nothing for 0 and clearing the display for as many
+    /// as it has rows; the same goes for columns scrolling left or right.
+    pub fn scroll_down(&mut self, n: u8) {
+        self.record(DrawEvent::ScrollDown(n));
+        let n = n as usize;
+        for row in (0..self.height).rev() {
+            self.pixels[row] = if row >= n { self.pixels[row - n] } else { 0 };
+        }
+    }
+
+    pub fn scroll_right(&mut self, n: u8) {
+        self.record(DrawEvent::ScrollRight(n));
+        for line in self.pixels.iter_mut() {
+            *line = line.checked_shr(n as u32).unwrap_or(0);
+        }
+    }
+
+    pub fn scroll_left(&mut self, n: u8) {
+        self.record(DrawEvent::ScrollLeft(n));
+        for line in self.pixels.iter_mut() {
+            *line = line.checked_shl(n as u32).unwrap_or(0);
+        }
+    }
+
+    /// For SKP and SKNP: whether `key` is currently held. Every held key reports as
+    /// pressed, however many arrived in the same frame, and checking consumes nothing.
+    pub fn check_if_pressed(&mut self, key: u8) -> bool {
+        self.poll_input();
+        key < 16 && self.is_held(key)
+    }
+
+    fn is_held(&self, key: u8) -> bool {
+        self.held[key as usize] > 0 || self.down[key as usize]
+    }
+
+    /// The key that quits, Ctrl+C unless set otherwise. It quits even while a debugger
+    /// command is being typed, and is never passed on to the keypad.
+    pub fn set_quit_key(&mut self, key: Key) {
+        self.quit_key = key;
+    }
+
+    /// A key that, for debugging a ROM stuck waiting on Fx0A, makes the wait finish
+    /// without a keypad press. It is never passed on to the keypad.
+    pub fn set_cancel_key(&mut self, key: Option<Key>) {
+        self.cancel_key = key;
+    }
+
+    /// For Fx0A: whether the cancel key was pressed this frame. Checking consumes it.
+    pub fn take_wait_cancelled(&mut self) -> bool {
+        mem::take(&mut self.wait_cancelled)
+    }
+
+    /// Records a press or release from a front-end that reports both, such as a
+    /// window. Keys pressed this way stay held until released. Keys past F are ignored.
+    pub fn handle_key_event(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Down(key) | KeyEvent::Up(key) if key >= 16 => (),
+            KeyEvent::Down(key) => {
+                self.unprocessed.push(key);
+                self.down[key as usize] = true;
             }
-            match Self::map_key(k) {
-                Some(key) if key == expected => {
-                    self.unprocessed.clear();
-                    return true;
-                }
-                Some(key) => self.unprocessed.push(key),
-                _ => (),
+            KeyEvent::Up(key) => {
+                self.down[key as usize] = false;
+                self.held[key as usize] = 0;
             }
         }
-
-        false
     }
 
+    /// For Fx0A: the most recent key press since the last call, if any. Earlier presses
+    /// that arrived alongside it are dropped, so the last key pressed wins.
     pub fn wait_for_key_press(&mut self) -> Option<u8> {
-        if let Some(Ok(k)) = self.stdin.next() {
-            if k == Key::Ctrl('c') {
-                self.exit = true;
+        self.poll_input();
+        let key = self.unprocessed.pop();
+        self.unprocessed.clear();
+        key
+    }
+
+    /// Reads all pending input, handling control keys and recording keypad presses.
+    pub fn poll_input(&mut self) {
+        while let Some(Ok(k)) = self.stdin.next() {
+            self.handle_key(k);
+        }
+    }
+
+    /// Handles one key as if it had been read from input: quitting, editing a debugger
+    /// command, a control key or a keypad press.
+    pub fn handle_key(&mut self, k: Key) {
+        if k == self.quit_key {
+            self.exit = true;
+            return;
+        }
+        if self.command.is_some() {
+            self.edit_command(k);
+            return;
+        }
+        if Some(k) == self.cancel_key {
+            self.wait_cancelled = true;
+            return;
+        }
+        self.handle_control_key(k);
+        if let Some(key) = self.map_key(k) {
+            self.unprocessed.push(key);
+            self.held[key as usize] = KEY_HOLD_FRAMES;
+        }
+    }
+
+    /// Reads all pending input without acting on it, returning whether there was any.
+    /// The quit key still quits.
+    pub fn take_any_key(&mut self) -> bool {
+        let mut any = false;
+        while let Some(Ok(k)) = self.stdin.next() {
+            self.exit |= k == self.quit_key;
+            any = true;
+        }
+        any
+    }
+
+    /// Returns the controls entered since the last call, oldest first.
+    pub fn take_controls(&mut self) -> Vec<Control> {
+        mem::take(&mut self.controls)
+    }
+
+    /// Whether presses Fx0A hasn't taken are dropped each frame; they are by default.
+    pub fn set_stale_keys(&mut self, stale_keys: StaleKeys) {
+        self.stale_keys = stale_keys;
+    }
+
+    /// Counts down how long keys are considered held, and drops stale presses if set
+    /// to; call once per frame. A cancel no Fx0A took is always dropped.
+    pub fn age_keys(&mut self) {
+        self.wait_cancelled = false;
+        if self.stale_keys == StaleKeys::Drop {
+            self.unprocessed.clear();
+        }
+        for frames in self.held.iter_mut() {
+            *frames = frames.saturating_sub(1);
+        }
+    }
+
+    /// The keypad keys currently held down, in ascending order.
+    pub fn pressed_keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16).filter(move |&key| self.is_held(key))
+    }
+
+    /// The held keypad keys as a bitmask, bit k set while key k is held.
+    pub fn keypad_mask(&self) -> u16 {
+        self.pressed_keys().fold(0, |mask, key| mask | 1 << key)
+    }
+
+    fn handle_control_key(&mut self, key: Key) {
+        match key {
+            Key::Ctrl('s') => self.controls.push(Control::Screenshot),
+            Key::Ctrl('p') => self.controls.push(Control::Pause),
+            Key::Ctrl('n') => self.controls.push(Control::Step),
+            Key::Ctrl('b') => self.controls.push(Control::StepBack),
+            Key::Ctrl('o') => self.controls.push(Control::StepOver),
+            Key::Up => self.controls.push(Control::ScrollMemory(-1)),
+            Key::Down => self.controls.push(Control::ScrollMemory(1)),
+            Key::PageUp => self.controls.push(Control::ScrollMemory(-32)),
+            Key::PageDown => self.controls.push(Control::ScrollMemory(32)),
+            Key::F(1) | Key::Char('?') => self.help = !self.help,
+            Key::Ctrl('g') => self.grid = !self.grid,
+            Key::Char(':') => {
+                self.command = Some(String::new());
+                self.status = None;
             }
-            match Self::map_key(k) {
-                Some(key) => Some(key),
-                _ => None,
+            _ => (),
+        }
+    }
+
+    fn edit_command(&mut self, key: Key) {
+        match key {
+            Key::Esc => self.command = None,
+            Key::Char('\n') => {
+                let command = self.command.take().unwrap();
+                self.controls.push(Control::Command(command));
             }
-        } else {
-            None
+            Key::Backspace => {
+                self.command.as_mut().unwrap().pop();
+            }
+            Key::Char(c) => self.command.as_mut().unwrap().push(c),
+            _ => (),
         }
     }
 
-    fn map_key(key: Key) -> Option<u8> {
+    fn map_key(&self, key: Key) -> Option<u8> {
         match key {
-            Key::Char('0') => Some(0),
-            Key::Char('1') => Some(1),
-            Key::Char('2') => Some(2),
-            Key::Char('3') => Some(3),
-            Key::Char('4') => Some(4),
-            Key::Char('5') => Some(5),
-            Key::Char('6') => Some(6),
-            Key::Char('7') => Some(7),
-            Key::Char('8') => Some(8),
-            Key::Char('9') => Some(9),
-            Key::Char('a') => Some(10),
-            Key::Char('b') => Some(11),
-            Key::Char('c') => Some(12),
-            Key::Char('d') => Some(13),
-            Key::Char('e') => Some(14),
-            Key::Char('f') => Some(15),
+            Key::Char(c) => self.keymap.get(&c).copied(),
             _ => None,
         }
     }
 }
 
+/// Each pixel's plane bits: 0 when unlit, 1 in the first plane only, 2 in the second
+/// only and 3 in both. There is a row for each row of `first`.
+fn plane_states(first: &[u64], second: &[u64]) -> Vec<[usize; 64]> {
+    first
+        .iter()
+        .zip(second.iter())
+        .map(|(&first, &second)| {
+            let mut row = [0; 64];
+            let bits = BitIterator::new(first).zip(BitIterator::new(second));
+            for (state, (first, second)) in row.iter_mut().zip(bits) {
+                *state = first as usize | (second as usize) << 1;
+            }
+            row
+        })
+        .collect()
+}
+
+/// XORs the sprite onto one plane, returning whether any lit pixel was turned off.
+fn xor_sprite(plane: &mut [u64], x: u8, y: u8, sprite: &[u8], width: usize, clip: Clip) -> bool {
+    let height = plane.len();
+    let mut overwritten = false;
+    for (i, bytes) in sprite.chunks(width).enumerate() {
+        let row = y as usize % height + i;
+        if clip.y && row >= height {
+            break;
+        }
+        let row = row % height;
+        // The row's pixels from bit 63 down
+        let pixels = bytes.iter().enumerate().fold(0, |pixels, (i, &byte)| {
+            pixels | (byte as u64) << (56 - 8 * i)
+        });
+        let bits = if clip.x {
+            pixels >> (x % 64)
+        } else {
+            wrap_row(pixels, x)
+        };
+        let new_line = plane[row] ^ bits;
+        overwritten = overwritten || plane[row] & new_line != plane[row];
+        plane[row] = new_line;
+    }
+    overwritten
+}
+
+/// A sprite row placed on a display row with its first pixel at column `x`. Rows
+/// hold the leftmost pixel in bit 63, so the sprite's pixels start there too, and any
+/// pixels past the right edge wrap around to the left.
+fn wrap_row(row: u64, x: u8) -> u64 {
+    let x = x as u32 % 64;
+    // Shifting left by 64 would overflow; at column 0 nothing wraps anyway
+    row >> x | row.checked_shl(64 - x).unwrap_or(0)
+}
+
+/// The debug grid as a display-sized buffer: every 8th row and column is set.
+fn grid_lines() -> [u64; MAX_DISPLAY_HEIGHT] {
+    let columns = 0x8080_8080_8080_8080;
+    let mut grid = [columns; MAX_DISPLAY_HEIGHT];
+    for row in grid.iter_mut().step_by(8) {
+        *row = u64::MAX;
+    }
+    grid
+}
+
+/// Draws the keypad in its 4x4 layout, one line per row, with the keys for which
+/// `pressed` is true in brackets. Every line has the same width, so redrawing it over
+/// an older panel leaves nothing behind.
+fn keypad_panel(pressed: impl Fn(u8) -> bool) -> String {
+    let mut panel = String::new();
+    for row in KEYPAD.iter() {
+        for &key in row.iter() {
+            if pressed(key) {
+                panel += &format!("[{:X}]", key);
+            } else {
+                panel += &format!(" {:X} ", key);
+            }
+        }
+        panel.push('\n');
+    }
+    panel
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::terminal::BitIterator;
+    use std::collections::VecDeque;
+    use std::io::{self, sink, Write};
+
+    use termion::event::Key;
+    use termion::{color, cursor};
+
+    use crate::backend::KeyEvent;
+    use crate::terminal::{BitIterator, Control, DrawEvent};
+
+    #[derive(Default)]
+    struct CapturingSink {
+        bytes: Vec<u8>,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl Write for CapturingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn render() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, CapturingSink::default());
+        term.draw_sprite(0, 0, &[0b1000_0001]);
+        *term.stdout.get_mut() = CapturingSink::default();
+
+        term.render();
+
+        let mut expected = String::new();
+        for y in 0..32 {
+            for x in 0..64 {
+                let on = y == 0 && (x == 0 || x == 7);
+                expected += &format!(
+                    "{}{}",
+                    cursor::Goto(x + 1, y + 1),
+                    if on { '█' } else { ' ' }
+                );
+            }
+        }
+        let out = term.stdout.get_ref();
+        assert_eq!(out.writes, 1);
+        assert_eq!(out.flushes, 1);
+        assert_eq!(String::from_utf8(out.bytes.clone()).unwrap(), expected);
+    }
+
+    #[test]
+    fn plane_palette() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, CapturingSink::default());
+        term.set_plane_palette(Some([
+            super::Color(16),
+            super::Color(15),
+            super::Color(9),
+            super::Color(11),
+        ]));
+        // Columns 1 to 3 show the first plane, the second and both
+        term.select_planes(0b11);
+        term.draw_sprite(0, 0, &[0b0101_0000, 0b0011_0000]);
+        *term.stdout.get_mut() = CapturingSink::default();
+        term.render();
+
+        let out = String::from_utf8(term.stdout.get_ref().bytes.clone()).unwrap();
+        let fg = |n| color::Fg(color::AnsiValue(n)).to_string();
+        let goto = |x| cursor::Goto(x, 1).to_string();
+        let expected = format!(
+            "{}{} {}{}█{}{}▒{}{}▓{}{} ",
+            goto(1),
+            fg(16),
+            goto(2),
+            fg(15),
+            goto(3),
+            fg(9),
+            goto(4),
+            fg(11),
+            goto(5),
+            fg(16)
+        );
+        assert!(out.starts_with(&expected), "{:?}", out);
+        // The rest of the display is unlit, so the color doesn't change again
+        assert_eq!(out.matches("\x1B[38;5;").count(), 5);
+        assert!(out.ends_with(&color::Fg(color::Reset).to_string()));
+    }
+
+    #[test]
+    fn anti_flicker() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, CapturingSink::default());
+        term.set_anti_flicker(true);
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        term.render();
+        // Erased in the next frame, as games do before redrawing
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        assert_eq!(term.pixels[0], 0);
+        *term.stdout.get_mut() = CapturingSink::default();
+        term.render();
+        assert!(String::from_utf8(term.stdout.get_ref().bytes.clone())
+            .unwrap()
+            .starts_with(&format!("{}█", cursor::Goto(1, 1))));
+
+        term.set_anti_flicker(false);
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        term.render();
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        assert_eq!(term.frame()[0], 0);
+
+        // Resetting drops the previous frame too
+        term.set_anti_flicker(true);
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        term.render();
+        term.reset_display();
+        assert_eq!(term.frame(), [0; super::MAX_DISPLAY_HEIGHT]);
+    }
+
+    #[test]
+    fn double_buffering() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.set_double_buffering(true);
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        term.swap_buffers();
+        term.draw_sprite(8, 0, &[0b1000_0000]);
+        term.render();
+        let cells = term.compose();
+        assert_eq!((cells[0][0], cells[0][8]), ('█', ' '));
+
+        term.swap_buffers();
+        let cells = term.compose();
+        assert_eq!((cells[0][0], cells[0][8]), ('█', '█'));
+    }
 
     #[test]
     fn draw_sprite() {
         let r: &[u8] = b"\x1Bayo\x7F\x1B[D";
-        let mut term = super::Terminal::new(r);
+        let mut term = super::Terminal::new(r, sink());
         let mut overwritten = term.draw_sprite(1, 1, &[0b1100_1100]);
         assert_eq!(overwritten, 0);
         assert_eq!(
@@ -210,6 +1196,548 @@ mod tests {
         );
     }
 
+    #[test]
+    fn planes() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.select_planes(0b11);
+        term.draw_sprite(0, 0, &[0xF0, 0x0F]);
+        assert_eq!(term.pixels[0], 0xF0 << 56);
+        assert_eq!(term.plane2[0], 0x0F << 56);
+        assert_eq!(&term.to_ascii()[..24], "████▒▒▒▒");
+
+        term.select_planes(0b01);
+        term.clear();
+        assert_eq!(term.framebuffer(), [0; 32]);
+        assert_eq!(term.plane2[0], 0x0F << 56);
+
+        term.reset_display();
+        assert_eq!(term.second_plane(), [0; 32]);
+    }
+
+    #[test]
+    fn bell() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, CapturingSink::default());
+        let bells = |term: &super::Terminal<&[u8], CapturingSink>| {
+            term.stdout
+                .get_ref()
+                .bytes
+                .iter()
+                .filter(|&&b| b == 7)
+                .count()
+        };
+        for _ in 0..5 {
+            term.set_sound(true);
+            term.render();
+        }
+        assert_eq!(bells(&term), 1);
+
+        term.set_sound(false);
+        term.set_sound(true);
+        term.render();
+        assert_eq!(bells(&term), 2);
+    }
+
+    #[test]
+    fn framebuffer_hash() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        let mut other = super::Terminal::new(r, sink());
+        assert_eq!(term.framebuffer_hash(), other.framebuffer_hash());
+
+        term.draw_sprite(10, 20, &[0xF0, 0x90, 0xF0]);
+        other.draw_sprite(10, 20, &[0xF0, 0x90, 0xF0]);
+        assert_eq!(term.framebuffer_hash(), other.framebuffer_hash());
+
+        other.draw_sprite(63, 31, &[0x80]);
+        assert_ne!(term.framebuffer_hash(), other.framebuffer_hash());
+    }
+
+    #[test]
+    fn pixel_order() {
+        assert_eq!(super::wrap_row(0b1000_0001 << 56, 0), 1 << 63 | 1 << 56);
+        assert_eq!(super::wrap_row(0b1000_0001 << 56, 8), 1 << 55 | 1 << 48);
+        assert_eq!(super::wrap_row(0b1100_0000 << 56, 63), 1 << 63 | 1);
+        assert_eq!(super::wrap_row(0b1000_0000 << 56, 64), 1 << 63);
+        let bits: Vec<bool> = super::BitIterator::new(1 << 63 | 1 << 61).take(3).collect();
+        assert_eq!(bits, [true, false, true]);
+
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(2, 0, &[0b1010_0000]);
+        assert!(term.to_ascii().starts_with("  █ █ "));
+    }
+
+    #[test]
+    fn draw_sprite_below_display() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 200, &[0xFF, 0x81]);
+        assert_eq!(term.pixels[8], 0xFF << 56);
+        assert_eq!(term.pixels[9], 0x81 << 56);
+        assert_eq!(term.pixels.iter().filter(|&&line| line != 0).count(), 2);
+
+        term.draw_sprite(0, 255, &[0xFF, 0xFF]);
+        assert_eq!(term.pixels[31], 0xFF << 56);
+        assert_eq!(term.pixels[0], 0xFF << 56);
+    }
+
+    #[test]
+    fn dimensions() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        assert_eq!(term.dimensions(), (64, 32));
+        for &expected in &[32, 64] {
+            term.set_display_height(expected);
+            let (width, height) = term.dimensions();
+            assert_eq!((width, height), (64, expected));
+            assert_eq!(term.rows().len(), height);
+            assert_eq!(term.to_ascii().lines().count(), height);
+            assert!(term
+                .to_ascii()
+                .lines()
+                .all(|line| line.chars().count() == width));
+        }
+    }
+
+    #[test]
+    fn to_ascii() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(1, 1, &[0b1010_0000]);
+        let ascii = term.to_ascii();
+        let lines: Vec<_> = ascii.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[0], " ".repeat(64));
+        assert_eq!(lines[1], format!(" █ █{}", " ".repeat(60)));
+    }
+
+    #[test]
+    fn pbm() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 0, &[0b1100_0011]);
+        term.draw_sprite(60, 31, &[0b1010_0000]);
+
+        let pbm = term.to_pbm();
+        assert_eq!(&pbm[..9], b"P4\n64 32\n");
+        assert_eq!(pbm.len(), 9 + 32 * 8);
+        assert_eq!(pbm[9..17], [0b1100_0011, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(pbm[9 + 31 * 8..], [0, 0, 0, 0, 0, 0, 0, 0b0000_1010]);
+
+        let mut copy = super::Terminal::new(r, sink());
+        copy.load_pbm(&pbm).unwrap();
+        assert_eq!(copy.pixels, term.pixels);
+        assert!(copy.load_pbm(b"P4\n64 32\n").is_err());
+    }
+
+    #[test]
+    fn scroll() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 0, &[0b1100_0000]);
+        term.draw_sprite(60, 31, &[0b1111_0000]);
+        term.scroll_down(3);
+        assert_eq!(term.pixels[0], 0);
+        assert_eq!(term.pixels[3], 0b11 << 62);
+        assert_eq!(term.pixels[31], 0);
+
+        term.scroll_right(4);
+        assert_eq!(term.pixels[3], 0b11 << 58);
+        term.scroll_left(4);
+        term.scroll_left(4);
+        assert_eq!(term.pixels[3], 0);
+    }
+
+    #[test]
+    fn scroll_edge_cases() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 0, &[0xFF; 15]);
+        term.draw_sprite(56, 17, &[0xFF; 15]);
+        let drawn = term.framebuffer();
+
+        term.scroll_down(0);
+        term.scroll_right(0);
+        term.scroll_left(0);
+        assert_eq!(term.framebuffer(), drawn);
+
+        term.scroll_down(4);
+        assert_eq!(term.framebuffer()[..4], [0; 4]);
+        assert_eq!(term.framebuffer()[4..], drawn[..28]);
+
+        term.scroll_down(32);
+        assert_eq!(term.framebuffer(), [0; 32]);
+
+        term.set_framebuffer(drawn);
+        term.scroll_right(64);
+        assert_eq!(term.framebuffer(), [0; 32]);
+        term.set_framebuffer(drawn);
+        term.scroll_left(255);
+        assert_eq!(term.framebuffer(), [0; 32]);
+        term.set_framebuffer(drawn);
+        term.scroll_down(255);
+        assert_eq!(term.framebuffer(), [0; 32]);
+    }
+
+    #[test]
+    fn stale_keys() {
+        let r: &[u8] = b"5";
+        let mut term = super::Terminal::new(r, sink());
+        term.poll_input();
+        term.age_keys();
+        assert_eq!(term.wait_for_key_press(), None);
+
+        let r: &[u8] = b"5";
+        let mut term = super::Terminal::new(r, sink());
+        term.set_stale_keys(super::StaleKeys::Keep);
+        term.poll_input();
+        term.age_keys();
+        assert_eq!(term.wait_for_key_press(), Some(5));
+    }
+
+    #[test]
+    fn pressed_keys() {
+        let r: &[u8] = b"14f";
+        let mut term = super::Terminal::new(r, sink());
+        assert_eq!(term.pressed_keys().count(), 0);
+        term.poll_input();
+        assert_eq!(term.pressed_keys().collect::<Vec<_>>(), [1, 4, 0xF]);
+
+        for _ in 0..super::KEY_HOLD_FRAMES {
+            term.age_keys();
+        }
+        assert_eq!(term.pressed_keys().count(), 0);
+    }
+
+    #[test]
+    fn keypad_mask() {
+        let r: &[u8] = b"03f";
+        let mut term = super::Terminal::new(r, sink());
+        assert_eq!(term.keypad_mask(), 0);
+        term.poll_input();
+        assert_eq!(term.keypad_mask(), 0b1000_0000_0000_1001);
+    }
+
+    #[test]
+    fn command_line() {
+        let r: &[u8] = b":clear 1 2x\x7f 3 4\n5";
+        let mut term = super::Terminal::new(r, sink());
+        term.poll_input();
+        assert_eq!(
+            term.take_controls(),
+            [Control::Command("clear 1 2 3 4".to_string())]
+        );
+        // Keys typed into the command don't reach the keypad
+        assert_eq!(term.wait_for_key_press(), Some(5));
+    }
+
+    #[test]
+    fn quit_key() {
+        let r: &[u8] = b"\x03";
+        let mut term = super::Terminal::new(r, sink());
+        term.poll_input();
+        assert!(term.exit);
+
+        let r: &[u8] = b"\x03:\x11";
+        let mut term = super::Terminal::new(r, sink());
+        term.set_quit_key(Key::Ctrl('q'));
+        term.poll_input();
+        assert!(term.exit);
+        assert_eq!(term.command, Some(String::new()));
+    }
+
+    #[test]
+    fn blit() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 1, &[0b1000_0000]);
+        term.blit(60, 0, &[0b1011 << 60 | 0b11, 1 << 63 | 1 << 58]);
+        let mut expected = [0; 32];
+        expected[0] = 0b1011;
+        expected[1] = 1 << 63 | 1 << 3;
+        assert_eq!(term.framebuffer(), expected);
+
+        // Past the bottom edge is dropped, and the ROM's pixels are left on
+        term.blit(0, 31, &[1 << 63, 1 << 63]);
+        assert_eq!(term.pixels[31], 1 << 63);
+        assert_eq!(term.pixels[32], 0);
+        term.blit(0, 1, &[1 << 63]);
+        assert_eq!(term.pixels[1], 1 << 63 | 1 << 3);
+    }
+
+    #[test]
+    fn clear_region() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.set_framebuffer([u64::MAX; 32]);
+        term.clear_region(8, 4, 8, 8);
+        for (y, &line) in term.rows().iter().enumerate() {
+            if (4..12).contains(&y) {
+                assert_eq!(line, !(0xFF << 48), "row {}", y);
+            } else {
+                assert_eq!(line, u64::MAX, "row {}", y);
+            }
+        }
+
+        // Clipped at the edges
+        term.clear_region(60, 30, 8, 8);
+        assert_eq!(term.pixels[31], !0xF);
+        term.clear_region(0, 0, 64, 1);
+        assert_eq!(term.pixels[0], 0);
+    }
+
+    #[test]
+    fn simultaneous_key_presses() {
+        let r: &[u8] = b"3a5";
+        let mut term = super::Terminal::new(r, sink());
+        assert_eq!(term.wait_for_key_press(), Some(5));
+        assert_eq!(term.wait_for_key_press(), None);
+
+        for key in [3, 0xA, 5] {
+            assert!(term.check_if_pressed(key));
+            assert!(term.check_if_pressed(key));
+        }
+        assert!(!term.check_if_pressed(4));
+        assert!(!term.check_if_pressed(0x33));
+    }
+
+    #[test]
+    fn key_events() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.handle_key_event(KeyEvent::Down(7));
+        for _ in 0..=super::KEY_HOLD_FRAMES {
+            term.age_keys();
+        }
+        assert!(term.check_if_pressed(7));
+        // Still held, but the press itself went stale frames ago
+        assert_eq!(term.wait_for_key_press(), None);
+        term.handle_key_event(KeyEvent::Up(7));
+        assert!(!term.check_if_pressed(7));
+        assert_eq!(term.pressed_keys().count(), 0);
+
+        // Not a keypad key
+        term.handle_key_event(KeyEvent::Down(16));
+        term.handle_key_event(KeyEvent::Up(255));
+        assert_eq!(term.pressed_keys().count(), 0);
+        assert_eq!(term.wait_for_key_press(), None);
+    }
+
+    #[test]
+    fn keymap() {
+        let r: &[u8] = b"q1x";
+        let mut term = super::Terminal::new(r, sink());
+        term.set_keymap(crate::keymap::parse("q = 4\nx = C").unwrap());
+        term.poll_input();
+        assert_eq!(term.pressed_keys().collect::<Vec<_>>(), [4, 0xC]);
+    }
+
+    #[test]
+    fn controls() {
+        let r: &[u8] = b"\x101\x13\x0e\x02\x0f";
+        let mut term = super::Terminal::new(r, sink());
+        term.poll_input();
+        assert_eq!(
+            term.take_controls(),
+            [
+                Control::Pause,
+                Control::Screenshot,
+                Control::Step,
+                Control::StepBack,
+                Control::StepOver
+            ]
+        );
+        assert!(term.take_controls().is_empty());
+        assert_eq!(term.wait_for_key_press(), Some(1));
+    }
+
+    /// Gives each read the next result in line, then would block forever after.
+    struct NonBlocking(VecDeque<io::Result<u8>>);
+
+    impl io::Read for NonBlocking {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(byte)) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn input_never_blocks() {
+        let would_block = || Err(io::ErrorKind::WouldBlock.into());
+        let reader = NonBlocking(vec![would_block(), Ok(b'5'), would_block()].into());
+        let mut term = super::Terminal::new(reader, sink());
+        // Each check reads until the input runs dry and returns straight away
+        assert!(!term.check_if_pressed(5));
+        assert!(term.check_if_pressed(5));
+        assert!(term.check_if_pressed(5));
+        assert_eq!(term.wait_for_key_press(), Some(5));
+        assert_eq!(term.wait_for_key_press(), None);
+
+        // A reader that has nothing at all
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        assert!(!term.check_if_pressed(5));
+        assert_eq!(term.wait_for_key_press(), None);
+    }
+
+    #[test]
+    fn handle_key() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.handle_key(Key::Char('a'));
+        assert_eq!(term.pressed_keys().collect::<Vec<_>>(), [0xA]);
+        assert!(!term.exit);
+
+        // Arrows scroll the memory view rather than pressing keypad keys
+        term.handle_key(Key::Up);
+        assert_eq!(term.pressed_keys().collect::<Vec<_>>(), [0xA]);
+        assert_eq!(term.take_controls(), [Control::ScrollMemory(-1)]);
+        assert!(!term.exit);
+
+        term.handle_key(Key::Ctrl('c'));
+        assert!(term.exit);
+        assert_eq!(term.wait_for_key_press(), Some(0xA));
+    }
+
+    #[test]
+    fn help_overlay() {
+        let r: &[u8] = b"?";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 12, &[0xFF; 8]);
+        let plain = term.compose();
+        let pixels = term.pixels;
+
+        term.poll_input();
+        let overlaid = term.compose();
+        assert_ne!(overlaid, plain);
+        let text: String = overlaid[11][4..60].iter().collect();
+        assert!(text.trim_end().ends_with("pause / resume"), "{}", text);
+        // The game's own pixels show around the text box
+        assert_eq!(overlaid[13][0], '█');
+        assert_eq!(term.pixels, pixels);
+
+        term.handle_control_key(Key::F(1));
+        assert_eq!(term.compose(), plain);
+    }
+
+    #[test]
+    fn draw_events() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(0, 0, &[0xFF]);
+        assert!(term.take_draw_events().is_empty());
+
+        term.set_draw_events(true);
+        term.draw_sprite(70, 3, &[0xFF, 0x81]);
+        term.draw_sprite(6, 3, &[0xFF]);
+        term.clear();
+        assert_eq!(
+            term.take_draw_events(),
+            [
+                DrawEvent::Sprite {
+                    x: 6,
+                    y: 3,
+                    rows: 2,
+                    collision: false
+                },
+                DrawEvent::Sprite {
+                    x: 6,
+                    y: 3,
+                    rows: 1,
+                    collision: true
+                },
+                DrawEvent::Clear { planes: 1 },
+            ]
+        );
+        assert!(term.take_draw_events().is_empty());
+    }
+
+    #[test]
+    fn grid_lines() {
+        let grid = super::grid_lines();
+        for (y, row) in grid.iter().enumerate() {
+            for x in 0..64 {
+                let marked = row & (1 << (63 - x)) != 0;
+                assert_eq!(marked, x % 8 == 0 || y % 8 == 0, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn grid_overlay() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        term.draw_sprite(1, 1, &[0x80]);
+        let plain = term.compose();
+        let pixels = term.pixels;
+
+        term.handle_control_key(Key::Ctrl('g'));
+        let overlaid = term.compose();
+        assert_eq!(overlaid[0][8..10], ['8', '░']);
+        assert_eq!(overlaid[16][..3], ['1', '6', '░']);
+        assert_eq!(overlaid[9][8], '░');
+        assert_eq!(overlaid[9][9], ' ');
+        assert_eq!(overlaid[1][1], '█');
+        assert_eq!(term.pixels, pixels);
+
+        term.handle_control_key(Key::Ctrl('g'));
+        assert_eq!(term.compose(), plain);
+    }
+
+    #[test]
+    fn brightness_ramp() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, sink());
+        assert_eq!(term.shade(0), ' ');
+        assert_eq!(term.shade(2), '▒');
+        assert_eq!(term.shade(4), '█');
+        assert_eq!(term.shade(5), '█');
+        assert_eq!(term.shade(usize::MAX), '█');
+
+        term.set_brightness_ramp(vec!['.', '#']);
+        assert_eq!(term.shade(0), '.');
+        assert_eq!(term.shade(1), '#');
+        assert_eq!(term.shade(3), '#');
+        term.draw_sprite(0, 0, &[0b1000_0000]);
+        assert!(term.to_ascii().starts_with("#...."));
+    }
+
+    #[test]
+    fn pixel_chars() {
+        let r: &[u8] = b"";
+        let mut term = super::Terminal::new(r, CapturingSink::default());
+        term.set_pixel_chars('.', '#');
+        assert_eq!(term.shade(2), '▒');
+        term.draw_sprite(0, 0, &[0b1010_0000]);
+        assert!(term.to_ascii().starts_with("#.#.."));
+
+        *term.stdout.get_mut() = CapturingSink::default();
+        term.render();
+        let out = String::from_utf8(term.stdout.get_ref().bytes.clone()).unwrap();
+        assert!(out.starts_with(&format!("{}#{}.", cursor::Goto(1, 1), cursor::Goto(2, 1))));
+        assert!(!out.contains('█'));
+    }
+
+    #[test]
+    fn keypad_panel() {
+        let panel = super::keypad_panel(|key| key == 0x1 || key == 0xE);
+        assert_eq!(
+            panel,
+            "[1] 2  3  C \n \
+              4  5  6  D \n \
+              7  8  9 [E]\n \
+              A  0  B  F \n"
+        );
+    }
+
     #[test]
     fn bit_iterator() {
         let val = 0b1111_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_1100;
@@ -0,0 +1,150 @@
+use std::io::{Read, Write};
+
+use crate::terminal::{Terminal, DISPLAY_WIDTH};
+
+/// Panics unless the display matches `expected`, printing both side by side.
+///
+/// The pattern describes the display from its top left corner, `#` for a lit pixel
+/// and `.` for an unlit one. Whitespace around each line is ignored, as are blank
+/// lines, so patterns can be indented along with the test. Every pixel the pattern
+/// doesn't cover must be unlit.
+#[track_caller]
+pub fn assert_display_matches<R: Read, W: Write>(terminal: &Terminal<R, W>, expected: &str) {
+    if let Err(message) = compare_display(terminal.rows(), expected) {
+        panic!("{}", message);
+    }
+}
+
+/// Compares the display's rows, however many it has, against a pattern as described
+/// for `assert_display_matches`.
+pub fn compare_display(pixels: &[u64], expected: &str) -> Result<(), String> {
+    let rows = pattern_rows(expected, pixels.len())?;
+    let mismatch = pixels
+        .iter()
+        .enumerate()
+        .any(|(y, &row)| row != rows.get(y).copied().unwrap_or(0));
+    if !mismatch {
+        return Ok(());
+    }
+
+    // Show the pattern's rows, and any lit rows below them
+    let shown = (0..pixels.len())
+        .rev()
+        .find(|&y| pixels[y] != 0)
+        .map_or(0, |y| y + 1)
+        .max(rows.len());
+    let mut message = format!(
+        "display does not match\n{:<width$}   expected\n",
+        "actual",
+        width = DISPLAY_WIDTH
+    );
+    for (y, &row) in pixels.iter().enumerate().take(shown) {
+        let expected = rows.get(y).copied().unwrap_or(0);
+        let marker = if row == expected { ' ' } else { '!' };
+        message.push_str(&format!(
+            "{} {} {}\n",
+            row_to_text(row),
+            marker,
+            row_to_text(expected)
+        ));
+    }
+    Err(message)
+}
+
+fn pattern_rows(pattern: &str, height: usize) -> Result<Vec<u64>, String> {
+    let lines: Vec<&str> = pattern
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.len() > height {
+        return Err(format!("pattern has more than {} rows", height));
+    }
+    lines
+        .iter()
+        .map(|line| {
+            if line.chars().count() > DISPLAY_WIDTH {
+                return Err(format!("pattern row `{}` is wider than the display", line));
+            }
+            let mut row = 0u64;
+            for (x, c) in line.chars().enumerate() {
+                match c {
+                    '#' => row |= 1 << (63 - x),
+                    '.' => (),
+                    _ => return Err(format!("unexpected `{}` in pattern row `{}`", c, line)),
+                }
+            }
+            Ok(row)
+        })
+        .collect()
+}
+
+fn row_to_text(row: u64) -> String {
+    (0..DISPLAY_WIDTH)
+        .map(|x| if row & 1 << (63 - x) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+
+    use super::{assert_display_matches, compare_display};
+    use crate::terminal::Terminal;
+
+    #[test]
+    fn matches() {
+        let r: &[u8] = b"";
+        let mut term = Terminal::new(r, sink());
+        term.draw_sprite(1, 1, &[0b1001_0000, 0b0110_0000]);
+        assert_display_matches(
+            &term,
+            "
+            .....
+            .#..#
+            ..##.
+            ",
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut pixels = [0; 32];
+        pixels[1] = 1 << 62;
+        pixels[3] = 1 << 63;
+        let message = compare_display(&pixels, "..\n.#").unwrap_err();
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines[0], "display does not match");
+        assert!(lines[1].starts_with("actual ") && lines[1].ends_with("   expected"));
+        // One line per row down to the last lit one, with mismatched rows marked
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[3], format!(".#{0}   .#{0}", ".".repeat(62)));
+        assert_eq!(
+            lines[5],
+            format!("#{} ! {}", ".".repeat(63), ".".repeat(64))
+        );
+
+        assert!(compare_display(&pixels, "x").is_err());
+    }
+
+    #[test]
+    fn tall_display() {
+        let r: &[u8] = b"";
+        let mut term = Terminal::new(r, sink());
+        term.set_display_height(64);
+        term.draw_sprite(0, 40, &[0x80]);
+        let mut pattern = ".\n".repeat(40);
+        pattern.push('#');
+        assert_display_matches(&term, &pattern);
+        assert!(compare_display(term.rows(), "").is_err());
+        assert!(compare_display(&[0; 32], &"\n.".repeat(33)).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "display does not match")]
+    fn assert_panics() {
+        let r: &[u8] = b"";
+        let term = Terminal::new(r, sink());
+        assert_display_matches(&term, "#");
+    }
+}
@@ -0,0 +1,162 @@
+//! Checks a ROM's execution against a trace from another emulator, in the format
+//! written by `--log-format`, to find the first instruction where the two disagree.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::cpu::CPU;
+
+// Matching lines shown before a divergence
+const CONTEXT_LINES: usize = 5;
+
+/// Where execution first departed from the reference trace.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// The 1-based line of the reference trace, and so the instruction, that differed.
+    pub step: usize,
+    pub expected: String,
+    /// The line this emulator produced, or the error that stopped it.
+    pub actual: String,
+    /// The lines before it, which both agreed on.
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "diverged from the reference at step {}:", self.step)?;
+        for line in &self.context {
+            writeln!(f, "           {}", line)?;
+        }
+        writeln!(f, "  expected {}", self.expected)?;
+        write!(f, "  actual   {}", self.actual)
+    }
+}
+
+/// Steps `cpu` once for each line of `reference` and returns how many matched, or
+/// where they first differed. Lines are compared by PC and opcode, and with
+/// `registers` also by V0 to VF, I and SP; the mnemonics are ignored, since emulators
+/// spell them differently.
+///
+/// There's no input, and the timers tick after every `cycles_per_frame` instructions.
+pub fn compare<R: Read, W: Write>(
+    cpu: &mut CPU<R, W>,
+    reference: &str,
+    registers: bool,
+) -> Result<usize, Divergence> {
+    let mut context = Vec::new();
+    let mut matched = 0;
+    let mut left_in_frame = cpu.cycles_per_frame().max(1);
+    let lines = reference.lines().filter(|line| !line.trim().is_empty());
+    for (n, expected) in lines.enumerate() {
+        let actual = cpu.trace_line();
+        if fields(&actual, registers) != fields(expected, registers) {
+            return Err(Divergence {
+                step: n + 1,
+                expected: expected.to_string(),
+                actual,
+                context,
+            });
+        }
+        if let Err(e) = cpu.step() {
+            return Err(Divergence {
+                step: n + 1,
+                expected: expected.to_string(),
+                actual: format!("error: {}", e),
+                context,
+            });
+        }
+        left_in_frame -= 1;
+        if left_in_frame == 0 {
+            cpu.tick_timers();
+            left_in_frame = cpu.cycles_per_frame().max(1);
+        }
+        if context.len() == CONTEXT_LINES {
+            context.remove(0);
+        }
+        context.push(expected.to_string());
+        matched += 1;
+    }
+    Ok(matched)
+}
+
+/// The fields of a trace line that are compared, upper-cased so either case matches.
+fn fields(line: &str, registers: bool) -> Vec<String> {
+    let words: Vec<String> = line.split_whitespace().map(str::to_uppercase).collect();
+    let mut fields: Vec<String> = words.iter().take(2).cloned().collect();
+    // V0 to VF, I and SP end the line, after a mnemonic of any number of words
+    if registers && words.len() >= 20 {
+        fields.extend_from_slice(&words[words.len() - 18..]);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+
+    use super::compare;
+    use crate::cpu::CPU;
+
+    const ROM: [u8; 8] = [
+        0x60, 0x01, // LD V0, 1
+        0x70, 0x01, // ADD V0, 1
+        0xA3, 0x00, // LD I, 0x300
+        0x12, 0x06, // JP 0x206
+    ];
+
+    fn cpu() -> CPU<&'static [u8], std::io::Sink> {
+        let mut cpu = CPU::new(&b""[..], sink());
+        cpu.load(&ROM);
+        cpu
+    }
+
+    fn reference() -> String {
+        let mut cpu = cpu();
+        let mut trace = String::new();
+        for _ in 0..3 {
+            trace += &cpu.trace_line();
+            trace.push('\n');
+            cpu.step().unwrap();
+        }
+        trace
+    }
+
+    #[test]
+    fn matching() {
+        assert_eq!(compare(&mut cpu(), &reference(), true), Ok(3));
+        // Other emulators name instructions differently
+        let renamed = reference().replace("LD I, 0x300", "MOV I,300");
+        assert_eq!(compare(&mut cpu(), &renamed, true), Ok(3));
+
+        // A speed of 0 counts as 1 instruction a frame, as in `run_frame`
+        let mut cpu = cpu();
+        cpu.set_cycles_per_frame(0);
+        assert_eq!(compare(&mut cpu, &reference(), true), Ok(3));
+    }
+
+    #[test]
+    fn diverging() {
+        let reference = reference();
+        let lines: Vec<&str> = reference.lines().collect();
+
+        let wrong_opcode = reference.replace("0202 7001", "0202 7002");
+        let divergence = compare(&mut cpu(), &wrong_opcode, false).unwrap_err();
+        assert_eq!(divergence.step, 2);
+        assert_eq!(divergence.actual, lines[1]);
+        assert_eq!(divergence.context, [lines[0]]);
+
+        // V0 is 2 after the ADD, which only shows when comparing registers
+        let wrong_v0 = format!(
+            "{}\n{}\n{}",
+            lines[0],
+            lines[1],
+            lines[2].replacen(" 02 ", " 03 ", 1)
+        );
+        assert_eq!(compare(&mut cpu(), &wrong_v0, false), Ok(3));
+        let divergence = compare(&mut cpu(), &wrong_v0, true).unwrap_err();
+        assert_eq!(divergence.step, 3);
+        assert!(divergence
+            .to_string()
+            .starts_with("diverged from the reference at step 3:\n"));
+    }
+}
@@ -0,0 +1,163 @@
+//! Reloading the ROM when its file changes, for `--watch`. Watching the file needs the
+//! `watch` feature; rereading on a change works without it.
+
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "watch")]
+use std::path::Path;
+
+#[cfg(feature = "watch")]
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+#[cfg(all(feature = "watch", feature = "zip"))]
+use crate::archive;
+use crate::rom::{read_rom, validate_rom};
+
+pub struct RomWatcher {
+    // As given to `read_rom`
+    rom: String,
+    // One message for each change seen to the file
+    changes: Receiver<()>,
+    // Stops watching when dropped
+    #[cfg(feature = "watch")]
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl RomWatcher {
+    /// Watches the ROM at `rom`, as `read_rom` reads it, so for an entry in a zip
+    /// archive the whole archive is watched. The directory holding the file is watched
+    /// rather than the file itself, so a build that replaces the file instead of
+    /// writing over it is still seen.
+    #[cfg(feature = "watch")]
+    pub fn new(rom: &str) -> notify::Result<Self> {
+        #[cfg(feature = "zip")]
+        let path = Path::new(archive::split_entry(rom).map_or(rom, |(archive, _)| archive));
+        #[cfg(not(feature = "zip"))]
+        let path = Path::new(rom);
+        let (sender, changes) = std::sync::mpsc::channel();
+        let name = path.file_name().map(|name| name.to_owned());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            let ours = event.paths.iter().any(|p| p.file_name() == name.as_deref());
+            if ours && (event.kind.is_create() || event.kind.is_modify()) {
+                // Only fails once the watcher is being dropped
+                let _ = sender.send(());
+            }
+        })?;
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(RomWatcher {
+            rom: rom.to_string(),
+            changes,
+            _watcher: Some(watcher),
+        })
+    }
+
+    /// Rereads `rom` on the changes sent to `changes`, without watching the file.
+    #[cfg(test)]
+    fn with_changes(rom: String, changes: Receiver<()>) -> Self {
+        RomWatcher {
+            rom,
+            changes,
+            #[cfg(feature = "watch")]
+            _watcher: None,
+        }
+    }
+
+    /// Whether the file changed since the last call. A single save usually arrives as
+    /// several changes, which all count as one.
+    pub fn changed(&self) -> bool {
+        self.changes.try_iter().count() > 0
+    }
+
+    /// If the file changed, reads the ROM again for the caller to reload, along with
+    /// whatever it sets up for the ROM. A file that can't be read or isn't a valid ROM
+    /// is an error, so the old one can be left running.
+    pub fn read_if_changed(&self) -> Result<Option<Vec<u8>>, String> {
+        if !self.changed() {
+            return Ok(None);
+        }
+        let rom = read_rom(&self.rom)?;
+        validate_rom(&rom).map_err(|e| e.to_string())?;
+        Ok(Some(rom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::sink;
+    use std::sync::mpsc::{self, Sender};
+
+    use super::RomWatcher;
+    use crate::cpu::CPU;
+
+    fn watcher(rom: &str) -> (RomWatcher, Sender<()>) {
+        let (sender, changes) = mpsc::channel();
+        (RomWatcher::with_changes(rom.to_string(), changes), sender)
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let path = env::temp_dir().join(format!("chip8-watch-{}.ch8", std::process::id()));
+        fs::write(&path, [0x60, 0x01, 0x12, 0x02]).unwrap(); // LD V0, 1; JP 0x202
+        let (watcher, changes) = watcher(path.to_str().unwrap());
+        let r: &[u8] = b"";
+        let mut cpu = CPU::new(r, sink());
+        cpu.load(&fs::read(&path).unwrap());
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(watcher.read_if_changed(), Ok(None));
+
+        // Rebuilt shorter, so the old jump mustn't be left behind after it
+        fs::write(&path, [0x61, 0x02]).unwrap(); // LD V1, 2
+        changes.send(()).unwrap();
+        changes.send(()).unwrap();
+        let rom = watcher.read_if_changed().unwrap().unwrap();
+        assert_eq!(rom, [0x61, 0x02]);
+        cpu.reload(&rom);
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.registers()[0], 0);
+        assert_eq!(cpu.memory()[0x200..0x204], [0x61, 0x02, 0, 0]);
+        assert_eq!(watcher.read_if_changed(), Ok(None));
+
+        fs::write(&path, b"").unwrap();
+        changes.send(()).unwrap();
+        assert_eq!(
+            watcher.read_if_changed(),
+            Err("the ROM is empty".to_string())
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn reread_archive_entry() {
+        use std::io::Write;
+        use zip::write::{FileOptions, ZipWriter};
+
+        let path = env::temp_dir().join(format!("chip8-watch-{}.zip", std::process::id()));
+        let write_archive = |rom: &[u8]| {
+            let mut writer = ZipWriter::new(fs::File::create(&path).unwrap());
+            writer
+                .start_file("pong.ch8", FileOptions::default())
+                .unwrap();
+            writer.write_all(rom).unwrap();
+            writer.finish().unwrap();
+        };
+        write_archive(&[0x12, 0x00]);
+        let (watcher, changes) = watcher(&format!("{}:pong.ch8", path.display()));
+
+        write_archive(&[0x60, 0x01]);
+        changes.send(()).unwrap();
+        assert_eq!(watcher.read_if_changed(), Ok(Some(vec![0x60, 0x01])));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::sink;
+
+use chip8::cpu::{Advance, CPU};
+
+// Sets the delay timer to 200, then counts V1 up to 50 and stops
+const ROM: [u8; 12] = [
+    0x60, 0xC8, // LD V0, 200
+    0xF0, 0x15, // LD DT, V0
+    0x71, 0x01, // ADD V1, 1
+    0x31, 0x32, // SE V1, 50
+    0x12, 0x04, // JP 0x204
+    0x12, 0x0A, // JP 0x20A
+];
+
+fn cpu() -> CPU<&'static [u8], std::io::Sink> {
+    let mut cpu = CPU::new(&b""[..], sink());
+    cpu.set_cycles_per_frame(10);
+    cpu.load(&ROM);
+    cpu
+}
+
+#[test]
+fn instruction() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.advance(Advance::Instruction).unwrap(), 1);
+    assert_eq!(cpu.advance(Advance::Instruction).unwrap(), 1);
+    assert_eq!((cpu.pc(), cpu.delay_timer()), (0x204, 200));
+}
+
+#[test]
+fn frame() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.advance(Advance::Frame).unwrap(), 10);
+    assert_eq!(cpu.delay_timer(), 199);
+    assert_eq!(cpu.cycles(), 10);
+}
+
+#[test]
+fn cycles() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.advance(Advance::Cycles(25)).unwrap(), 25);
+    assert_eq!(cpu.cycles(), 25);
+    // Two whole frames' worth
+    assert_eq!(cpu.delay_timer(), 198);
+
+    // Stops early at the halt, after 2 + 49 * 3 + 2 + 1 instructions in all
+    assert_eq!(cpu.advance(Advance::Cycles(1000)).unwrap(), 152 - 25);
+    assert!(cpu.is_halted());
+    assert_eq!(cpu.advance(Advance::Cycles(10)).unwrap(), 0);
+    assert_eq!(cpu.advance(Advance::Instruction).unwrap(), 0);
+}
+
+#[test]
+fn until_halt() {
+    let mut cpu = cpu();
+    assert_eq!(cpu.advance(Advance::UntilHalt).unwrap(), 152);
+    assert!(cpu.is_halted());
+    assert_eq!(cpu.registers()[1], 50);
+    // One tick for each of the 16 frames it took
+    assert_eq!(cpu.delay_timer(), 200 - 16);
+}
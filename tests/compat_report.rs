@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+
+use chip8::compat;
+use chip8::quirks::Quirks;
+
+fn dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms")
+}
+
+#[test]
+fn classifies_bundled_roms() {
+    let report = compat::report(&dir(), 1_000, None, Quirks::default()).unwrap();
+    assert_eq!(
+        report,
+        "bad_opcode.ch8: error: unknown opcode 5AB1 at 202\n\
+         halts.ch8: ok, halted after 2 cycles\n\
+         loops.ch8: timed out after 1000 cycles\n"
+    );
+}
+
+#[test]
+fn stable_redraw_loop() {
+    let rom = [
+        0xF0, 0x29, // LD F, V0
+        // loop:
+        0xD0, 0x05, // DRW V0, V0, 5
+        0xD0, 0x05, // DRW V0, V0, 5
+        0xD0, 0x05, // DRW V0, V0, 5
+        0xD0, 0x05, // DRW V0, V0, 5
+        0x12, 0x02, // JP loop
+    ];
+    assert_eq!(
+        compat::run(&rom, 10_000, None, Quirks::default()),
+        compat::Outcome::TimedOut
+    );
+    // The first frame sets I, then every frame ends two loops later as it started
+    assert_eq!(
+        compat::run(&rom, 10_000, Some(60), Quirks::default()),
+        compat::Outcome::Stable(610)
+    );
+    assert_eq!(
+        compat::report(&dir(), 1_000, Some(60), Quirks::default()).unwrap(),
+        "bad_opcode.ch8: error: unknown opcode 5AB1 at 202\n\
+         halts.ch8: ok, halted after 2 cycles\n\
+         loops.ch8: ok, stable after 610 cycles\n"
+    );
+}
@@ -0,0 +1,61 @@
+use std::io::sink;
+
+use chip8::cpu::CPU;
+
+const DELAY: u8 = 5;
+
+#[test]
+fn busy_wait_on_delay_timer() {
+    let rom = [
+        0x60, DELAY, // LD V0, DELAY
+        0xF0, 0x15, // LD DT, V0
+        0xF1, 0x07, // LD V1, DT
+        0x31, 0x00, // SE V1, 0
+        0x12, 0x04, // JP 0x204
+        0x12, 0x0A, // JP 0x20A
+    ];
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.load(&rom);
+
+    // Each frame spins through the loop many times but DT only moves between frames,
+    // so every read in a frame sees the same intermediate value.
+    let mut reads = Vec::new();
+    while cpu.run_frame().unwrap() {
+        reads.push(cpu.registers()[1]);
+    }
+    assert_eq!(reads, [5, 4, 3, 2, 1]);
+    assert_eq!(cpu.registers()[1], 0);
+    assert!(cpu.is_halted());
+}
+
+#[test]
+fn timers_count_down_independently() {
+    let rom = [
+        0x70, 0x01, // ADD V0, 1
+        0x12, 0x00, // JP 0x200
+    ];
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.load(&rom);
+
+    // (DT, ST, beeping) after each frame, the sound stopping with ST alone
+    let mut run = |dt, st, frames| {
+        cpu.set_delay_timer(dt);
+        cpu.set_sound_timer(st);
+        (0..frames)
+            .map(|_| {
+                let frame = cpu.step_frame().unwrap();
+                (cpu.delay_timer(), cpu.sound_timer(), frame.beeping)
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        run(5, 2, 4),
+        [(4, 1, true), (3, 0, false), (2, 0, false), (1, 0, false)]
+    );
+    assert_eq!(
+        run(2, 4, 4),
+        [(1, 3, true), (0, 2, true), (0, 1, true), (0, 0, false)]
+    );
+}
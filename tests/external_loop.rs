@@ -0,0 +1,40 @@
+use std::io::sink;
+
+use chip8::cpu::CPU;
+
+const CYCLES_PER_FRAME: usize = 10;
+
+#[test]
+fn external_loop() {
+    let rom = [
+        0x60, 0x03, // LD V0, 3
+        0xF0, 0x15, // LD DT, V0
+        0xF0, 0x29, // LD F, V0
+        0x61, 0x00, // LD V1, 0
+        0xD1, 0x15, // DRW V1, V1, 5
+        0xF2, 0x07, // LD V2, DT
+        0x32, 0x00, // SE V2, 0
+        0x12, 0x0A, // JP 0x20A
+        0x12, 0x10, // JP 0x210
+    ];
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.load(&rom);
+
+    let mut frame = || {
+        for _ in 0..CYCLES_PER_FRAME {
+            cpu.step().unwrap();
+        }
+        cpu.tick_timers();
+        cpu.present();
+        cpu.delay_timer()
+    };
+    assert_eq!(frame(), 2);
+    assert_eq!(frame(), 1);
+    assert_eq!(frame(), 0);
+    assert_eq!(frame(), 0);
+
+    let pbm = cpu.terminal().to_pbm();
+    let rows: Vec<u8> = pbm[9..].chunks(8).take(6).map(|row| row[0]).collect();
+    assert_eq!(rows, [0xF0, 0x10, 0xF0, 0x10, 0xF0, 0x00]);
+}
@@ -0,0 +1,66 @@
+use std::cell::Cell;
+use std::io::sink;
+use std::rc::Rc;
+use std::time::Duration;
+
+use chip8::backend::Display;
+use chip8::clock::{Clock, FrameTimer};
+use chip8::cpu::CPU;
+
+// Sets the delay timer to 60, then counts in V0 forever
+const ROM: [u8; 8] = [
+    0x60, 0x3C, // LD V0, 60
+    0xF0, 0x15, // LD DT, V0
+    0x70, 0x01, // ADD V0, 1
+    0x12, 0x04, // JP 0x204
+];
+
+#[derive(Clone, Default)]
+struct FakeClock(Rc<Cell<Duration>>);
+
+impl Clock for FakeClock {
+    fn now(&self) -> Duration {
+        self.0.get()
+    }
+}
+
+struct Renders(Rc<Cell<u32>>);
+
+impl Display for Renders {
+    fn present(&mut self, _rows: &[u64]) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+/// Runs the ROM for a second as the main loop would under `--fps-cap`, returning the
+/// delay timer and how many frames were rendered.
+fn one_second(fps_cap: u32) -> (u8, u32) {
+    let clock = FakeClock::default();
+    let mut timer = FrameTimer::with_fps(Box::new(clock.clone()), fps_cap);
+    let renders = Rc::new(Cell::new(0));
+    let mut cpu = CPU::new(&b""[..], sink());
+    cpu.set_cycles_per_frame(10 * 60 / fps_cap);
+    cpu.set_render_fps(60 * 60 / fps_cap);
+    cpu.set_timer_hz(60 * 60 / fps_cap);
+    cpu.set_display(Some(Box::new(Renders(renders.clone()))));
+    cpu.load(&ROM);
+
+    for _ in 0..1000 {
+        clock.0.set(clock.0.get() + Duration::from_millis(1));
+        while timer.should_tick_timers() {
+            assert!(cpu.run_frame().unwrap());
+        }
+    }
+    (cpu.delay_timer(), renders.get())
+}
+
+#[test]
+fn uncapped() {
+    assert_eq!(one_second(60), (0, 60));
+}
+
+#[test]
+fn capped_at_30() {
+    // Half the frames, so half the renders, but the delay timer still runs out on time
+    assert_eq!(one_second(30), (0, 30));
+}
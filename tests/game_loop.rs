@@ -0,0 +1,98 @@
+use std::io::sink;
+
+use chip8::backend::KeyEvent;
+use chip8::cpu::CPU;
+use chip8::testing::assert_display_matches;
+
+const WAIT_FOR_KEY: u16 = 0x20A;
+
+// Draws a two pixel wide block, waits for a key, moves the block two pixels right for
+// 6 or left for 4, pauses three frames on the delay timer and draws it again
+const ROM: [u8; 37] = [
+    0x60, 0x08, // LD V0, 8
+    0x61, 0x04, // LD V1, 4
+    0x63, 0x03, // LD V3, 3
+    0xA2, 0x24, // LD I, block
+    // loop:
+    0xD0, 0x11, // DRW V0, V1, 1
+    0xF2, 0x0A, // LD V2, K
+    0xD0, 0x11, // DRW V0, V1, 1
+    0x32, 0x06, // SE V2, 6
+    0x12, 0x14, // JP left
+    0x70, 0x02, // ADD V0, 2
+    // left:
+    0x32, 0x04, // SE V2, 4
+    0x12, 0x1A, // JP pause
+    0x70, 0xFE, // ADD V0, -2
+    // pause:
+    0xF3, 0x15, // LD DT, V3
+    0xF4, 0x07, // LD V4, DT
+    0x34, 0x00, // SE V4, 0
+    0x12, 0x1C, // JP pause + 2
+    0x12, 0x08, // JP loop
+    0xC0, // block: ##......
+];
+
+type Emulator = CPU<&'static [u8], std::io::Sink>;
+
+/// Presses and releases `key`, returning how many frames the ROM took to get back to
+/// waiting for the next one.
+fn press(cpu: &mut Emulator, key: u8) -> u32 {
+    cpu.terminal_mut().handle_key_event(KeyEvent::Down(key));
+    let mut frames = 0;
+    loop {
+        cpu.step_frame().unwrap();
+        frames += 1;
+        if cpu.pc() == WAIT_FOR_KEY {
+            break;
+        }
+        // Blank while paused, between erasing the block and drawing it again
+        assert_eq!(cpu.terminal().framebuffer(), [0; 32]);
+        assert!(frames < 10, "the ROM never waited for another key");
+    }
+    cpu.terminal_mut().handle_key_event(KeyEvent::Up(key));
+    frames
+}
+
+#[test]
+fn move_a_block_with_the_keypad() {
+    let mut cpu = CPU::new(&b""[..], sink());
+    cpu.set_cycles_per_frame(10);
+    cpu.load(&ROM);
+
+    cpu.step_frame().unwrap();
+    assert_eq!(cpu.pc(), WAIT_FOR_KEY);
+    // Waiting uses up whole frames without going anywhere
+    assert_eq!(cpu.step_frame().unwrap().cycles, 10);
+    assert_eq!(cpu.pc(), WAIT_FOR_KEY);
+    assert_display_matches(
+        cpu.terminal(),
+        "
+        ................
+        ................
+        ................
+        ................
+        ........##......
+        ",
+    );
+
+    // The frame taking the key, two more until the delay timer runs out, and one
+    // to draw
+    for &key in &[6, 6, 6, 4] {
+        assert_eq!(press(&mut cpu, key), 4);
+    }
+    // Not a key the ROM moves for, so the block stays put
+    assert_eq!(press(&mut cpu, 1), 4);
+
+    assert_eq!(cpu.registers()[0], 12);
+    assert_display_matches(
+        cpu.terminal(),
+        "
+        ................
+        ................
+        ................
+        ................
+        ............##..
+        ",
+    );
+}
@@ -0,0 +1,48 @@
+use chip8::quirks::{Quirks, ShiftQuirk};
+use chip8::suite::{self, QUIRKS_ROM};
+
+#[test]
+fn cosmac_passes_quirks_rom() {
+    assert_eq!(
+        suite::run(&QUIRKS_ROM, Quirks::cosmac()),
+        [true, true, true]
+    );
+}
+
+#[test]
+fn each_quirk_is_detected() {
+    assert_eq!(
+        suite::run(&QUIRKS_ROM, Quirks::default()),
+        [false, false, false]
+    );
+    let quirks = Quirks {
+        shift: ShiftQuirk::ModernVx,
+        ..Quirks::cosmac()
+    };
+    assert_eq!(suite::run(&QUIRKS_ROM, quirks), [true, true, false]);
+}
+
+#[test]
+fn report_lists_every_combination() {
+    let report = suite::report();
+    assert_eq!(report.matches("PASS").count(), 1);
+    assert_eq!(report.matches("FAIL").count(), 7);
+}
+
+#[test]
+fn reads_the_top_row_of_a_hires_display() {
+    let mut rom = vec![0x12, 0x60]; // 200: JP 0x260, for a 64x64 display
+    rom.resize(0x60, 0);
+    rom.extend_from_slice(&[
+        0xA2, 0x66, // 260: LD I, pass
+        0xD0, 0x01, // 262: DRW V0, V0, 1
+        0x12, 0x64, // 264: JP 0x264
+        0xFF, // 266: pass
+    ]);
+    let test = suite::TestRom {
+        name: "hires",
+        checks: &["top row"],
+        rom: Box::leak(rom.into_boxed_slice()),
+    };
+    assert_eq!(suite::run(&test, Quirks::default()), [true]);
+}
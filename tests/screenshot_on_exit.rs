@@ -0,0 +1,38 @@
+use std::env;
+use std::fs;
+use std::io::sink;
+
+use chip8::cpu::CPU;
+
+#[test]
+fn screenshot_on_halt() {
+    let rom = [
+        0x60, 0x0A, // LD V0, 0xA
+        0xF0, 0x29, // LD F, V0
+        0xD0, 0x05, // DRW V0, V0, 5
+        0x12, 0x06, // JP 0x206
+    ];
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.load(&rom);
+    while cpu.run_frame().unwrap() {}
+    assert!(cpu.is_halted());
+
+    let dir = env::temp_dir();
+    let pbm = dir.join("chip8_screenshot_on_halt.pbm");
+    let txt = dir.join("chip8_screenshot_on_halt.txt");
+    cpu.terminal().save_screenshot(&pbm).unwrap();
+    cpu.terminal().save_screenshot(&txt).unwrap();
+
+    assert_eq!(fs::read(&pbm).unwrap(), cpu.terminal().to_pbm());
+    let ascii = fs::read_to_string(&txt).unwrap();
+    let rows: Vec<String> = ascii
+        .lines()
+        .skip(10)
+        .take(5)
+        .map(|row| row.chars().skip(10).take(4).collect())
+        .collect();
+    assert_eq!(rows, ["████", "█  █", "████", "█  █", "█  █"]);
+    fs::remove_file(pbm).unwrap();
+    fs::remove_file(txt).unwrap();
+}
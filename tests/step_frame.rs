@@ -0,0 +1,44 @@
+use std::io::sink;
+
+use chip8::cpu::{FrameResult, CPU};
+
+#[test]
+fn step_frame() {
+    let rom = [
+        0x60, 0x02, // LD V0, 2
+        0xF0, 0x18, // LD ST, V0
+        0x71, 0x01, // ADD V1, 1
+        0x31, 0x0F, // SE V1, 15
+        0x12, 0x04, // JP 0x204
+        0x12, 0x0A, // JP 0x20A
+    ];
+    let input: &[u8] = b"";
+    let mut cpu = CPU::new(input, sink());
+    cpu.set_cycles_per_frame(10);
+    cpu.load(&rom);
+
+    let first = cpu.step_frame().unwrap();
+    assert_eq!(
+        first,
+        FrameResult {
+            cycles: 10,
+            beeping: true,
+            halted: false
+        }
+    );
+    assert_eq!(cpu.sound_timer(), 1);
+
+    // The sound stops, and the loop counting V1 to 15 ends in a jump to itself
+    let mut frames = vec![first];
+    while !frames.last().unwrap().halted {
+        frames.push(cpu.step_frame().unwrap());
+        assert!(frames.len() < 10);
+    }
+    assert!(!frames[1].beeping);
+    assert_eq!(cpu.registers()[1], 15);
+    assert_eq!(frames.len(), 5);
+    assert_eq!(frames.last().unwrap().cycles, 7);
+
+    // Once halted, frames run nothing
+    assert_eq!(cpu.step_frame().unwrap().cycles, 0);
+}